@@ -0,0 +1,13 @@
+//! Build script of `proc-api`: compiles the Protocol Buffers definitions of
+//! the gRPC service behind the `grpc` feature, using a vendored `protoc` so
+//! that none needs to be installed.
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto");
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+        tonic_build::compile_protos("proto/proc_api.proto")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,347 @@
+//! This module defines the authentication of requests by bearer tokens and
+//! their authorization by the scopes granted to each token.
+//!
+//! Authentication is disabled unless tokens are configured, in which case each
+//! route requires a token granting its [`Scope`], see [`require`].
+//!
+//! Independently, the processes visible to a caller can be restricted to its
+//! own ones, like the `hidepid=` mount option of `/proc` does, see
+//! [`visibility`].
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use futures_util::future;
+use serde::{Deserialize, Serialize};
+use warp::http::header::WWW_AUTHENTICATE;
+use warp::http::StatusCode;
+use warp::reject;
+use warp::{Filter, Rejection};
+
+use crate::config::{Config, SharedConfig};
+use crate::error::ApiError;
+use crate::proc::ProcInfo;
+use crate::server::{self, Peer};
+
+/// A permission that can be granted to a token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Reading processes, snapshots, differences and metrics.
+    Read,
+    /// Refreshing the cache or importing snapshots into it.
+    Refresh,
+    /// Streaming processes.
+    Stream,
+    /// Sending signals to processes, reserved for the endpoints doing so.
+    Signal,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Read => "read",
+            Self::Refresh => "refresh",
+            Self::Stream => "stream",
+            Self::Signal => "signal",
+        })
+    }
+}
+
+/// What is granted to a token.
+#[derive(Debug, Deserialize)]
+pub struct Grant {
+    /// The secret value of the token, as sent by clients.
+    token: String,
+    /// A name identifying the holder of the token in logs.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// The scopes granted to the token.
+    pub scopes: HashSet<Scope>,
+    /// Who holds the token, when processes are only visible to their owner.
+    #[serde(flatten)]
+    pub identity: Identity,
+}
+
+/// Who a caller is, as far as the visibility of processes is concerned.
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub struct Identity {
+    /// The user whose processes are visible to the caller.
+    #[serde(default)]
+    pub uid: Option<u32>,
+    /// Whether all the processes are visible to the caller.
+    #[serde(default)]
+    pub admin: bool,
+}
+
+impl Identity {
+    /// Returns the processes visible to the caller, if it is identified.
+    fn visibility(self) -> Option<Visibility> {
+        match self {
+            Self { admin: true, .. } => Some(Visibility::All),
+            Self { uid: Some(uid), .. } => Some(Visibility::Owner(uid)),
+            Self { uid: None, .. } => None,
+        }
+    }
+}
+
+/// The identities of the callers presenting client certificates, by subject,
+/// when processes are only visible to their owner.
+#[derive(Debug, Default)]
+pub struct Subjects {
+    identities: HashMap<String, Identity>,
+}
+
+/// An entry of the file loaded by [`Subjects::load`].
+#[derive(Debug, Deserialize)]
+struct SubjectEntry {
+    subject: String,
+    #[serde(flatten)]
+    identity: Identity,
+}
+
+impl Subjects {
+    /// Loads the identities from the JSON file at the given path, containing an
+    /// array of objects with a `subject` field and `uid` or `admin` ones.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Unable to open {path:?}."))?;
+        let entries = serde_json::from_reader::<_, Vec<SubjectEntry>>(BufReader::new(file))
+            .with_context(|| format!("Unable to load subjects from {path:?}."))?;
+        info!("Loaded {} subjects from {path:?}.", entries.len());
+        Ok(entries
+            .into_iter()
+            .map(|entry| (entry.subject, entry.identity))
+            .collect())
+    }
+
+    /// Returns the identity of the given subject, if it is known.
+    pub fn get(&self, subject: &str) -> Option<Identity> {
+        self.identities.get(subject).copied()
+    }
+}
+
+impl FromIterator<(String, Identity)> for Subjects {
+    fn from_iter<I: IntoIterator<Item = (String, Identity)>>(identities: I) -> Self {
+        Self {
+            identities: identities.into_iter().collect(),
+        }
+    }
+}
+
+/// The processes visible to a caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// All of them: visibility is not restricted or the caller is an admin.
+    All,
+    /// Only the ones of the given user.
+    Owner(u32),
+}
+
+impl Visibility {
+    /// Tells whether the given process is visible to the caller.
+    pub fn allows(self, proc: &ProcInfo) -> bool {
+        match self {
+            Self::All => true,
+            Self::Owner(uid) => proc.uid == uid,
+        }
+    }
+}
+
+/// The tokens accepted by the server, by secret value.
+#[derive(Debug, Default)]
+pub struct Tokens {
+    grants: HashMap<String, Grant>,
+}
+
+impl Grant {
+    /// Returns the secret value of the token.
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+}
+
+impl Tokens {
+    /// Loads the tokens from the JSON file at the given path, containing an
+    /// array of objects with `token`, `scopes` and optionally `name` fields.
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Unable to open {path:?}."))?;
+        let grants = serde_json::from_reader::<_, Vec<Grant>>(BufReader::new(file))
+            .with_context(|| format!("Unable to load tokens from {path:?}."))?;
+        info!("Loaded {} tokens from {path:?}.", grants.len());
+        Ok(grants.into_iter().collect())
+    }
+
+    /// Returns what is granted to the given token, if it is accepted.
+    pub fn get(&self, token: &str) -> Option<&Grant> {
+        self.grants.get(token)
+    }
+}
+
+impl FromIterator<Grant> for Tokens {
+    fn from_iter<I: IntoIterator<Item = Grant>>(grants: I) -> Self {
+        Self {
+            grants: grants
+                .into_iter()
+                .map(|grant| (grant.token.clone(), grant))
+                .collect(),
+        }
+    }
+}
+
+/// Why a request was rejected by [`require`] or [`visibility`].
+#[derive(Debug)]
+pub enum AuthError {
+    /// No accepted token was given.
+    Unauthorized,
+    /// The given token does not grant the required scope.
+    Forbidden(Scope),
+    /// Processes are only visible to their owner, but who the caller is could
+    /// not be determined.
+    Unidentified,
+}
+
+/// Checks that the given `Authorization` header carries a bearer token
+/// granting the given scope, if the configuration has tokens.
+pub fn authorize(config: &Config, header: Option<&str>, scope: Scope) -> Result<(), AuthError> {
+    let Some(tokens) = config.tokens() else {
+        return Ok(());
+    };
+    let grant = bearer(header)
+        .and_then(|token| tokens.get(token))
+        .ok_or(AuthError::Unauthorized)?;
+
+    if grant.scopes.contains(&scope) {
+        Ok(())
+    } else {
+        debug!(
+            "Token {} lacks the {scope} scope.",
+            grant.name.as_deref().unwrap_or("<unnamed>")
+        );
+        Err(AuthError::Forbidden(scope))
+    }
+}
+
+/// Determines the [`Visibility`] of the caller: all the processes if the
+/// configuration does not restrict it, otherwise those of the user identified, in order, by
+/// the bearer token of the given `Authorization` header, the subject of the
+/// client certificate or the credentials of the Unix socket peer, root seeing
+/// them all.
+pub fn identify(
+    config: &Config,
+    header: Option<&str>,
+    peer: &Peer,
+) -> Result<Visibility, AuthError> {
+    let Some(subjects) = config.subjects() else {
+        return Ok(Visibility::All);
+    };
+    let by_token = config.tokens().and_then(|tokens| {
+        let token = bearer(header)?;
+        tokens.get(token)?.identity.visibility()
+    });
+    let by_subject = || {
+        let subject = peer.subject.as_deref()?;
+        subjects.get(subject)?.visibility()
+    };
+    let by_cred = || match peer.cred?.uid() {
+        0 => Some(Visibility::All),
+        uid => Some(Visibility::Owner(uid)),
+    };
+
+    by_token
+        .or_else(by_subject)
+        .or_else(by_cred)
+        .ok_or_else(|| {
+            debug!("Unable to identify {peer}.");
+            AuthError::Unidentified
+        })
+}
+
+/// Filter requiring the request to carry a bearer token granting the given
+/// scope, if the configuration has tokens, otherwise letting everything
+/// through.
+///
+/// See also: [`authorize`].
+pub fn require(
+    scope: Scope,
+    config: SharedConfig,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .or(warp::any().map(|| None))
+        .unify()
+        .and_then(move |header: Option<String>| {
+            future::ready(
+                authorize(&config, header.as_deref(), scope)
+                    .map_err(|err| reject::custom(ApiError::from(err))),
+            )
+        })
+        .untuple_one()
+}
+
+/// Filter extracting the [`Visibility`] of the caller.
+///
+/// See also: [`identify`].
+pub fn visibility(
+    config: SharedConfig,
+) -> impl Filter<Extract = (Visibility,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(server::peer())
+        .and_then(move |header: Option<String>, peer: Peer| {
+            future::ready(
+                identify(&config, header.as_deref(), &peer)
+                    .map_err(|err| reject::custom(ApiError::from(err))),
+            )
+        })
+}
+
+/// Extracts the token of the given `Authorization` header, if it is a bearer
+/// one.
+pub fn bearer(header: Option<&str>) -> Option<&str> {
+    header?
+        .split_once(' ')
+        .filter(|(kind, _)| kind.eq_ignore_ascii_case("bearer"))
+        .map(|(_, token)| token.trim())
+}
+
+impl From<AuthError> for ApiError {
+    /// Replies `401 Unauthorized` or `403 Forbidden` with the matching
+    /// `WWW-Authenticate` challenge.
+    fn from(err: AuthError) -> Self {
+        let (err, challenge) = match err {
+            AuthError::Unauthorized => (
+                ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "unauthorized",
+                    "A valid bearer token is required.",
+                ),
+                "Bearer".to_owned(),
+            ),
+            AuthError::Forbidden(scope) => (
+                ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "forbidden",
+                    format!("The token does not grant the {scope} scope."),
+                )
+                .with_details(serde_json::json!({ "scope": scope })),
+                format!("Bearer error=\"insufficient_scope\", scope=\"{scope}\""),
+            ),
+            AuthError::Unidentified => (
+                ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    "unidentified",
+                    "The caller could not be identified.",
+                ),
+                "Bearer".to_owned(),
+            ),
+        };
+        // The scope is a plain word: the challenge is a valid header value.
+        err.with_header(WWW_AUTHENTICATE, challenge.parse().unwrap())
+    }
+}
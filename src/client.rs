@@ -0,0 +1,303 @@
+//! This module defines a typed async client of the API, exchanging the types
+//! of the [`crate::model`] with a `proc-api` server.
+//!
+//! Besides plain requests, the client can subscribe to the stream of processes
+//! as a [`Stream`] of [`Event`]s, reconnecting automatically whenever the
+//! stream is interrupted, see [`Client::subscribe`].
+
+use std::error::Error;
+use std::fmt;
+use std::time::Duration;
+
+use async_stream::stream;
+use futures_util::stream::{Stream, StreamExt};
+use reqwest::header::ACCEPT;
+use reqwest::{RequestBuilder, Response, StatusCode};
+
+use crate::model::{CacheData, ErrorBody, ProcInfo, SearchQuery, Signal};
+
+/// Delay before the first attempt to reconnect to the stream, doubled after
+/// each failed attempt.
+const RECONNECT_MIN: Duration = Duration::from_millis(500);
+/// Maximum delay between attempts to reconnect to the stream.
+const RECONNECT_MAX: Duration = Duration::from_secs(30);
+
+/// A client of the API of a `proc-api` server.
+#[derive(Debug, Clone)]
+pub struct Client {
+    base: String,
+    http: reqwest::Client,
+    token: Option<String>,
+}
+
+/// An event of the stream of processes, see [`Client::subscribe`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// The stream was (re)connected: the server sends all the cached processes
+    /// again, then the ones discovered by each refresh.
+    Connected,
+    /// A process sent by the server: a cached one, or one discovered by a
+    /// refresh.
+    Process(ProcInfo),
+    /// A process that a refresh found to have exited.
+    Removed(ProcInfo),
+}
+
+/// Why a request of a [`Client`] failed.
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request could not be sent, or its reply could not be received.
+    Http(reqwest::Error),
+    /// The server replied with an error.
+    Api { status: StatusCode, body: ErrorBody },
+    /// The server sent an event whose data is not a process.
+    InvalidEvent(serde_json::Error),
+}
+
+impl Client {
+    /// Builds a client of the server at the given base URL, e.g.
+    /// `http://127.0.0.1:8080`.
+    pub fn new(base: impl Into<String>) -> Self {
+        Self::with_http_client(base, reqwest::Client::new())
+    }
+
+    /// Builds a client of the server at the given base URL sending its
+    /// requests with the given HTTP client, e.g. to configure TLS.
+    pub fn with_http_client(base: impl Into<String>, http: reqwest::Client) -> Self {
+        let mut base = base.into();
+        base.truncate(base.trim_end_matches('/').len());
+        Self {
+            base,
+            http,
+            token: None,
+        }
+    }
+
+    /// Authenticates the requests of the client with the given bearer token.
+    pub fn with_token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    /// Lists the cached processes.
+    pub async fn processes(&self) -> Result<CacheData, ClientError> {
+        let res = self.send(self.get("/processes")).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Lists the cached processes matching all the filters of the given query,
+    /// at least one being required.
+    pub async fn search(&self, query: &SearchQuery) -> Result<CacheData, ClientError> {
+        let res = self.send(self.get("/search").query(query)).await?;
+        Ok(res.json().await?)
+    }
+
+    /// Refreshes the cached processes.
+    pub async fn refresh(&self) -> Result<(), ClientError> {
+        self.send(self.http.post(self.url("/acquire_process_list")))
+            .await?;
+        Ok(())
+    }
+
+    /// Sends the given signal to the process of the given PID, if the server
+    /// allows it.
+    pub async fn signal(&self, pid: u32, signal: Signal) -> Result<(), ClientError> {
+        let url = self.url(&format!("/processes/{pid}/signal"));
+        self.send(self.http.post(url).query(&[("signal", signal)]))
+            .await?;
+        Ok(())
+    }
+
+    /// Subscribes to the stream of processes: the cached ones, then the ones
+    /// discovered and removed by each refresh.
+    ///
+    /// Whenever the stream is interrupted, it is reconnected after a delay
+    /// growing with the failed attempts, and [`Event::Connected`] is sent
+    /// again. Errors are sent as they happen, the stream only ending after
+    /// errors that retrying cannot fix, e.g. `401 Unauthorized`.
+    pub fn subscribe(&self) -> impl Stream<Item = Result<Event, ClientError>> {
+        let client = self.clone();
+
+        stream! {
+            let mut delay = RECONNECT_MIN;
+
+            loop {
+                let req = client
+                    .http
+                    .get(client.url("/data"))
+                    .header(ACCEPT, "text/event-stream");
+
+                match client.send(req).await {
+                    Ok(res) => {
+                        debug!("Subscribed to {}.", client.base);
+                        delay = RECONNECT_MIN;
+                        yield Ok(Event::Connected);
+
+                        let mut events = Box::pin(sse_events(res));
+                        while let Some(event) = events.next().await {
+                            let (kind, data) = match event {
+                                Ok(event) => event,
+                                Err(err) => {
+                                    yield Err(err);
+                                    continue;
+                                }
+                            };
+                            let event = match kind.as_str() {
+                                "message" => Event::Process,
+                                "removed" => Event::Removed,
+                                // Newer kinds of events.
+                                _ => continue,
+                            };
+                            yield serde_json::from_str(&data)
+                                .map(event)
+                                .map_err(ClientError::InvalidEvent);
+                        }
+                    }
+                    Err(err) => {
+                        let permanent = err.is_permanent();
+                        delay = delay.max(err.retry_after().unwrap_or_default());
+                        yield Err(err);
+                        if permanent {
+                            break;
+                        }
+                    }
+                }
+
+                debug!("Reconnecting to {} in {delay:?}.", client.base);
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(RECONNECT_MAX);
+            }
+        }
+    }
+
+    /// Returns the URL of the given path on the server.
+    fn url(&self, path: &str) -> String {
+        format!("{}{path}", self.base)
+    }
+
+    /// Builds a `GET` request of the given path, in JSON.
+    fn get(&self, path: &str) -> RequestBuilder {
+        self.http
+            .get(self.url(path))
+            .header(ACCEPT, "application/json")
+    }
+
+    /// Sends the given request, authenticated if a token is set, and turns
+    /// error replies into [`ClientError::Api`].
+    async fn send(&self, mut req: RequestBuilder) -> Result<Response, ClientError> {
+        if let Some(token) = &self.token {
+            req = req.bearer_auth(token);
+        }
+        let res = req.send().await?;
+        let status = res.status();
+
+        if status.is_success() {
+            return Ok(res);
+        }
+        let text = res.text().await?;
+        // Replies of something else than the API, e.g. of a proxy, have no
+        // such body: keep their text as the message.
+        let body = serde_json::from_str(&text).unwrap_or_else(|_| ErrorBody {
+            code: "unknown".to_owned(),
+            message: text,
+            details: None,
+        });
+        Err(ClientError::Api { status, body })
+    }
+}
+
+/// Extracts the type, `message` by default, and the data of the server-sent
+/// events of the given reply.
+fn sse_events(res: Response) -> impl Stream<Item = Result<(String, String), ClientError>> {
+    stream! {
+        let mut chunks = res.bytes_stream();
+        let mut buf = Vec::new();
+        let mut event = String::new();
+        let mut data = String::new();
+
+        while let Some(chunk) = chunks.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    yield Err(err.into());
+                    break;
+                }
+            };
+            buf.extend_from_slice(&chunk);
+
+            while let Some(end) = buf.iter().position(|&byte| byte == b'\n') {
+                let line = buf.drain(..=end).collect::<Vec<_>>();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim_end_matches(['\n', '\r']);
+
+                if line.is_empty() {
+                    let event = std::mem::take(&mut event);
+                    // Comments, e.g. keep-alives, dispatch events without data.
+                    if !data.is_empty() {
+                        let event = if event.is_empty() { "message".to_owned() } else { event };
+                        yield Ok((event, std::mem::take(&mut data)));
+                    }
+                } else if let Some(value) = line.strip_prefix("event:") {
+                    event = value.trim_start().to_owned();
+                } else if let Some(value) = line.strip_prefix("data:") {
+                    if !data.is_empty() {
+                        data.push('\n');
+                    }
+                    data.push_str(value.strip_prefix(' ').unwrap_or(value));
+                }
+            }
+        }
+    }
+}
+
+impl ClientError {
+    /// Tells whether retrying the request cannot succeed: the server rejected
+    /// it for something else than its load or a failure.
+    fn is_permanent(&self) -> bool {
+        match self {
+            Self::Api { status, .. } => {
+                status.is_client_error() && *status != StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the delay after which the server asked to retry, if any.
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::Api { body, .. } => body
+                .details
+                .as_ref()?
+                .get("retry_after")?
+                .as_u64()
+                .map(Duration::from_secs),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "HTTP error: {err}"),
+            Self::Api { status, body } => write!(f, "{status}: {} ({})", body.message, body.code),
+            Self::InvalidEvent(err) => write!(f, "Invalid event: {err}"),
+        }
+    }
+}
+
+impl Error for ClientError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Http(err) => Some(err),
+            Self::Api { .. } => None,
+            Self::InvalidEvent(err) => Some(err),
+        }
+    }
+}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
@@ -0,0 +1,267 @@
+//! This module defines the commands querying a remote server using the
+//! [`proc_api::client`], and how they print their results for humans.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::Write;
+use std::io::{self, IsTerminal};
+
+use anyhow::{Context, Result};
+use futures_util::StreamExt;
+use log::warn;
+use proc_api::client::{Client, Event};
+use proc_api::model::{CacheData, ProcInfo, SearchQuery};
+
+/// The port of the server when the host does not give one, as by `serve`.
+const DEFAULT_PORT: u16 = 8080;
+
+/// How to reach the server queried by a command.
+#[derive(clap::Args, Debug, PartialEq, Eq)]
+pub struct RemoteArgs {
+    /// The server to query, as `<host>[:<port>]`, the port being 8080 by
+    /// default, or as a URL, e.g. `https://example.com`.
+    #[arg(long, default_value = "127.0.0.1")]
+    pub host: String,
+    /// The bearer token to authenticate with, if the server requires one.
+    #[arg(long, env = "PROC_API_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+}
+
+impl RemoteArgs {
+    /// Builds the client of the server.
+    pub fn client(&self) -> Result<Client> {
+        let client = Client::new(base_url(&self.host)?);
+
+        Ok(match &self.token {
+            Some(token) => client.with_token(token),
+            None => client,
+        })
+    }
+}
+
+/// Returns the base URL of the server of the given `--host`.
+pub fn base_url(host: &str) -> Result<String> {
+    if host.contains("://") {
+        return Ok(host.to_owned());
+    }
+    let mut url = reqwest::Url::parse(&format!("http://{host}"))
+        .with_context(|| format!("Invalid host: {host}"))?;
+
+    if url.port().is_none() {
+        // Only fails for URLs that cannot have a port, unlike HTTP ones.
+        url.set_port(Some(DEFAULT_PORT)).unwrap();
+    }
+    Ok(url.into())
+}
+
+/// The arguments of the `ps` command.
+#[derive(clap::Args, Debug, PartialEq, Eq)]
+pub struct PsArgs {
+    #[command(flatten)]
+    pub remote: RemoteArgs,
+    /// Only list the processes of the given user name.
+    #[arg(long)]
+    pub user: Option<String>,
+    /// Only list the processes of the given name.
+    #[arg(long)]
+    pub name: Option<String>,
+    /// Only list the children of the process of the given identifier.
+    #[arg(long)]
+    pub ppid: Option<u32>,
+}
+
+/// Prints the processes of the server matching the filters as a table.
+pub async fn ps(args: PsArgs) -> Result<()> {
+    let client = args.remote.client()?;
+    let query = SearchQuery {
+        ppid: args.ppid,
+        name: args.name,
+        username: args.user,
+        ..Default::default()
+    };
+    let procs = if query.ppid.is_none() && query.name.is_none() && query.username.is_none() {
+        client.processes().await?
+    } else {
+        client.search(&query).await?
+    };
+
+    print!("{}", table(&procs));
+    Ok(())
+}
+
+/// Prints the processes discovered and removed by the refreshes of the
+/// server as they happen, until interrupted.
+pub async fn watch(args: RemoteArgs) -> Result<()> {
+    let client = args.client()?;
+    let color = io::stdout().is_terminal();
+    let mut known = None::<CacheData>;
+    let mut events = Box::pin(client.subscribe());
+    let mut last_err = None;
+
+    while let Some(event) = events.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(err) => {
+                warn!("{err}");
+                last_err = Some(err);
+                continue;
+            }
+        };
+        last_err = None;
+
+        match event {
+            // The stream sends all the processes again at each connection:
+            // only print what changed while it was not connected.
+            Event::Connected => {
+                let procs = client.processes().await?;
+
+                if let Some(known) = &known {
+                    for proc in known.difference(&procs) {
+                        println!("{}", change_line(false, proc, color));
+                    }
+                    for proc in procs.difference(known) {
+                        println!("{}", change_line(true, proc, color));
+                    }
+                }
+                known = Some(procs);
+            }
+            Event::Process(proc) => {
+                if known
+                    .get_or_insert_with(CacheData::new)
+                    .insert(proc.clone())
+                {
+                    println!("{}", change_line(true, &proc, color));
+                }
+            }
+            Event::Removed(proc) => {
+                if known.as_mut().is_some_and(|known| known.remove(&proc)) {
+                    println!("{}", change_line(false, &proc, color));
+                }
+            }
+        }
+    }
+
+    match last_err {
+        Some(err) => Err(err.into()),
+        None => Ok(()),
+    }
+}
+
+/// Refreshes the processes cached by the server.
+pub async fn refresh(args: RemoteArgs) -> Result<()> {
+    args.client()?.refresh().await?;
+    Ok(())
+}
+
+/// Prints the processes of the server as a tree of parents and children.
+pub async fn tree(args: RemoteArgs) -> Result<()> {
+    print!("{}", tree_lines(&args.client()?.processes().await?));
+    Ok(())
+}
+
+/// Formats the given processes as a table with a header row, sorted by PID.
+pub fn table(procs: &CacheData) -> String {
+    let mut procs = procs.iter().collect::<Vec<_>>();
+    procs.sort_unstable_by_key(|proc| proc.pid);
+
+    let rows = procs.iter().map(|proc| {
+        [
+            proc.pid.to_string(),
+            proc.ppid
+                .map_or_else(|| "-".to_owned(), |ppid| ppid.to_string()),
+            proc.uid.to_string(),
+            proc.username.clone(),
+            proc.name.clone(),
+        ]
+    });
+    let rows = [["PID", "PPID", "UID", "USER", "NAME"].map(str::to_owned)]
+        .into_iter()
+        .chain(rows)
+        .collect::<Vec<_>>();
+    let mut widths = [0; 5];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut table = String::new();
+    for [pid, ppid, uid, user, name] in rows {
+        // Writing to a string does not fail.
+        writeln!(
+            table,
+            "{pid:>w0$} {ppid:>w1$} {uid:>w2$} {user:<w3$} {name}",
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+            w3 = widths[3],
+        )
+        .unwrap();
+    }
+    table
+}
+
+/// Formats a line telling that the given process spawned or exited, colored
+/// in green or red if asked.
+pub fn change_line(spawned: bool, proc: &ProcInfo, color: bool) -> String {
+    let (sign, code) = if spawned { ('+', 32) } else { ('-', 31) };
+    let line = format!("{sign} {} {} ({})", proc.pid, proc.name, proc.username);
+
+    if color {
+        format!("\x1b[{code}m{line}\x1b[0m")
+    } else {
+        line
+    }
+}
+
+/// Formats the given processes as a tree, each under its parent, sorted by
+/// PID. Processes whose parent is not listed, e.g. not visible to the caller,
+/// are roots.
+pub fn tree_lines(procs: &CacheData) -> String {
+    let pids = procs.iter().map(|proc| proc.pid).collect::<HashSet<_>>();
+    let mut children = BTreeMap::<Option<u32>, Vec<&ProcInfo>>::new();
+
+    for proc in procs {
+        let parent = proc.ppid.filter(|ppid| pids.contains(ppid));
+        children.entry(parent).or_default().push(proc);
+    }
+    for procs in children.values_mut() {
+        procs.sort_unstable_by_key(|proc| proc.pid);
+    }
+
+    let mut lines = String::new();
+    for root in children.get(&None).into_iter().flatten() {
+        writeln!(lines, "{} {} ({})", root.pid, root.name, root.username).unwrap();
+        tree_children(&children, root.pid, "", &mut lines);
+    }
+    lines
+}
+
+/// Formats the descendants of the given process for [`tree_lines`], each line
+/// starting with the given prefix.
+fn tree_children(
+    children: &BTreeMap<Option<u32>, Vec<&ProcInfo>>,
+    pid: u32,
+    prefix: &str,
+    lines: &mut String,
+) {
+    let Some(procs) = children.get(&Some(pid)) else {
+        return;
+    };
+
+    for (i, proc) in procs.iter().enumerate() {
+        let last = i + 1 == procs.len();
+        let (branch, indent) = if last {
+            ("└─", "  ")
+        } else {
+            ("├─", "│ ")
+        };
+
+        writeln!(
+            lines,
+            "{prefix}{branch} {} {} ({})",
+            proc.pid, proc.name, proc.username
+        )
+        .unwrap();
+        tree_children(children, proc.pid, &format!("{prefix}{indent} "), lines);
+    }
+}
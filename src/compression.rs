@@ -0,0 +1,242 @@
+//! This module defines the compression of responses: which encodings are
+//! supported, how the one of a request is negotiated and how bodies are
+//! compressed, streamed ones included.
+
+use std::io::{self, Write};
+use std::mem;
+
+use async_stream::try_stream;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use futures_util::StreamExt;
+use warp::http::header::{CONTENT_ENCODING, CONTENT_LENGTH, ETAG, VARY};
+use warp::http::{HeaderMap, HeaderValue, StatusCode};
+use warp::hyper::body::HttpBody;
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+/// Bodies known to be smaller than this are not worth compressing.
+const MIN_SIZE: u64 = 256;
+/// Brotli's default quality is tuned for static content: too slow here.
+const BROTLI_QUALITY: u32 = 5;
+/// Brotli's default window size.
+const BROTLI_LGWIN: u32 = 22;
+
+/// A content coding in which responses can be compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    Zstd,
+    #[value(name = "br")]
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// All the supported encodings, by order of preference.
+    pub const ALL: &'static [Self] = &[Self::Zstd, Self::Brotli, Self::Gzip];
+
+    /// Returns the name of the content coding, as used in headers.
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// Selects the encoding of the highest quality in the `Accept-Encoding`
+    /// header among the enabled ones, the first enabled one between equal
+    /// qualities, as clients do not order their header by preference. `None`
+    /// means that the response is to be sent uncompressed.
+    pub fn negotiate(enabled: &[Self], accept: Option<&str>) -> Option<Self> {
+        let codings = accept?
+            .split(',')
+            .filter_map(|coding| {
+                let mut params = coding.split(';').map(str::trim);
+                let name = params.next().filter(|name| !name.is_empty())?;
+                let quality = params
+                    .filter_map(|param| param.strip_prefix("q="))
+                    .find_map(|q| q.parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                Some((name.to_ascii_lowercase(), quality))
+            })
+            .collect::<Vec<_>>();
+        let quality = |name: &str| {
+            codings
+                .iter()
+                .find(|(coding, _)| coding == name)
+                .map(|&(_, q)| q)
+        };
+
+        let mut best: Option<(Self, f32)> = None;
+
+        for &encoding in enabled {
+            let quality = quality(encoding.name())
+                .or_else(|| {
+                    (encoding == Self::Gzip)
+                        .then(|| quality("x-gzip"))
+                        .flatten()
+                })
+                .or_else(|| quality("*"))
+                .unwrap_or(0.0);
+
+            if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                best = Some((encoding, quality));
+            }
+        }
+
+        best.map(|(encoding, _)| encoding)
+    }
+}
+
+/// A compressor of a body, chunk after chunk.
+enum Encoder {
+    Zstd(zstd::Encoder<'static, Vec<u8>>),
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+    /// Builds a compressor in the given encoding.
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Zstd => Self::Zstd(zstd::Encoder::new(Vec::new(), 0)?),
+            Encoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                0,
+                BROTLI_QUALITY,
+                BROTLI_LGWIN,
+            ))),
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Default::default())),
+        })
+    }
+
+    /// Compresses the given chunk and flushes the compressor, so that the
+    /// returned data is enough to decompress everything given so far.
+    fn chunk(&mut self, chunk: &[u8]) -> io::Result<Vec<u8>> {
+        let writer: &mut dyn Write = match self {
+            Self::Zstd(enc) => enc,
+            Self::Brotli(enc) => enc,
+            Self::Gzip(enc) => enc,
+        };
+        writer.write_all(chunk)?;
+        writer.flush()?;
+
+        Ok(mem::take(match self {
+            Self::Zstd(enc) => enc.get_mut(),
+            Self::Brotli(enc) => enc.get_mut(),
+            Self::Gzip(enc) => enc.get_mut(),
+        }))
+    }
+
+    /// Ends the compressed data, returning what remains of it.
+    fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Zstd(enc) => enc.finish(),
+            Self::Brotli(enc) => Ok(enc.into_inner()),
+            Self::Gzip(enc) => enc.finish(),
+        }
+    }
+}
+
+/// Compresses the given reply in the given encoding, if any and if it is
+/// worth it.
+///
+/// Each chunk of the body is compressed and flushed on its own, so streams
+/// are delivered as soon as their items are produced, just like when
+/// uncompressed. The `ETag` of compressed replies, and of the `304 Not
+/// Modified` ones that could have been, is made weak, as the compressed bytes
+/// differ from the uncompressed ones.
+pub fn compress(encoding: Option<Encoding>, reply: impl Reply) -> Response {
+    let mut res = reply.into_response();
+
+    if res.status() == StatusCode::NOT_MODIFIED && encoding.is_some() {
+        weaken_etag(res.headers_mut());
+    }
+    if res.headers().contains_key(CONTENT_ENCODING)
+        || matches!(
+            res.status(),
+            StatusCode::NO_CONTENT | StatusCode::NOT_MODIFIED
+        )
+        || res
+            .body()
+            .size_hint()
+            .exact()
+            .is_some_and(|size| size < MIN_SIZE)
+    {
+        return res;
+    }
+    res.headers_mut()
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+
+    let Some(encoding) = encoding else {
+        return res;
+    };
+    let mut encoder = match Encoder::new(encoding) {
+        Ok(encoder) => encoder,
+        Err(err) => {
+            error!("Unable to compress reply in {}: {err:#}", encoding.name());
+            return res;
+        }
+    };
+
+    let (mut parts, mut body) = res.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    weaken_etag(&mut parts.headers);
+    parts
+        .headers
+        .insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+
+    let body = Body::wrap_stream::<_, _, io::Error>(try_stream! {
+        while let Some(chunk) = body.next().await {
+            let chunk = encoder.chunk(&chunk.map_err(io::Error::other)?)?;
+
+            if !chunk.is_empty() {
+                yield Bytes::from(chunk);
+            }
+        }
+        yield Bytes::from(encoder.finish()?);
+    });
+    Response::from_parts(parts, body)
+}
+
+/// Turns the strong `ETag` of the given headers, if any, into a weak one, which
+/// still matches it in `If-None-Match`.
+fn weaken_etag(headers: &mut HeaderMap) {
+    let Some(etag) = headers.get(ETAG) else {
+        return;
+    };
+    if !etag.as_bytes().starts_with(b"W/") {
+        let mut weak = b"W/".to_vec();
+        weak.extend_from_slice(etag.as_bytes());
+        // Prefixing a valid value keeps it valid.
+        headers.insert(ETAG, HeaderValue::from_bytes(&weak).unwrap());
+    }
+}
+
+/// Filter negotiating the [`Encoding`] of the request, among the enabled
+/// ones, from its `Accept-Encoding` header.
+pub fn negotiate(
+    enabled: Vec<Encoding>,
+) -> impl Filter<Extract = (Option<Encoding>,), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String>("accept-encoding")
+        .or(warp::any().map(|| None))
+        .unify()
+        .map(move |accept: Option<String>| Encoding::negotiate(&enabled, accept.as_deref()))
+}
+
+/// Wraps the given filter so that its replies are compressed in the encoding
+/// negotiated among the enabled ones.
+pub fn compressed<F, R>(
+    enabled: Vec<Encoding>,
+    filter: F,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone
+where
+    F: Filter<Extract = (R,), Error = warp::Rejection> + Clone + Send + Sync,
+    R: Reply,
+{
+    negotiate(enabled)
+        .and(filter)
+        .map(|encoding, reply: R| compress(encoding, reply))
+}
@@ -0,0 +1,96 @@
+//! This module defines the conditional requests supported by the endpoints
+//! returning the processes of a snapshot, so that pollers do not download them
+//! again when nothing has been refreshed since their previous request.
+//!
+//! The validators of a reply are derived from its snapshot: the `ETag` from
+//! its ID, which is the generation of the refresh that produced it, and its
+//! time, as IDs start over when the server restarts without a state directory,
+//! from the format of the reply and from the processes visible to the caller,
+//! and the `Last-Modified` date from its time.
+
+use std::convert::Infallible;
+use std::time::UNIX_EPOCH;
+
+use headers::{ETag, HeaderMapExt, IfModifiedSince, IfNoneMatch, LastModified};
+use warp::http::StatusCode;
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+use crate::auth::Visibility;
+use crate::format::Format;
+use crate::proc::Snapshot;
+
+/// The preconditions of a request, as given by its headers.
+#[derive(Debug, Default)]
+pub struct Conditions {
+    if_none_match: Option<IfNoneMatch>,
+    if_modified_since: Option<IfModifiedSince>,
+}
+
+impl Conditions {
+    /// Replies `304 Not Modified` if the client already has the processes of
+    /// the given snapshot visible to it in the given format, otherwise builds
+    /// the reply and adds the validators of the snapshot to it.
+    pub fn reply(
+        &self,
+        snap: &Snapshot,
+        format: Format,
+        visibility: Visibility,
+        reply: impl FnOnce() -> Box<dyn Reply>,
+    ) -> Box<dyn Reply> {
+        let etag = Self::etag(snap, format, visibility);
+        let last_modified = LastModified::from(snap.time);
+
+        let mut res = if self.is_modified(snap, &etag) {
+            reply().into_response()
+        } else {
+            let mut res = Response::default();
+            *res.status_mut() = StatusCode::NOT_MODIFIED;
+            res
+        };
+
+        // Errors are never cached, so they do not get validators.
+        if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED {
+            res.headers_mut().typed_insert(etag);
+            res.headers_mut().typed_insert(last_modified);
+        }
+        Box::new(res)
+    }
+
+    /// Tells whether the snapshot of the given `ETag` should be sent: only
+    /// `If-None-Match` is evaluated when both preconditions are given.
+    fn is_modified(&self, snap: &Snapshot, etag: &ETag) -> bool {
+        match (&self.if_none_match, &self.if_modified_since) {
+            (Some(if_none_match), _) => if_none_match.precondition_passes(etag),
+            (None, Some(if_modified_since)) => if_modified_since.is_modified(snap.time),
+            (None, None) => true,
+        }
+    }
+
+    /// Builds the `ETag` of the processes of the given snapshot visible with
+    /// the given visibility in the given format.
+    fn etag(snap: &Snapshot, format: Format, visibility: Visibility) -> ETag {
+        let time = snap
+            .time
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let tag = match visibility {
+            Visibility::All => format!("\"{}-{time:x}-{}\"", snap.id, format.name()),
+            Visibility::Owner(uid) => {
+                format!("\"{}-{time:x}-{}-u{uid}\"", snap.id, format.name())
+            }
+        };
+        // The tag only contains digits, letters and dashes: it is valid.
+        tag.parse().unwrap()
+    }
+}
+
+/// Filter extracting the [`Conditions`] of the request from its headers,
+/// ignoring invalid ones as required for conditional requests.
+pub fn conditions() -> impl Filter<Extract = (Conditions,), Error = Infallible> + Clone {
+    warp::header::headers_cloned().map(|headers: warp::http::HeaderMap| Conditions {
+        if_none_match: headers.typed_get(),
+        if_modified_since: headers.typed_get(),
+    })
+}
@@ -0,0 +1,70 @@
+//! This module defines the configuration of the server: the tokens it accepts,
+//! who sees which processes, the limits of each client and whether signals
+//! are allowed.
+//!
+//! It is set up before serving and only read afterwards, so unlike the
+//! [`crate::proc::ProcCache`] it is shared without a lock: authenticating and
+//! limiting requests never waits for a refresh of the cache.
+
+use std::sync::Arc;
+
+use crate::auth::{Subjects, Tokens};
+use crate::limits::Limits;
+
+/// The configuration shared by the routes.
+///
+/// Instantiate using [`Default`].
+pub type SharedConfig = Arc<Config>;
+
+/// The configuration of the server, everything being disabled by default.
+#[derive(Debug, Default)]
+pub struct Config {
+    tokens: Option<Tokens>,
+    subjects: Option<Subjects>,
+    limits: Limits,
+    signals: bool,
+}
+
+impl Config {
+    /// Requires requests to carry one of the given tokens.
+    pub fn set_tokens(&mut self, tokens: Tokens) {
+        self.tokens = Some(tokens);
+    }
+
+    /// Returns the tokens accepted by the server, if authentication is enabled.
+    pub fn tokens(&self) -> Option<&Tokens> {
+        self.tokens.as_ref()
+    }
+
+    /// Only shows callers their own processes, identifying those presenting
+    /// client certificates with the given subjects.
+    pub fn restrict_visibility(&mut self, subjects: Subjects) {
+        self.subjects = Some(subjects);
+    }
+
+    /// Returns the identities of the client certificate subjects, if processes
+    /// are only visible to their owner.
+    pub fn subjects(&self) -> Option<&Subjects> {
+        self.subjects.as_ref()
+    }
+
+    /// Limits the requests of each client as given.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Returns the limits on the requests of each client.
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Lets callers send signals to the processes visible to them.
+    pub fn allow_signals(&mut self) {
+        self.signals = true;
+    }
+
+    /// Tells whether callers can send signals to processes.
+    pub fn signals_allowed(&self) -> bool {
+        self.signals
+    }
+}
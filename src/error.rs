@@ -0,0 +1,165 @@
+//! This module defines the errors replied by the API, all with a JSON body
+//! made of a machine-readable code, a human-readable message and optional
+//! details, see [`ApiError`], and the recovery of rejections into such errors,
+//! see [`recover`].
+
+use serde::Serialize;
+use serde_json::Value;
+use warp::filters::body::BodyDeserializeError;
+use warp::http::header::{HeaderName, HeaderValue};
+use warp::http::{HeaderMap, StatusCode};
+use warp::reject::{
+    InvalidHeader, InvalidQuery, LengthRequired, MethodNotAllowed, MissingHeader, PayloadTooLarge,
+    Reject, UnsupportedMediaType,
+};
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+use crate::format::Format;
+use crate::model::ErrorBody;
+
+/// An error replied by the API, either directly by handlers or by rejecting
+/// the request in filters.
+#[derive(Debug, Clone)]
+pub struct ApiError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+    details: Option<Value>,
+    headers: HeaderMap,
+}
+
+impl ApiError {
+    /// Builds an error of the given status, code and message, without details.
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+            details: None,
+            headers: HeaderMap::new(),
+        }
+    }
+
+    /// Builds a `400 Bad Request` error of the given code and message.
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    /// Builds a `404 Not Found` error of the given message.
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, "not_found", message)
+    }
+
+    /// Builds a `406 Not Acceptable` error detailing the supported formats.
+    pub fn not_acceptable(supported: &[Format]) -> Self {
+        Self::new(
+            StatusCode::NOT_ACCEPTABLE,
+            "not_acceptable",
+            "None of the supported formats is acceptable.",
+        )
+        .with_details(serde_json::json!({
+            "supported": supported.iter().map(|format| format.name()).collect::<Vec<_>>(),
+        }))
+    }
+
+    /// Builds a `500 Internal Server Error` error of the given code and
+    /// message, detailing the chain of causes of the given error.
+    pub fn internal(code: &'static str, message: &str, err: &anyhow::Error) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            code,
+            format!("{message}: {err}"),
+        )
+        .with_details(serde_json::json!({
+            "causes": err.chain().map(ToString::to_string).collect::<Vec<_>>(),
+        }))
+    }
+
+    /// Adds the given details to the error.
+    pub fn with_details(mut self, details: impl Serialize) -> Self {
+        self.details = serde_json::to_value(details).ok();
+        self
+    }
+
+    /// Adds the given header to the reply of the error.
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.insert(name, value);
+        self
+    }
+}
+
+impl Reject for ApiError {}
+
+impl Reply for ApiError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            code: self.code.to_owned(),
+            message: self.message,
+            details: self.details,
+        };
+        let mut res =
+            warp::reply::with_status(warp::reply::json(&body), self.status).into_response();
+
+        res.headers_mut().extend(self.headers);
+        res
+    }
+}
+
+/// Turns every rejection into an [`ApiError`] reply: the ones rejected with
+/// such an error as they are, and warp's own ones into their equivalent, e.g.
+/// `404 Not Found` for unknown paths and `405 Method Not Allowed` for wrong
+/// methods.
+pub async fn recover(rejection: Rejection) -> Result<ApiError, Rejection> {
+    if let Some(err) = rejection.find::<ApiError>() {
+        return Ok(err.clone());
+    }
+    if rejection.is_not_found() {
+        return Ok(ApiError::not_found("No such endpoint."));
+    }
+
+    // Warp keeps the rejections of all the routes that were tried: wrong
+    // methods are only reported when no route of the right method rejected
+    // the request for another reason.
+    let err = if let Some(err) = rejection.find::<InvalidQuery>() {
+        ApiError::bad_request("invalid_query", err.to_string())
+    } else if let Some(err) = rejection.find::<BodyDeserializeError>() {
+        ApiError::bad_request("invalid_body", err.to_string())
+    } else if let Some(err) = rejection.find::<InvalidHeader>() {
+        ApiError::bad_request("invalid_header", err.to_string())
+    } else if let Some(err) = rejection.find::<MissingHeader>() {
+        ApiError::bad_request("missing_header", err.to_string())
+    } else if let Some(err) = rejection.find::<LengthRequired>() {
+        ApiError::new(
+            StatusCode::LENGTH_REQUIRED,
+            "length_required",
+            err.to_string(),
+        )
+    } else if let Some(err) = rejection.find::<PayloadTooLarge>() {
+        ApiError::new(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            err.to_string(),
+        )
+    } else if let Some(err) = rejection.find::<UnsupportedMediaType>() {
+        ApiError::new(
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            "unsupported_media_type",
+            err.to_string(),
+        )
+    } else if let Some(err) = rejection.find::<MethodNotAllowed>() {
+        ApiError::new(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "method_not_allowed",
+            err.to_string(),
+        )
+    } else {
+        error!("Unhandled rejection: {rejection:?}");
+        ApiError::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "Unhandled rejection.",
+        )
+    };
+    Ok(err)
+}
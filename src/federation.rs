@@ -0,0 +1,272 @@
+//! This module defines the aggregator mode of the server, behind both the
+//! `server` and `client` features: `GET /processes`, `GET /search` and
+//! `GET /data` fanned out to upstream `proc-api` servers through the
+//! [`crate::client`], each process being tagged with the host that listed it.
+//!
+//! Upstream servers that cannot be reached are reported by host instead of
+//! failing the whole request: in the `errors` of the [`AggregatedProcs`], or
+//! as `upstream_error` events of the stream.
+//!
+//! Clients are authenticated and limited as by the [`crate::routes`] of a
+//! server, following the given [`crate::config::Config`].
+
+use std::convert::Infallible;
+use std::future::Future;
+use std::sync::Arc;
+
+use futures_util::future;
+use futures_util::stream::{self, StreamExt};
+use tokio::time::{self, Duration};
+use warp::{sse, Filter};
+
+use crate::auth::{self, Scope};
+use crate::client::{Client, ClientError, Event};
+use crate::config::SharedConfig;
+use crate::error::{self, ApiError};
+use crate::limits::{self, Endpoint, StreamPermit};
+use crate::model::{
+    AggregatedProcs, CacheData, ErrorBody, HostProcInfo, ProcInfo, SearchQuery, UpstreamError,
+};
+
+/// Maximum time to wait for the reply of each upstream server when listing
+/// processes, before reporting it as timed out, and for connections to it to
+/// be established, streams included.
+pub const UPSTREAM_TOUT: Duration = Duration::from_secs(10);
+
+/// An upstream server of the aggregator.
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    host: String,
+    client: Client,
+}
+
+impl Upstream {
+    /// Builds the upstream server queried with the given client, its
+    /// processes being tagged with the given host.
+    pub fn new(host: impl Into<String>, client: Client) -> Self {
+        Self {
+            host: host.into(),
+            client,
+        }
+    }
+
+    /// Returns the host tagging the processes of the server.
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+}
+
+/// The upstream servers shared by the routes.
+pub type Upstreams = Arc<[Upstream]>;
+
+/// Global route of the aggregator dispatching to all the other routes of the
+/// [module](`self`), each requiring its [`Scope`] if authentication is enabled
+/// by the given configuration, and some limited per client if limits are.
+/// Rejected requests are replied with JSON errors by [`error::recover`].
+pub fn routes(
+    upstreams: Vec<Upstream>,
+    config: &SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let upstreams = Upstreams::from(upstreams);
+
+    list_procs(Arc::clone(&upstreams), Arc::clone(config))
+        .or(search_procs(Arc::clone(&upstreams), Arc::clone(config)))
+        .or(stream_procs(upstreams, Arc::clone(config)))
+        .recover(error::recover)
+}
+
+/// Route listing the processes of all the upstream servers, limited as
+/// searches since it is sent to each of them as well.
+pub fn list_procs(
+    upstreams: Upstreams,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("processes")
+        .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(limits::rate(Endpoint::Search, config))
+        .and(with_upstreams(upstreams))
+        .then(|upstreams: Upstreams| async move {
+            warp::reply::json(&fan_out(&upstreams, Client::processes).await)
+        })
+}
+
+/// Route searching the processes of all the upstream servers, forwarding the
+/// parameters of the request to each of them, limited as searches.
+pub fn search_procs(
+    upstreams: Upstreams,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("search")
+        .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(limits::rate(Endpoint::Search, config))
+        .and(warp::query::<SearchQuery>())
+        .and(with_upstreams(upstreams))
+        .and_then(search)
+}
+
+/// Route multiplexing the streams of processes of all the upstream servers
+/// into a single SSE one, counted as one stream in the limits of the client.
+pub fn stream_procs(
+    upstreams: Upstreams,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("data")
+        .and(warp::get())
+        .and(auth::require(Scope::Stream, Arc::clone(&config)))
+        .and(limits::stream(config))
+        .and(with_upstreams(upstreams))
+        .map(stream)
+}
+
+/// Handles [`search_procs`], rejecting queries without filters before sending
+/// them upstream.
+async fn search(
+    query: SearchQuery,
+    upstreams: Upstreams,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if let SearchQuery {
+        pid: None,
+        ppid: None,
+        uid: None,
+        name: None,
+        username: None,
+        ..
+    } = query
+    {
+        return Ok(Box::new(ApiError::bad_request(
+            "missing_filter",
+            "At least one of pid, ppid, uid, name and username is required.",
+        )));
+    }
+    let procs = fan_out(&upstreams, |client| client.search(&query)).await;
+    Ok(Box::new(warp::reply::json(&procs)))
+}
+
+/// Handles [`stream_procs`]: the events of each upstream server are sent as
+/// they are received, their processes tagged with its host.
+///
+/// Each upstream server reconnected sends a `connected` event with its host,
+/// followed by all its processes again, and each error of an upstream server
+/// is sent as an `upstream_error` event, see [`Client::subscribe`].
+///
+/// The given permit is held until the client disconnects.
+fn stream(permit: StreamPermit, upstreams: Upstreams) -> impl warp::Reply {
+    let events = stream::select_all(upstreams.iter().map(|upstream| {
+        let host = upstream.host.clone();
+        upstream
+            .client
+            .subscribe()
+            .map(move |event| Ok::<_, Infallible>(upstream_event(&host, event)))
+            .boxed()
+    }))
+    .map(move |event| {
+        let _permit = &permit;
+        event
+    });
+
+    sse::reply(sse::keep_alive().stream(events))
+}
+
+/// Builds the SSE event forwarding the given event of the upstream server of
+/// the given host.
+fn upstream_event(host: &str, event: Result<Event, ClientError>) -> sse::Event {
+    let tagged = |proc| HostProcInfo {
+        host: host.to_owned(),
+        proc,
+    };
+    let event = match event {
+        Ok(Event::Connected) => sse::Event::default()
+            .event("connected")
+            .json_data(serde_json::json!({ "host": host })),
+        Ok(Event::Process(proc)) => sse::Event::default().json_data(tagged(proc)),
+        Ok(Event::Removed(proc)) => sse::Event::default()
+            .event("removed")
+            .json_data(tagged(proc)),
+        Err(err) => {
+            warn!("Upstream {host}: {err}");
+            sse::Event::default()
+                .event("upstream_error")
+                .json_data(UpstreamError {
+                    host: host.to_owned(),
+                    error: error_body(err),
+                })
+        }
+    };
+    // Only fails for data that is not serializable to JSON.
+    event.unwrap()
+}
+
+/// Sends the given request to all the upstream servers concurrently, merging
+/// the processes they reply with and reporting the errors of the others.
+async fn fan_out<'a, F, Fut>(upstreams: &'a [Upstream], request: F) -> AggregatedProcs
+where
+    F: Fn(&'a Client) -> Fut,
+    Fut: Future<Output = Result<CacheData, ClientError>>,
+{
+    let replies = future::join_all(
+        upstreams
+            .iter()
+            .map(|upstream| time::timeout(UPSTREAM_TOUT, request(&upstream.client))),
+    )
+    .await;
+    let mut aggregated = AggregatedProcs::default();
+
+    for (upstream, reply) in upstreams.iter().zip(replies) {
+        let err = match reply {
+            Ok(Ok(procs)) => {
+                aggregated
+                    .processes
+                    .extend(procs.into_iter().map(|proc: ProcInfo| HostProcInfo {
+                        host: upstream.host.clone(),
+                        proc,
+                    }));
+                continue;
+            }
+            Ok(Err(err)) => {
+                warn!("Upstream {}: {err}", upstream.host);
+                error_body(err)
+            }
+            Err(_) => {
+                warn!("Upstream {}: no reply in time.", upstream.host);
+                ErrorBody {
+                    code: "upstream_timeout".to_owned(),
+                    message: format!("No reply within {} seconds.", UPSTREAM_TOUT.as_secs()),
+                    details: None,
+                }
+            }
+        };
+        aggregated.errors.insert(upstream.host.clone(), err);
+    }
+
+    aggregated
+        .processes
+        .sort_unstable_by(|a, b| (&a.host, a.proc.pid).cmp(&(&b.host, b.proc.pid)));
+    aggregated
+}
+
+/// Turns the given error of a request to an upstream server into the body
+/// reporting it: the error replied by the server as is, otherwise an
+/// `unreachable` or `invalid_event` one.
+fn error_body(err: ClientError) -> ErrorBody {
+    let (code, message) = match err {
+        ClientError::Api { body, .. } => return body,
+        ClientError::Http(err) => ("unreachable", format!("{:#}", anyhow::Error::new(err))),
+        ClientError::InvalidEvent(err) => ("invalid_event", err.to_string()),
+    };
+
+    ErrorBody {
+        code: code.to_owned(),
+        message,
+        details: None,
+    }
+}
+
+/// Convenience shortcut to add the upstream servers as an argument of each
+/// handler.
+fn with_upstreams(
+    upstreams: Upstreams,
+) -> impl Filter<Extract = (Upstreams,), Error = Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&upstreams))
+}
@@ -0,0 +1,285 @@
+//! This module defines the formats in which process lists can be returned and
+//! how the one of a request is negotiated.
+
+use std::convert::Infallible;
+
+use anyhow::Result;
+use serde::Deserialize;
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::Filter;
+
+use crate::error::ApiError;
+use crate::proc::{ProcInfo, Snapshot};
+
+/// A format in which process data can be returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    Tsv,
+    MsgPack,
+    Cbor,
+    /// Server-Sent Events, each event being a JSON process.
+    EventStream,
+    /// Newline-delimited JSON, one process per line.
+    Ndjson,
+}
+
+/// Defines the acceptable parameter overriding the `Accept` header.
+#[derive(Debug, Deserialize)]
+pub struct FormatQuery {
+    pub format: Option<String>,
+}
+
+impl Format {
+    /// The formats supported by process lists, the first one being the default.
+    pub const LISTS: &'static [Self] =
+        &[Self::Json, Self::Csv, Self::Tsv, Self::MsgPack, Self::Cbor];
+    /// The formats supported by process streams, the first one being the
+    /// default. Binary formats are then streamed as sequences of processes.
+    pub const STREAMS: &'static [Self] =
+        &[Self::EventStream, Self::Ndjson, Self::MsgPack, Self::Cbor];
+
+    /// Returns the short name of the format, as accepted in queries.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Csv => "csv",
+            Self::Tsv => "tsv",
+            Self::MsgPack => "msgpack",
+            Self::Cbor => "cbor",
+            Self::EventStream => "sse",
+            Self::Ndjson => "ndjson",
+        }
+    }
+
+    /// Returns the media types of the format, the first one being the one
+    /// replied with, the others being accepted aliases.
+    fn media_types(self) -> &'static [&'static str] {
+        match self {
+            Self::Json => &["application/json"],
+            Self::Csv => &["text/csv"],
+            Self::Tsv => &["text/tab-separated-values"],
+            Self::MsgPack => &[
+                "application/msgpack",
+                "application/x-msgpack",
+                "application/vnd.msgpack",
+            ],
+            Self::Cbor => &["application/cbor", "application/cbor-seq"],
+            Self::EventStream => &["text/event-stream"],
+            Self::Ndjson => &["application/x-ndjson", "application/jsonl"],
+        }
+    }
+
+    /// Returns the media type replied with for a list of processes in the
+    /// format.
+    pub fn media_type(self) -> &'static str {
+        self.media_types()[0]
+    }
+
+    /// Returns the media type replied with for a stream of processes in the
+    /// format, which differs from the one of a list for CBOR.
+    pub fn stream_media_type(self) -> &'static str {
+        match self {
+            Self::Cbor => "application/cbor-seq",
+            _ => self.media_type(),
+        }
+    }
+
+    /// Selects the format explicitly named in the query if any, otherwise the
+    /// one most preferred by the `Accept` header, among the supported ones.
+    /// `None` means that no supported format is acceptable.
+    pub fn negotiate(
+        supported: &[Self],
+        query: Option<&str>,
+        accept: Option<&str>,
+    ) -> Option<Self> {
+        if let Some(name) = query {
+            return supported.iter().copied().find(|fmt| fmt.name() == name);
+        }
+        let Some(accept) = accept else {
+            return Some(supported[0]);
+        };
+
+        // Keep the first format of the highest quality, so the order of the
+        // header is respected between equal qualities.
+        let mut best: Option<(Self, f32)> = None;
+
+        for range in accept.split(',') {
+            let mut params = range.split(';').map(str::trim);
+            let media_range = params.next().unwrap_or_default().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| param.strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            let format = match media_range.strip_suffix("/*") {
+                Some("*") => Some(supported[0]),
+                Some(kind) => supported.iter().copied().find(|fmt| {
+                    fmt.media_types()[0]
+                        .strip_prefix(kind)
+                        .is_some_and(|sub| sub.starts_with('/'))
+                }),
+                None => supported
+                    .iter()
+                    .copied()
+                    .find(|fmt| fmt.media_types().contains(&media_range.as_str())),
+            };
+
+            if let Some(format) = format {
+                if quality > 0.0 && best.is_none_or(|(_, q)| quality > q) {
+                    best = Some((format, quality));
+                }
+            }
+        }
+
+        best.map(|(format, _)| format)
+    }
+
+    /// Builds the reply listing the given processes in the format, which must
+    /// be one of [`Self::LISTS`].
+    pub fn reply<'a>(self, procs: impl IntoIterator<Item = &'a ProcInfo>) -> Box<dyn warp::Reply> {
+        let procs = procs.into_iter().collect::<Vec<_>>();
+        let body = match self {
+            Self::Json => return Box::new(warp::reply::json(&procs)),
+            Self::Csv | Self::Tsv => self.delimited(procs),
+            Self::MsgPack => rmp_serde::to_vec_named(&procs).map_err(Into::into),
+            Self::Cbor => {
+                let mut body = Vec::new();
+                ciborium::into_writer(&procs, &mut body)
+                    .map(|_| body)
+                    .map_err(Into::into)
+            }
+            Self::EventStream | Self::Ndjson => unreachable!("{self:?} is not a list format."),
+        };
+
+        match body {
+            Ok(body) => Box::new(warp::reply::with_header(
+                body,
+                "content-type",
+                match self {
+                    Self::Csv | Self::Tsv => {
+                        format!("{}; charset=utf-8; header=present", self.media_types()[0])
+                    }
+                    _ => self.media_types()[0].to_owned(),
+                },
+            )),
+            Err(err) => {
+                error!("Unable to write {} reply: {err:#}", self.name());
+                Box::new(ApiError::internal(
+                    "encoding_failed",
+                    &format!("Unable to write {} reply", self.name()),
+                    &err,
+                ))
+            }
+        }
+    }
+
+    /// Builds the reply listing all the processes of the given snapshot in the
+    /// format, which must be one of [`Self::LISTS`].
+    ///
+    /// JSON being the most requested, the snapshot's cached serialization is
+    /// used for it instead of serializing the processes again.
+    pub fn snapshot_reply(self, snap: &Snapshot) -> Box<dyn warp::Reply> {
+        match self {
+            Self::Json => Box::new(warp::reply::with_header(
+                Response::new(Body::from(snap.json())),
+                "content-type",
+                self.media_types()[0],
+            )),
+            _ => self.reply(&snap.procs),
+        }
+    }
+
+    /// Encodes a single process as an item of a stream in the format, which
+    /// must be one of [`Self::STREAMS`] apart from [`Self::EventStream`], the
+    /// latter being handled by [`warp::sse`].
+    pub fn encode_item(self, proc: &ProcInfo) -> Result<Vec<u8>> {
+        Ok(match self {
+            Self::Ndjson => {
+                let mut item = serde_json::to_vec(proc)?;
+                item.push(b'\n');
+                item
+            }
+            Self::MsgPack => rmp_serde::to_vec_named(proc)?,
+            Self::Cbor => {
+                let mut item = Vec::new();
+                ciborium::into_writer(proc, &mut item)?;
+                item
+            }
+            _ => unreachable!("{self:?} is not a stream item format."),
+        })
+    }
+
+    /// Writes the processes as delimiter-separated values, with a header row,
+    /// by PID so that the rows of unchanged processes can be compared.
+    fn delimited(self, mut procs: Vec<&ProcInfo>) -> Result<Vec<u8>> {
+        procs.sort_unstable_by_key(|proc| proc.pid);
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(if self == Self::Tsv { b'\t' } else { b',' })
+            // Written by hand so that it is present even without any process.
+            .has_headers(false)
+            .from_writer(Vec::new());
+
+        writer.write_record(ProcInfo::FIELDS)?;
+        for proc in procs {
+            writer.serialize(proc)?;
+        }
+
+        Ok(writer.into_inner().map_err(|err| err.into_error())?)
+    }
+}
+
+/// Filter negotiating the [`Format`] of the request, among the supported
+/// ones, from its `format` query parameter and `Accept` header.
+pub fn negotiate(
+    supported: &'static [Format],
+) -> impl Filter<Extract = (Option<Format>,), Error = Infallible> + Clone {
+    requested().map(move |query: FormatQuery, accept: Option<String>| {
+        Format::negotiate(supported, query.format.as_deref(), accept.as_deref())
+    })
+}
+
+/// Filter negotiating the [`Format`] of a stream like [`negotiate`] does
+/// among [`Format::STREAMS`], but falling back to the default one, SSE, when
+/// the `Accept` header only asks for unsupported textual formats, e.g.
+/// `application/json`: streams used to be SSE whatever the header.
+pub fn negotiate_stream() -> impl Filter<Extract = (Option<Format>,), Error = Infallible> + Clone {
+    requested().map(|query: FormatQuery, accept: Option<String>| {
+        let format = query.format.as_deref();
+        let accept = accept.as_deref();
+
+        Format::negotiate(Format::STREAMS, format, accept).or_else(|| {
+            (format.is_none() && accept.is_some_and(textual)).then_some(Format::STREAMS[0])
+        })
+    })
+}
+
+/// Filter extracting the `format` query parameter and `Accept` header of the
+/// request, both being optional.
+fn requested() -> impl Filter<Extract = (FormatQuery, Option<String>), Error = Infallible> + Clone {
+    warp::query::<FormatQuery>()
+        .or(warp::any().map(|| FormatQuery { format: None }))
+        .unify()
+        .and(
+            warp::header::optional::<String>("accept")
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+}
+
+/// Tells whether all the media ranges of the given `Accept` header are
+/// textual, JSON included.
+fn textual(accept: &str) -> bool {
+    accept.split(',').all(|range| {
+        let media_range = range
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+        media_range.starts_with("text/")
+            || media_range == "application/json"
+            || media_range.ends_with("+json")
+    })
+}
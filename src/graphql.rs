@@ -0,0 +1,425 @@
+//! This module defines the GraphQL schema of the API, served by
+//! [`crate::routes::graphql`] and [`crate::routes::graphql_subscriptions`]:
+//! the processes of the current snapshot, generated from [`ProcInfo`] with
+//! their parent, children and user resolved, and a subscription to the
+//! processes spawned and exited.
+//!
+//! As nested lists, e.g. `children` or `user { processes }`, multiply the
+//! processes resolved by a single query, queries are limited in depth, in
+//! complexity, each list counting as [`LIST_COMPLEXITY`] times its items, and
+//! in aliases.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use async_graphql::async_trait::async_trait;
+use async_graphql::connection::{self, Connection, Edge};
+use async_graphql::extensions::{Extension, ExtensionContext, ExtensionFactory, NextParseQuery};
+use async_graphql::parser::types::{ExecutableDocument, Selection, SelectionSet};
+use async_graphql::{
+    ComplexObject, Context, EmptyMutation, Enum, InputObject, Object, Result, Schema, ServerError,
+    ServerResult, SimpleObject, Variables,
+};
+use async_stream::stream;
+use futures_util::Stream;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::auth::Visibility;
+use crate::model::SearchQuery;
+use crate::proc::{CacheData, Change, ProcCache, ProcInfo, Snapshot};
+
+/// Maximum depth of queries, bounding the nesting of parents and children.
+const MAX_DEPTH: usize = 32;
+/// Maximum complexity of queries, each field counting as one.
+const MAX_COMPLEXITY: usize = 10_000;
+/// Complexity of each item of lists of processes, by which the complexity of
+/// their fields is multiplied, as their length is only known once resolved.
+const LIST_COMPLEXITY: usize = 50;
+/// Maximum number of aliased fields in queries, as each one resolves its field
+/// again.
+const MAX_ALIASES: usize = 16;
+
+/// The schema served by the routes.
+pub type ProcSchema = Schema<Query, EmptyMutation, Subscription>;
+
+/// Builds the schema over the given cache.
+pub fn schema(cache: ProcCache) -> ProcSchema {
+    Schema::build(Query, EmptyMutation, Subscription)
+        .data(cache)
+        .limit_depth(MAX_DEPTH)
+        .limit_recursive_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .extension(AliasLimit)
+        .finish()
+}
+
+/// Extension rejecting queries with more than [`MAX_ALIASES`] aliased fields.
+struct AliasLimit;
+
+impl ExtensionFactory for AliasLimit {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(AliasLimit)
+    }
+}
+
+#[async_trait]
+impl Extension for AliasLimit {
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let doc = next.run(ctx, query, variables).await?;
+        let aliases = doc
+            .operations
+            .iter()
+            .map(|(_, op)| &op.node.selection_set.node)
+            .chain(
+                doc.fragments
+                    .values()
+                    .map(|frag| &frag.node.selection_set.node),
+            )
+            .map(aliases)
+            .sum::<usize>();
+
+        if aliases > MAX_ALIASES {
+            return Err(ServerError::new(
+                format!("Query has {aliases} aliases, more than the maximum of {MAX_ALIASES}."),
+                None,
+            ));
+        }
+        Ok(doc)
+    }
+}
+
+/// Counts the aliased fields of the given selection set, nested ones included.
+fn aliases(set: &SelectionSet) -> usize {
+    set.items
+        .iter()
+        .map(|item| match &item.node {
+            Selection::Field(field) => {
+                usize::from(field.node.alias.is_some()) + aliases(&field.node.selection_set.node)
+            }
+            Selection::InlineFragment(frag) => aliases(&frag.node.selection_set.node),
+            Selection::FragmentSpread(_) => 0,
+        })
+        .sum()
+}
+
+/// The processes visible to a caller, indexed by PID and by parent in order to
+/// resolve nested fields.
+#[derive(Debug, Default)]
+pub struct Procs {
+    by_pid: HashMap<u32, ProcInfo>,
+    children: HashMap<u32, Vec<u32>>,
+}
+
+impl Procs {
+    /// Indexes the given processes visible to the caller.
+    pub fn new(procs: &CacheData, visibility: Visibility) -> Self {
+        let mut index = Self::default();
+
+        for proc in procs.iter().filter(|proc| visibility.allows(proc)) {
+            if let Some(ppid) = proc.ppid {
+                index.children.entry(ppid).or_default().push(proc.pid);
+            }
+            index.by_pid.insert(proc.pid, proc.clone());
+        }
+        for children in index.children.values_mut() {
+            children.sort_unstable();
+        }
+        index
+    }
+
+    /// Returns the processes of the request: those of the snapshot given to
+    /// it if it is a query, otherwise the current ones, e.g. for subscription
+    /// events, as indexed by its [`ProcsIndex`].
+    async fn of(ctx: &Context<'_>) -> Result<Arc<Self>> {
+        let snap = match ctx.data_opt::<Arc<Snapshot>>() {
+            Some(snap) => Arc::clone(snap),
+            None => Arc::clone(ctx.data::<ProcCache>()?.read().await.current()),
+        };
+        let visibility = *ctx.data::<Visibility>()?;
+        Ok(ctx.data::<ProcsIndex>()?.index(&snap, visibility))
+    }
+
+    /// Returns the process of the given PID.
+    fn get(&self, pid: u32) -> Option<&ProcInfo> {
+        self.by_pid.get(&pid)
+    }
+
+    /// Returns the children of the process of the given PID, by PID.
+    fn children(&self, pid: u32) -> impl Iterator<Item = &ProcInfo> {
+        self.children
+            .get(&pid)
+            .into_iter()
+            .flatten()
+            .filter_map(|pid| self.get(*pid))
+    }
+}
+
+/// The index of the processes of a request or of a subscriber, only built once
+/// a field needs it, then shared by all the fields resolved over the same
+/// snapshot instead of being rebuilt by each of them.
+#[derive(Debug, Default)]
+pub struct ProcsIndex(Mutex<Option<(u64, Arc<Procs>)>>);
+
+impl ProcsIndex {
+    /// Returns the processes of the given snapshot visible to the caller,
+    /// indexing them unless they already are.
+    fn index(&self, snap: &Snapshot, visibility: Visibility) -> Arc<Procs> {
+        let mut index = self.0.lock().unwrap();
+
+        match &*index {
+            Some((id, procs)) if *id == snap.id => Arc::clone(procs),
+            _ => {
+                let procs = Arc::new(Procs::new(&snap.procs, visibility));
+                *index = Some((snap.id, Arc::clone(&procs)));
+                procs
+            }
+        }
+    }
+}
+
+/// Filters of processes, AND-ed, as the parameters of `GET /search`.
+#[derive(Debug, Default, InputObject)]
+pub struct ProcessFilter {
+    pub pid: Option<u32>,
+    pub ppid: Option<u32>,
+    pub uid: Option<u32>,
+    pub name: Option<String>,
+    pub username: Option<String>,
+}
+
+impl From<ProcessFilter> for SearchQuery {
+    fn from(filter: ProcessFilter) -> Self {
+        Self {
+            pid: filter.pid,
+            ppid: filter.ppid,
+            uid: filter.uid,
+            name: filter.name,
+            username: filter.username,
+            at: None,
+        }
+    }
+}
+
+/// A field of the processes by which they can be sorted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum ProcessField {
+    Pid,
+    Ppid,
+    Uid,
+    Name,
+    Username,
+}
+
+/// The order of a list of processes, ties being broken by PID.
+#[derive(Debug, Clone, Copy, InputObject)]
+pub struct ProcessSort {
+    pub field: ProcessField,
+    #[graphql(default)]
+    pub descending: bool,
+}
+
+impl ProcessSort {
+    /// Sorts the given processes.
+    fn sort(self, procs: &mut [&ProcInfo]) {
+        procs.sort_by(|a, b| {
+            let order = match self.field {
+                ProcessField::Pid => a.pid.cmp(&b.pid),
+                ProcessField::Ppid => a.ppid.cmp(&b.ppid),
+                ProcessField::Uid => a.uid.cmp(&b.uid),
+                ProcessField::Name => a.name.cmp(&b.name),
+                ProcessField::Username => a.username.cmp(&b.username),
+            }
+            .then(a.pid.cmp(&b.pid));
+
+            if self.descending {
+                order.reverse()
+            } else {
+                order
+            }
+        });
+    }
+}
+
+/// Returns the complexity of a list of the given length if any, otherwise
+/// [`LIST_COMPLEXITY`], whose items have the given complexity.
+fn list_complexity(first: Option<i32>, child_complexity: usize) -> usize {
+    first
+        .map_or(LIST_COMPLEXITY, |first| first.max(1) as usize)
+        .saturating_mul(child_complexity)
+}
+
+/// The root of the queries.
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// The processes matching the filter, sorted by PID unless specified
+    /// otherwise, paginated by offset.
+    #[graphql(complexity = "list_complexity(first, child_complexity)")]
+    async fn processes(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<ProcessFilter>,
+        sort: Option<ProcessSort>,
+        first: Option<i32>,
+        after: Option<String>,
+    ) -> Result<Connection<usize, ProcInfo>> {
+        let procs = Procs::of(ctx).await?;
+        let query = SearchQuery::from(filter.unwrap_or_default());
+        let mut rows = procs
+            .by_pid
+            .values()
+            .filter(|proc| query.matches(proc))
+            .collect::<Vec<_>>();
+        sort.unwrap_or(ProcessSort {
+            field: ProcessField::Pid,
+            descending: false,
+        })
+        .sort(&mut rows);
+
+        connection::query(
+            after,
+            None,
+            first,
+            None,
+            |after: Option<usize>, _, first, _| async move {
+                let start = after.map_or(0, |after| after + 1).min(rows.len());
+                let end = first.map_or(rows.len(), |first| {
+                    start.saturating_add(first).min(rows.len())
+                });
+                let mut connection = Connection::new(start > 0, end < rows.len());
+
+                connection.edges.extend(
+                    rows[start..end]
+                        .iter()
+                        .enumerate()
+                        .map(|(i, proc)| Edge::new(start + i, (*proc).clone())),
+                );
+                Ok::<_, async_graphql::Error>(connection)
+            },
+        )
+        .await
+    }
+
+    /// The process of the given PID, if any.
+    async fn process(&self, ctx: &Context<'_>, pid: u32) -> Result<Option<ProcInfo>> {
+        Ok(Procs::of(ctx).await?.get(pid).cloned())
+    }
+}
+
+#[ComplexObject]
+impl ProcInfo {
+    /// The parent process, if any.
+    async fn parent(&self, ctx: &Context<'_>) -> Result<Option<ProcInfo>> {
+        let procs = Procs::of(ctx).await?;
+        Ok(self.ppid.and_then(|ppid| procs.get(ppid)).cloned())
+    }
+
+    /// The child processes, by PID.
+    #[graphql(complexity = "list_complexity(None, child_complexity)")]
+    async fn children(&self, ctx: &Context<'_>) -> Result<Vec<ProcInfo>> {
+        Ok(Procs::of(ctx).await?.children(self.pid).cloned().collect())
+    }
+
+    /// The user running the process.
+    async fn user(&self) -> User {
+        User {
+            uid: self.uid,
+            username: self.username.clone(),
+        }
+    }
+}
+
+/// A user running processes.
+#[derive(Debug, SimpleObject)]
+#[graphql(complex)]
+pub struct User {
+    pub uid: u32,
+    pub username: String,
+}
+
+#[ComplexObject]
+impl User {
+    /// The processes run by the user, by PID.
+    #[graphql(complexity = "list_complexity(None, child_complexity)")]
+    async fn processes(&self, ctx: &Context<'_>) -> Result<Vec<ProcInfo>> {
+        let procs = Procs::of(ctx).await?;
+        let mut procs = procs
+            .by_pid
+            .values()
+            .filter(|proc| proc.uid == self.uid)
+            .cloned()
+            .collect::<Vec<_>>();
+        procs.sort_unstable_by_key(|proc| proc.pid);
+        Ok(procs)
+    }
+}
+
+/// What happened to a process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum ProcessEventKind {
+    Spawned,
+    Exited,
+}
+
+/// A process spawned or exited, as discovered by a refresh.
+#[derive(Debug, SimpleObject)]
+pub struct ProcessEvent {
+    pub kind: ProcessEventKind,
+    pub process: ProcInfo,
+}
+
+impl From<Change> for ProcessEvent {
+    fn from(change: Change) -> Self {
+        match change {
+            Change::Added(process) => Self {
+                kind: ProcessEventKind::Spawned,
+                process,
+            },
+            Change::Removed(process) => Self {
+                kind: ProcessEventKind::Exited,
+                process,
+            },
+        }
+    }
+}
+
+/// The root of the subscriptions.
+pub struct Subscription;
+
+#[async_graphql::Subscription]
+impl Subscription {
+    /// The processes spawned and exited, as discovered by each refresh of the
+    /// cache from now on. Ends if the subscriber lags behind.
+    async fn process_events(&self, ctx: &Context<'_>) -> Result<impl Stream<Item = ProcessEvent>> {
+        let visibility = *ctx.data::<Visibility>()?;
+        let cache = Arc::clone(ctx.data::<ProcCache>()?);
+        let mut rx = cache.read().await.subscribe();
+
+        Ok(stream! {
+            loop {
+                match rx.recv().await {
+                    Ok(changes) => {
+                        for change in changes {
+                            if visibility.allows(change.proc()) {
+                                yield ProcessEvent::from(change);
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("GraphQL subscription: lagged behind by {count} messages.");
+                        cache.read().await.metrics().record_lagged();
+                        break;
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        })
+    }
+}
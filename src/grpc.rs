@@ -0,0 +1,233 @@
+//! This module defines the gRPC service of the API, behind the `grpc` feature:
+//! `ListProcesses`, `Search`, `Refresh` and `Watch`, defined by
+//! `proto/proc_api.proto`, over the same cache as the [`crate::routes`] and
+//! with the same authentication and limits.
+
+use std::pin::Pin;
+
+use anyhow::Result;
+use futures_util::future;
+use futures_util::stream::{Stream, StreamExt};
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::auth::{self, AuthError, Scope, Visibility};
+use crate::config::SharedConfig;
+use crate::handlers;
+use crate::limits::{self, Endpoint, Limited};
+use crate::model::SearchQuery;
+use crate::proc::{CacheData, CacheInner, Change, ProcCache, ProcInfo};
+use crate::server::Peer;
+
+/// The types and the server and client stubs generated from the definitions.
+pub mod proto {
+    tonic::include_proto!("proc_api");
+}
+
+use proto::proc_api_server::{ProcApi, ProcApiServer};
+use proto::process_event::Kind;
+use proto::{
+    ListProcessesRequest, Process, ProcessEvent, ProcessList, RefreshRequest, RefreshResponse,
+    SearchRequest, WatchRequest,
+};
+
+/// Serves the gRPC service of the given cache, configured as given, on the
+/// connections accepted by the TCP listener, forever, in plaintext: callers are only identified by
+/// their token or IP address.
+pub async fn serve(listener: TcpListener, cache: ProcCache, config: SharedConfig) -> Result<()> {
+    info!("Listening on grpc://{}.", listener.local_addr()?);
+    Server::builder()
+        .add_service(ProcApiServer::new(GrpcService::new(cache, config)))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await?;
+    Ok(())
+}
+
+/// The implementation of the gRPC service over a cache.
+#[derive(Debug, Clone)]
+pub struct GrpcService {
+    cache: ProcCache,
+    config: SharedConfig,
+}
+
+impl GrpcService {
+    /// Builds the service of the given cache, configured as given.
+    pub fn new(cache: ProcCache, config: SharedConfig) -> Self {
+        Self { cache, config }
+    }
+}
+
+/// Returns the `authorization` metadata of the given request, if any.
+fn header<T>(request: &Request<T>) -> Option<&str> {
+    request.metadata().get("authorization")?.to_str().ok()
+}
+
+/// Returns the [`Peer`] of the given request.
+fn peer<T>(request: &Request<T>) -> Peer {
+    Peer {
+        addr: request.remote_addr(),
+        ..Default::default()
+    }
+}
+
+/// Lists the given processes visible to the caller matching the given query.
+fn list(procs: &CacheData, visibility: Visibility, query: &SearchQuery) -> ProcessList {
+    let mut processes = procs
+        .iter()
+        .filter(|proc| visibility.allows(proc) && query.matches(proc))
+        .map(Process::from)
+        .collect::<Vec<_>>();
+    processes.sort_unstable_by_key(|proc| proc.pid);
+    ProcessList { processes }
+}
+
+#[tonic::async_trait]
+impl ProcApi for GrpcService {
+    async fn list_processes(
+        &self,
+        request: Request<ListProcessesRequest>,
+    ) -> Result<Response<ProcessList>, Status> {
+        auth::authorize(&self.config, header(&request), Scope::Read)?;
+        let visibility = auth::identify(&self.config, header(&request), &peer(&request))?;
+        let cache = self.cache.read().await;
+
+        Ok(Response::new(list(
+            &cache.current().procs,
+            visibility,
+            &SearchQuery::default(),
+        )))
+    }
+
+    async fn search(
+        &self,
+        request: Request<SearchRequest>,
+    ) -> Result<Response<ProcessList>, Status> {
+        let config = &self.config;
+        auth::authorize(config, header(&request), Scope::Read)?;
+        limits::acquire_rate(config, Endpoint::Search, header(&request), peer(&request))?;
+        let visibility = auth::identify(config, header(&request), &peer(&request))?;
+        let query = SearchQuery::from(request.into_inner());
+
+        if let SearchQuery {
+            pid: None,
+            ppid: None,
+            uid: None,
+            name: None,
+            username: None,
+            ..
+        } = query
+        {
+            return Err(Status::invalid_argument(
+                "At least one of pid, ppid, uid, name and username is required.",
+            ));
+        }
+        Ok(Response::new(list(
+            &self.cache.read().await.current().procs,
+            visibility,
+            &query,
+        )))
+    }
+
+    async fn refresh(
+        &self,
+        request: Request<RefreshRequest>,
+    ) -> Result<Response<RefreshResponse>, Status> {
+        let config = &self.config;
+        auth::authorize(config, header(&request), Scope::Refresh)?;
+        limits::acquire_rate(config, Endpoint::Refresh, header(&request), peer(&request))?;
+        debug!("Refresh requested by {} over gRPC.", peer(&request));
+
+        match CacheInner::refresh(&self.cache).await {
+            Ok(()) => Ok(Response::new(RefreshResponse {})),
+            Err(err) => {
+                error!("Unable to refresh the cache: {err:#}");
+                Err(Status::internal(format!(
+                    "Unable to refresh the cache: {err:#}"
+                )))
+            }
+        }
+    }
+
+    type WatchStream = Pin<Box<dyn Stream<Item = Result<ProcessEvent, Status>> + Send>>;
+
+    async fn watch(
+        &self,
+        request: Request<WatchRequest>,
+    ) -> Result<Response<Self::WatchStream>, Status> {
+        let config = &self.config;
+        auth::authorize(config, header(&request), Scope::Stream)?;
+        let visibility = auth::identify(config, header(&request), &peer(&request))?;
+        let permit = limits::acquire_stream(config, header(&request), peer(&request))?;
+        let events = handlers::proc_events(self.cache.clone(), permit)
+            .await
+            .filter(move |change| future::ready(visibility.allows(change.proc())))
+            .map(ProcessEvent::from)
+            .map(Ok);
+
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+impl From<&ProcInfo> for Process {
+    fn from(proc: &ProcInfo) -> Self {
+        Self {
+            pid: proc.pid,
+            ppid: proc.ppid,
+            uid: proc.uid,
+            name: proc.name.clone(),
+            username: proc.username.clone(),
+        }
+    }
+}
+
+impl From<SearchRequest> for SearchQuery {
+    fn from(request: SearchRequest) -> Self {
+        Self {
+            pid: request.pid,
+            ppid: request.ppid,
+            uid: request.uid,
+            name: request.name,
+            username: request.username,
+            at: None,
+        }
+    }
+}
+
+impl From<Change> for ProcessEvent {
+    fn from(change: Change) -> Self {
+        let kind = match change {
+            Change::Added(_) => Kind::Spawned,
+            Change::Removed(_) => Kind::Exited,
+        };
+
+        Self {
+            kind: kind.into(),
+            process: Some(Process::from(change.proc())),
+        }
+    }
+}
+
+impl From<AuthError> for Status {
+    fn from(err: AuthError) -> Self {
+        match err {
+            AuthError::Unauthorized => Self::unauthenticated("A valid bearer token is required."),
+            AuthError::Forbidden(scope) => {
+                Self::permission_denied(format!("The token does not grant the {scope} scope."))
+            }
+            AuthError::Unidentified => {
+                Self::permission_denied("The caller could not be identified.")
+            }
+        }
+    }
+}
+
+impl From<Limited> for Status {
+    fn from(limited: Limited) -> Self {
+        Self::resource_exhausted(format!(
+            "Too many requests: retry in {} seconds.",
+            limited.retry_after_secs()
+        ))
+    }
+}
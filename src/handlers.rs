@@ -1,17 +1,42 @@
 //! This module defines the async functions handling requests sent by the API's
 //! [`crate::routes`] and returning the desired information.
 
+use std::borrow::Cow;
 use std::convert::Infallible;
+use std::sync::Arc;
 
+use async_graphql::http::WebSocketProtocols;
+use async_graphql::Data;
+use async_graphql_warp::GraphQLWebSocket;
 use async_stream::stream;
+use futures_util::future;
 use futures_util::stream::{self, Stream, StreamExt};
+use tokio::sync::broadcast::error::RecvError;
 use tokio::time::{self, Duration};
+use warp::hyper::body::Bytes;
+use warp::hyper::Body;
+use warp::reply::Response;
+use warp::ws::Ws;
 use warp::{http::StatusCode, sse};
 
-use crate::proc::ProcCache;
-use crate::routes::SearchQuery;
+use crate::auth::Visibility;
+use crate::conditional::Conditions;
+use crate::config::SharedConfig;
+use crate::error::ApiError;
+use crate::format::Format;
+use crate::graphql::{ProcSchema, ProcsIndex};
+use crate::limits::StreamPermit;
+use crate::metrics;
+use crate::model::duplicate_pid;
+use crate::openapi;
+use crate::proc::{
+    CacheData, CacheInner, Change, Diff, ProcCache, Snapshot, SnapshotInfo, Timestamp,
+};
+use crate::routes::{AtQuery, DiffQuery, SearchQuery, SignalQuery};
+use crate::server::Peer;
+use crate::store;
 
-/// Timeout used in [`proc_sse_events`] in order to cancel the stream task
+/// Timeout used in [`proc_events`] in order to cancel the stream task
 /// during testing, but not when running normally.
 const SSE_TOUT: Duration = if cfg!(test) {
     Duration::from_secs(1)
@@ -20,25 +45,105 @@ const SSE_TOUT: Duration = if cfg!(test) {
 };
 
 /// Handles [`crate::routes::list_procs`] by returning the currently-cached
-/// process data as a JSON reply.
-pub async fn list_procs(cache: ProcCache) -> Result<impl warp::Reply, Infallible> {
-    Ok(warp::reply::json(cache.read().await.get()))
+/// process data, or the one selected by the query, as a reply in the
+/// negotiated format, or `304 Not Modified` if the client already has it.
+/// Only the processes visible to the caller are returned.
+pub async fn list_procs(
+    query: AtQuery,
+    format: Option<Format>,
+    conds: Conditions,
+    visibility: Visibility,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let Some(format) = format else {
+        return Ok(Box::new(ApiError::not_acceptable(Format::LISTS)));
+    };
+    // Only hold the lock while selecting the snapshot, not while encoding it.
+    let snap = select_snapshot_at(&*cache.read().await, query.at).cloned();
+
+    Ok(match snap {
+        Some(snap) => conds.reply(&snap, format, visibility, || {
+            visible_reply(&snap, format, visibility)
+        }),
+        None => Box::new(no_snapshot_at(query.at)),
+    })
 }
 
 /// Handles [`crate::routes::refresh_procs`] by refreshing the cache and returning a
-/// status code reflecting the success or failure of the operation.
-pub async fn refresh_procs(cache: ProcCache) -> Result<impl warp::Reply, Infallible> {
-    Ok(cache
-        .write()
+/// status code reflecting the success of the operation, or an error carrying
+/// the cause of its failure.
+pub async fn refresh_procs(
+    peer: Peer,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    debug!("Refresh requested by {peer}.");
+    Ok(match CacheInner::refresh(&cache).await {
+        Ok(()) => Box::new(StatusCode::OK),
+        Err(err) => {
+            error!("Unable to refresh the cache: {err:#}");
+            Box::new(ApiError::internal(
+                "refresh_failed",
+                "Unable to refresh the cache",
+                &err,
+            ))
+        }
+    })
+}
+
+/// Handles [`crate::routes::signal_proc`] by sending the signal to the cached
+/// process of the given PID, if signals are allowed and it is visible to the
+/// caller, and returning a status code reflecting the success of the
+/// operation, or an error carrying the cause of its failure.
+pub async fn signal_proc(
+    pid: u32,
+    query: SignalQuery,
+    visibility: Visibility,
+    peer: Peer,
+    cache: ProcCache,
+    config: SharedConfig,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if !config.signals_allowed() {
+        return Ok(Box::new(ApiError::new(
+            StatusCode::FORBIDDEN,
+            "signals_disabled",
+            "Signals are not allowed by the server.",
+        )));
+    }
+    let proc = cache
+        .read()
         .await
-        .refresh()
-        .map_or(StatusCode::INTERNAL_SERVER_ERROR, |_| StatusCode::OK))
+        .get()
+        .iter()
+        .find(|proc| proc.pid == pid && visibility.allows(proc))
+        .cloned();
+    let Some(proc) = proc else {
+        return Ok(Box::new(no_process(pid)));
+    };
+
+    Ok(match CacheInner::signal(&cache, proc, query.signal).await {
+        Ok(true) => {
+            info!("Sent {} to process {pid} for {peer}.", query.signal);
+            Box::new(StatusCode::OK)
+        }
+        Ok(false) => Box::new(no_process(pid)),
+        Err(err) => {
+            error!("Unable to send {} to process {pid}: {err:#}", query.signal);
+            Box::new(ApiError::internal(
+                "signal_failed",
+                "Unable to send the signal",
+                &err,
+            ))
+        }
+    })
 }
 
 /// Handles [`crate::routes::search_procs`] by filtering the results and then
 /// doing what [`list_procs`] does.
 pub async fn search_procs(
     query: SearchQuery,
+    format: Option<Format>,
+    conds: Conditions,
+    visibility: Visibility,
     cache: ProcCache,
 ) -> Result<Box<dyn warp::Reply>, Infallible> {
     match query {
@@ -47,81 +152,495 @@ pub async fn search_procs(
         // filter any process out of the resulting vector.
         SearchQuery {
             pid: None,
+            ppid: None,
             uid: None,
             name: None,
             username: None,
-        } => Ok(Box::new(StatusCode::BAD_REQUEST)),
-        _ => Ok(Box::new(warp::reply::json(
-            &cache
-                .read()
-                .await
-                .get()
+            ..
+        } => Ok(Box::new(ApiError::bad_request(
+            "missing_filter",
+            "At least one of pid, ppid, uid, name and username is required.",
+        ))),
+        _ => {
+            let Some(format) = format else {
+                return Ok(Box::new(ApiError::not_acceptable(Format::LISTS)));
+            };
+            let Some(snap) = select_snapshot_at(&*cache.read().await, query.at).cloned() else {
+                return Ok(Box::new(no_snapshot_at(query.at)));
+            };
+
+            Ok(conds.reply(&snap, format, visibility, || {
+                format.reply(
+                    snap.procs
+                        .iter()
+                        .filter(|&proc| visibility.allows(proc) && query.matches(proc)),
+                )
+            }))
+        }
+    }
+}
+
+/// Handles [`crate::routes::list_snapshots`] by returning the summaries of
+/// the retained snapshots as a JSON reply, from the oldest to the current one,
+/// only counting the processes visible to the caller.
+pub async fn list_snapshots(
+    visibility: Visibility,
+    cache: ProcCache,
+) -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(
+        &cache
+            .read()
+            .await
+            .snapshots()
+            .map(|snap| SnapshotInfo {
+                count: visible_procs(&snap.procs, visibility).len(),
+                ..snap.info()
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Handles [`crate::routes::snapshot_procs`] by doing what [`list_procs`]
+/// does for the snapshot of the given ID, if it is still retained.
+pub async fn snapshot_procs(
+    id: u64,
+    format: Option<Format>,
+    conds: Conditions,
+    visibility: Visibility,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let Some(format) = format else {
+        return Ok(Box::new(ApiError::not_acceptable(Format::LISTS)));
+    };
+
+    let snap = cache.read().await.snapshot(id).cloned();
+
+    Ok(match snap {
+        Some(snap) => conds.reply(&snap, format, visibility, || {
+            visible_reply(&snap, format, visibility)
+        }),
+        None => Box::new(no_snapshot(id)),
+    })
+}
+
+/// Builds the reply listing the processes of the given snapshot visible with
+/// the given visibility, in the given format.
+fn visible_reply(snap: &Snapshot, format: Format, visibility: Visibility) -> Box<dyn warp::Reply> {
+    match visibility {
+        // The snapshot's cached serialization can only be used as a whole.
+        Visibility::All => format.snapshot_reply(snap),
+        Visibility::Owner(_) => {
+            format.reply(snap.procs.iter().filter(|&proc| visibility.allows(proc)))
+        }
+    }
+}
+
+/// Returns the given processes visible with the given visibility, only copying
+/// them if some are hidden.
+fn visible_procs(procs: &CacheData, visibility: Visibility) -> Cow<'_, CacheData> {
+    match visibility {
+        Visibility::All => Cow::Borrowed(procs),
+        Visibility::Owner(_) => Cow::Owned(
+            procs
                 .iter()
-                .filter(|&proc| {
-                    // Logic for each filter:
-                    //  * None means not received in the request => true;
-                    //  * Some(match) means filter matches => true;
-                    //  * Some(_) means filters does not match => false;
-                    // AND them all to reach usual search functionnality.
-                    (query.pid.is_none() || query.pid == Some(proc.pid))
-                        && (query.uid.is_none() || query.uid == Some(proc.uid))
-                        && query
-                            .name
-                            .as_ref()
-                            .map_or(true, |name| name == proc.name.as_str())
-                        && query
-                            .username
-                            .as_ref()
-                            .map_or(true, |username| username == proc.username.as_str())
-                })
-                // Collect to a vector because uniqueness guarantees and
-                // operations of sets need not be used anymore are this point.
-                .collect::<Vec<_>>(),
+                .filter(|&proc| visibility.allows(proc))
+                .cloned()
+                .collect(),
+        ),
+    }
+}
+
+/// Handles [`crate::routes::diff_snapshots`] by comparing the processes
+/// visible to the caller in the two snapshots selected by the query, if they
+/// are still retained, and returning their differences as a JSON reply.
+pub async fn diff_snapshots(
+    query: DiffQuery,
+    visibility: Visibility,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let Some(from) = query.from else {
+        return Ok(Box::new(ApiError::bad_request(
+            "missing_from",
+            "The from snapshot is required.",
+        )));
+    };
+    let snaps = {
+        let cache = cache.read().await;
+        (
+            cache.snapshot(from).cloned(),
+            select_snapshot(&cache, query.to).cloned(),
+        )
+    };
+
+    Ok(match snaps {
+        (Some(from), Some(to)) => Box::new(warp::reply::json(&Diff::between(
+            &visible_procs(&from.procs, visibility),
+            &visible_procs(&to.procs, visibility),
+        ))),
+        (None, _) => Box::new(no_snapshot(from)),
+        (_, None) => Box::new(no_snapshot(query.to.unwrap_or_default())),
+    })
+}
+
+/// Handles [`crate::routes::diff_uploaded`] by doing what [`diff_snapshots`]
+/// does, but starting from the uploaded process data.
+pub async fn diff_uploaded(
+    query: DiffQuery,
+    base: CacheData,
+    visibility: Visibility,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    // The base is the body: another one cannot be selected.
+    if query.from.is_some() {
+        return Ok(Box::new(ApiError::bad_request(
+            "unexpected_from",
+            "The from snapshot is the uploaded one.",
+        )));
+    }
+    if let Some(pid) = duplicate_pid(&base) {
+        return Ok(Box::new(ApiError::bad_request(
+            "duplicate_pid",
+            format!("Several uploaded processes have the PID {pid}."),
+        )));
+    }
+    let to = select_snapshot(&*cache.read().await, query.to).cloned();
+
+    Ok(match to {
+        Some(to) => Box::new(warp::reply::json(&Diff::between(
+            &visible_procs(&base, visibility),
+            &visible_procs(&to.procs, visibility),
         ))),
+        None => Box::new(no_snapshot(query.to.unwrap_or_default())),
+    })
+}
+
+/// Handles [`crate::routes::export_snapshots`] by encoding all the retained
+/// snapshots, or only the one of the given ID, as a binary reply, with only
+/// the processes visible to the caller.
+pub async fn export_snapshots(
+    id: Option<u64>,
+    visibility: Visibility,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    // Only hold the lock while sharing the snapshots, not while encoding them.
+    let snaps: Vec<Arc<Snapshot>> = {
+        let cache = cache.read().await;
+        match id {
+            None => cache.snapshots().cloned().collect(),
+            Some(id) => match cache.snapshot(id) {
+                Some(snap) => vec![Arc::clone(snap)],
+                None => return Ok(Box::new(no_snapshot(id))),
+            },
+        }
+    };
+    let encoded = match visibility {
+        Visibility::All => store::encode(snaps.iter().map(|snap| &**snap)),
+        Visibility::Owner(_) => store::encode(
+            &snaps
+                .into_iter()
+                .map(|snap| snap.filtered(|proc| visibility.allows(proc)))
+                .collect::<Vec<_>>(),
+        ),
+    };
+
+    Ok(match encoded {
+        Ok(bytes) => Box::new(warp::reply::with_header(
+            bytes,
+            "content-type",
+            "application/octet-stream",
+        )),
+        Err(err) => {
+            error!("Unable to export snapshots: {err:#}");
+            Box::new(ApiError::internal(
+                "export_failed",
+                "Unable to export snapshots",
+                &err,
+            ))
+        }
+    })
+}
+
+/// Handles [`crate::routes::import_snapshots`] by decoding the uploaded
+/// snapshots, importing them in order into the cache and returning their
+/// summaries as a JSON reply. The imported snapshots replace the processes
+/// of all the users: only callers seeing them all can import them.
+pub async fn import_snapshots(
+    body: Bytes,
+    visibility: Visibility,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if visibility != Visibility::All {
+        return Ok(Box::new(admin_required(
+            "The imported snapshots replace all the processes: only admins can import them.",
+        )));
+    }
+    let snaps = store::decode(&*body).and_then(|snaps| {
+        for snap in &snaps {
+            if let Some(pid) = duplicate_pid(&snap.procs) {
+                anyhow::bail!(
+                    "Several processes of snapshot {} have the PID {pid}.",
+                    snap.id
+                );
+            }
+        }
+        Ok(snaps)
+    });
+    let snaps = match snaps {
+        Ok(snaps) => snaps,
+        Err(err) => {
+            debug!("Rejected snapshot import: {err:#}");
+            return Ok(Box::new(
+                ApiError::bad_request("invalid_snapshots", format!("Invalid snapshots: {err}"))
+                    .with_details(serde_json::json!({
+                        "causes": err.chain().map(ToString::to_string).collect::<Vec<_>>(),
+                    })),
+            ));
+        }
+    };
+
+    Ok(match CacheInner::import(&cache, snaps).await {
+        Ok(infos) => Box::new(warp::reply::json(&infos)),
+        Err(err) => {
+            error!("Unable to import snapshot: {err:#}");
+            Box::new(ApiError::internal(
+                "import_failed",
+                "Unable to import snapshot",
+                &err,
+            ))
+        }
+    })
+}
+
+/// Handles [`crate::routes::metrics`] by rendering the metrics as a text reply,
+/// collecting the resource usage of processes first if enabled. The metrics
+/// cover the processes of all the users: they are only replied to callers
+/// seeing them all.
+pub async fn metrics(
+    visibility: Visibility,
+    cache: ProcCache,
+    config: SharedConfig,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    if visibility != Visibility::All {
+        return Ok(Box::new(admin_required(
+            "The metrics cover all the processes: only admins can read them.",
+        )));
+    }
+    let resources = if cache.read().await.metrics().proc_gauges() == 0 {
+        Vec::new()
+    } else {
+        CacheInner::resources(&cache).await.unwrap_or_else(|err| {
+            warn!("Unable to collect process resources: {err:#}");
+            Vec::new()
+        })
+    };
+
+    Ok(Box::new(warp::reply::with_header(
+        metrics::render(&*cache.read().await, &config, &resources),
+        "content-type",
+        "text/plain; version=0.0.4",
+    )))
+}
+
+/// Builds the error replied to callers not seeing all the processes when the
+/// request concerns all of them, for the given reason.
+fn admin_required(message: &str) -> ApiError {
+    ApiError::new(StatusCode::FORBIDDEN, "admin_required", message)
+}
+
+/// Builds the error replied when the snapshot of the given ID is not retained.
+fn no_snapshot(id: u64) -> ApiError {
+    ApiError::not_found(format!("Snapshot {id} is not retained."))
+        .with_details(serde_json::json!({ "id": id }))
+}
+
+/// Builds the error replied when the process of the given PID is not cached,
+/// not visible to the caller, or no longer running.
+fn no_process(pid: u32) -> ApiError {
+    ApiError::not_found(format!("No such process: {pid}."))
+        .with_details(serde_json::json!({ "pid": pid }))
+}
+
+/// Builds the error replied when no snapshot retained was current at the given
+/// time, which is always given as the current snapshot always exists.
+fn no_snapshot_at(at: Option<Timestamp>) -> ApiError {
+    let at = at.map(|at| at.to_string()).unwrap_or_default();
+    ApiError::not_found(format!("No retained snapshot was current at {at}."))
+        .with_details(serde_json::json!({ "at": at }))
+}
+
+/// Handles [`crate::routes::graphql`] by executing the query over the
+/// processes of the current snapshot visible to the caller, only indexed if
+/// the query resolves any, and returning the GraphQL response, which carries
+/// its errors, if any.
+pub async fn graphql(
+    request: async_graphql::Request,
+    visibility: Visibility,
+    schema: ProcSchema,
+    cache: ProcCache,
+) -> Result<impl warp::Reply, Infallible> {
+    let snap = Arc::clone(cache.read().await.current());
+    let request = request
+        .data(snap)
+        .data(visibility)
+        .data(ProcsIndex::default());
+    let response = schema.execute(request).await;
+
+    Ok(warp::reply::json(&response))
+}
+
+/// Handles [`crate::routes::graphql_subscriptions`] by upgrading the
+/// connection to a WebSocket serving the subscriptions of the caller with the
+/// given protocol, holding the given permit until it is closed.
+pub async fn graphql_subscriptions(
+    ws: Ws,
+    protocol: WebSocketProtocols,
+    visibility: Visibility,
+    permit: StreamPermit,
+    schema: ProcSchema,
+) -> Result<impl warp::Reply, Infallible> {
+    let mut data = Data::default();
+    data.insert(visibility);
+    data.insert(ProcsIndex::default());
+    data.insert(permit);
+    let reply = ws.on_upgrade(move |socket| {
+        GraphQLWebSocket::new(socket, schema, protocol)
+            .with_data(data)
+            .serve()
+    });
+
+    Ok(warp::reply::with_header(
+        reply,
+        "sec-websocket-protocol",
+        protocol.sec_websocket_protocol(),
+    ))
+}
+
+/// Handles [`crate::routes::openapi`] by returning the OpenAPI document of the
+/// API as a JSON reply.
+pub async fn openapi() -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::json(openapi::document()))
+}
+
+/// The page of the web dashboard, self-contained so that it works without
+/// access to the Internet.
+const DASHBOARD: &str = include_str!("dashboard.html");
+
+/// Handles [`crate::routes::dashboard`] by returning the page of the web
+/// dashboard.
+pub async fn dashboard() -> Result<impl warp::Reply, Infallible> {
+    Ok(warp::reply::html(DASHBOARD))
+}
+
+/// Selects the current snapshot if no ID is given, or the retained one of the
+/// given ID otherwise.
+fn select_snapshot(cache: &CacheInner, id: Option<u64>) -> Option<&Arc<Snapshot>> {
+    match id {
+        None => Some(cache.current()),
+        Some(id) => cache.snapshot(id),
+    }
+}
+
+/// Selects the current snapshot if no time is given, or the one that was
+/// current at that time otherwise, if it is still retained.
+fn select_snapshot_at(cache: &CacheInner, at: Option<Timestamp>) -> Option<&Arc<Snapshot>> {
+    match at {
+        None => Some(cache.current()),
+        Some(Timestamp(time)) => cache.snapshot_at(time),
     }
 }
 
 /// Handles [`crate::routes::stream_procs`] by setting up the streaming
 /// capabilities of the API, building a stream from the data and returning a
-/// [`warp::sse`] reply.
-pub async fn stream_procs(cache: ProcCache) -> Result<impl warp::Reply, Infallible> {
-    Ok(sse::reply(
-        warp::sse::keep_alive().stream(proc_sse_events(cache).await),
-    ))
+/// [`warp::sse`] reply, or a binary or NDJSON one depending on the negotiated
+/// format. Only the processes visible to the caller are streamed.
+///
+/// Removed processes are only streamed as SSE, as `removed` events: the other
+/// formats are plain sequences of processes.
+pub async fn stream_procs(
+    format: Option<Format>,
+    visibility: Visibility,
+    permit: StreamPermit,
+    cache: ProcCache,
+) -> Result<Box<dyn warp::Reply>, Infallible> {
+    let Some(format) = format else {
+        return Ok(Box::new(ApiError::not_acceptable(Format::STREAMS)));
+    };
+    let events = proc_events(cache, permit)
+        .await
+        .filter(move |change| future::ready(visibility.allows(change.proc())));
+
+    Ok(match format {
+        Format::EventStream => Box::new(sse::reply(warp::sse::keep_alive().stream(events.map(
+            |change| {
+                let event = match change {
+                    Change::Added(_) => sse::Event::default(),
+                    Change::Removed(_) => sse::Event::default().event("removed"),
+                };
+                // Unwrapping here *should* ***hopefully*** be fine here
+                // because the data is known to be correct JSON-capable data
+                // at this point.
+                Ok::<_, Infallible>(event.json_data(change.proc()).unwrap())
+            },
+        )))),
+        _ => Box::new(warp::reply::with_header(
+            Response::new(Body::wrap_stream(events.filter_map(move |change| {
+                future::ready(match change {
+                    Change::Added(proc) => Some(format.encode_item(&proc)),
+                    Change::Removed(_) => None,
+                })
+            }))),
+            "content-type",
+            format.stream_media_type(),
+        )),
+    })
 }
 
-/// Builds the actual stream for [`stream_procs`].
+/// Builds the actual stream of changes for [`stream_procs`] and the gRPC
+/// `Watch`, holding the given permit until it ends.
 ///
 /// See also: [`crate::proc::CacheInner::refresh`] for the other end of the
 /// channel.
-async fn proc_sse_events(cache: ProcCache) -> impl Stream<Item = Result<sse::Event, Infallible>> {
+pub async fn proc_events(cache: ProcCache, permit: StreamPermit) -> impl Stream<Item = Change> {
     // Get a receiver, thus switching the cache to stream mode. As it is moved
     // into the stream builder, it will be automatically dropped right after
     // the stream is stopped by the client, thus avoiding channel lagging and
     // enabling switching back to the normal "blocking" mode of the cache when
     // the channel's receiver count finally drops to zero.
-    let mut rx = cache.read().await.subscribe();
-    // First immediately emit the currently-cached data,
-    stream::iter(cache.read().await.get().clone().into_iter())
+    let (mut rx, current) = {
+        let cache = cache.read().await;
+        (cache.subscribe(), Arc::clone(cache.current()))
+    };
+    // First immediately emit the currently-cached data, as added,
+    stream::iter(current.procs.clone().into_iter().map(Change::Added))
         // then stream new data received from the channel.
         .chain(
             // https://docs.rs/tokio/latest/tokio/stream/index.html
             stream! {
-                debug!("SSE: stream started.");
-                while let Ok(Ok(proc_group)) = time::timeout(SSE_TOUT, async {
-                    debug!("SSE: waiting for channel data...");
-                    rx.recv().await
-                })
-                .await
-                {
-                    debug!("SSE: received {} new processes.", proc_group.len());
-                    yield stream::iter(proc_group.into_iter());
+                let _permit = permit;
+                debug!("Stream: started.");
+                loop {
+                    match time::timeout(SSE_TOUT, async {
+                        debug!("Stream: waiting for channel data...");
+                        rx.recv().await
+                    })
+                    .await
+                    {
+                        Ok(Ok(changes)) => {
+                            debug!("Stream: received {} changes.", changes.len());
+                            yield stream::iter(changes.into_iter());
+                        }
+                        // Stop rather than skip data: the client is expected
+                        // to reconnect and thus receive the whole cache again.
+                        Ok(Err(RecvError::Lagged(count))) => {
+                            warn!("Stream: lagged behind by {count} messages.");
+                            cache.read().await.metrics().record_lagged();
+                            break;
+                        }
+                        _ => break,
+                    }
                 }
-                debug!("SSE: stream ended.");
+                debug!("Stream: ended.");
             }
             .flatten(),
         )
-        // Unwrapping here *should* ***hopefully*** be fine here because the
-        // data is known to be correct JSON-capable data at this point.
-        .map(|proc| Ok(sse::Event::default().json_data(proc).unwrap()))
 }
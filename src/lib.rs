@@ -0,0 +1,3207 @@
+//! Library of `proc-api`: the [`model`] of the API, a typed [`client`] for it
+//! behind the `client` feature, and the server implementing it behind the
+//! `server` feature, with its [`federation`] of other servers behind both
+//! `server` and `client`, all enabled by default, and its optional gRPC service
+//! behind the `grpc` one.
+
+#[macro_use]
+extern crate log;
+
+#[cfg(feature = "client")]
+pub mod client;
+pub mod model;
+
+#[cfg(feature = "server")]
+pub mod auth;
+#[cfg(feature = "server")]
+pub mod compression;
+#[cfg(feature = "server")]
+pub mod conditional;
+#[cfg(feature = "server")]
+pub mod config;
+#[cfg(feature = "server")]
+pub mod error;
+#[cfg(all(feature = "server", feature = "client"))]
+pub mod federation;
+#[cfg(feature = "server")]
+pub mod format;
+#[cfg(feature = "server")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "server")]
+pub mod handlers;
+#[cfg(feature = "server")]
+pub mod limits;
+#[cfg(feature = "server")]
+pub mod metrics;
+#[cfg(feature = "server")]
+pub mod openapi;
+#[cfg(feature = "server")]
+pub mod proc;
+#[cfg(feature = "server")]
+pub mod routes;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod source;
+#[cfg(feature = "server")]
+pub mod store;
+
+/// Basic integration tests.
+#[cfg(all(test, feature = "server"))]
+#[allow(clippy::explicit_auto_deref)]
+mod tests {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::net::SocketAddr;
+    use std::path::Path;
+    use std::str;
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    use tokio::net::TcpListener;
+    use tokio::sync::{Barrier, RwLock};
+    use tokio::time::{self, Duration};
+    use warp::http::StatusCode;
+    use warp::hyper::body::{Bytes, HttpBody};
+    use warp::hyper::Body;
+    use warp::reply::Response;
+    use warp::test::request;
+    use warp::Filter;
+
+    use super::*;
+    use auth::Subjects;
+    use compression::Encoding;
+    use config::{Config, SharedConfig};
+    use limits::{Limits, Rate};
+    use proc::{CacheInner, Diff, ProcCache, ProcInfo, SnapshotInfo};
+    use server::Tls;
+    use source::{RecordingSource, ReplaySource, ScriptedSource, SysinfoSource};
+    use store::StateDir;
+
+    /// Builds a process with predictable information from its PID.
+    fn proc_info(pid: u32) -> ProcInfo {
+        ProcInfo {
+            pid,
+            ppid: (pid > 1).then_some(pid / 2),
+            uid: pid % 2,
+            name: format!("proc{pid}"),
+            username: format!("user{}", pid % 2),
+        }
+    }
+
+    /// Builds a cache refreshed from a new scripted source, also returned in
+    /// order to script it.
+    fn scripted_cache() -> (ProcCache, ScriptedSource) {
+        let source = ScriptedSource::default();
+        let mut inner = CacheInner::default();
+        inner.set_source(Box::new(source.clone()));
+        (Arc::new(RwLock::new(inner)), source)
+    }
+
+    /// Parses the processes received as SSE data events, in order, ignoring
+    /// the `removed` ones.
+    fn sse_procs(body: &[u8]) -> Vec<ProcInfo> {
+        sse_events(body)
+            .into_iter()
+            .filter_map(|(event, proc)| (event == "message").then_some(proc))
+            .collect()
+    }
+
+    /// Parses the types and processes of the SSE events received, in order.
+    fn sse_events(body: &[u8]) -> Vec<(String, ProcInfo)> {
+        str::from_utf8(body)
+            .unwrap()
+            .split("\n\n")
+            .filter_map(|event| {
+                let data = event.lines().find_map(|line| line.strip_prefix("data:"))?;
+                let kind = event
+                    .lines()
+                    .find_map(|line| line.strip_prefix("event:"))
+                    .unwrap_or("message");
+                Some((kind.to_owned(), serde_json::from_str(data).unwrap()))
+            })
+            .collect()
+    }
+
+    /// Returns the code of the JSON error received.
+    fn error_code(body: &[u8]) -> String {
+        serde_json::from_slice::<serde_json::Value>(body).unwrap()["code"]
+            .as_str()
+            .unwrap()
+            .to_owned()
+    }
+
+    /// Fetch processes without refreshing them first: empty JSON array in OK
+    /// response.
+    #[tokio::test]
+    async fn test_list_procs_empty() {
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .reply(&routes::list_procs(
+                ProcCache::default(),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "[]");
+    }
+
+    /// Refresh processes: empty OK response, non-empty cache.
+    #[tokio::test]
+    async fn test_refresh_procs() {
+        let cache = ProcCache::default();
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "");
+        assert!(!cache.read().await.get().is_empty());
+    }
+
+    /// Spawn processes and make the source fail, refresh twice: INTERNAL
+    /// SERVER ERROR response and unchanged cache, then OK response and exactly
+    /// the spawned processes cached.
+    #[tokio::test]
+    async fn test_refresh_procs_failure() {
+        let (cache, source) = scripted_cache();
+        let filter = routes::refresh_procs(Arc::clone(&cache), SharedConfig::default());
+
+        source
+            .spawn(proc_info(1))
+            .spawn(proc_info(2))
+            .fail_next("Scripted failure.");
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(cache.read().await.get().is_empty());
+
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            cache.read().await.get(),
+            &[proc_info(1), proc_info(2)].into_iter().collect()
+        );
+    }
+
+    /// Refresh processes, then fetch them: non-empty JSON array in OK response.
+    #[tokio::test]
+    async fn test_list_procs_refreshed() {
+        let cache = ProcCache::default();
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .reply(&routes::list_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(
+            !serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(&**res.body()).unwrap())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// Query the search endpoint without parameters: JSON error in BAD REQUEST
+    /// response.
+    #[tokio::test]
+    async fn test_search_procs_empty_noparams_is_badrequest() {
+        let res = request()
+            .method("GET")
+            .path("/search")
+            .reply(&routes::search_procs(
+                ProcCache::default(),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res.body()), "missing_filter");
+    }
+
+    /// Query the search endpoint without parameters even after refreshing
+    /// the cache: JSON error in BAD REQUEST response.
+    #[tokio::test]
+    async fn test_search_procs_refreshed_noparams_is_badrequest() {
+        let cache = ProcCache::default();
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let res = request()
+            .method("GET")
+            .path("/search")
+            .reply(&routes::search_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res.body()), "missing_filter");
+    }
+
+    /// Search for root processes without refreshing the cache first: empty
+    /// JSON array in OK response.
+    #[tokio::test]
+    async fn test_search_procs_empty() {
+        let res = request()
+            .method("GET")
+            .path("/search?uid=0")
+            .reply(&routes::search_procs(
+                ProcCache::default(),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.body(), "[]");
+    }
+
+    /// Search for root processes by refreshing the cache first: non-empty JSON
+    /// array in OK response.
+    #[tokio::test]
+    async fn test_search_procs_refreshed() {
+        let cache = ProcCache::default();
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let res = request()
+            .method("GET")
+            .path("/search?uid=0")
+            .reply(&routes::search_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(
+            !serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(&**res.body()).unwrap())
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    /// Spawn processes, refresh them, then search them using one and two
+    /// filters: exactly the matching processes in OK responses.
+    #[tokio::test]
+    async fn test_search_procs_scripted() {
+        let (cache, source) = scripted_cache();
+        for pid in 1..=4 {
+            source.spawn(proc_info(pid));
+        }
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let filter = routes::search_procs(Arc::clone(&cache), SharedConfig::default());
+
+        for (path, expected) in [
+            ("/search?uid=0", vec![proc_info(2), proc_info(4)]),
+            ("/search?uid=0&name=proc4", vec![proc_info(4)]),
+            ("/search?uid=1&name=proc4", vec![]),
+        ] {
+            let res = request().method("GET").path(path).reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let mut procs =
+                serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(res.body()).unwrap()).unwrap();
+            procs.sort_unstable_by_key(|proc| proc.pid);
+            assert_eq!(procs, expected);
+        }
+    }
+
+    /// Spawn processes, one with a comma in its name, refresh them, then list
+    /// them as CSV: header row and one row per process by PID, quoted as
+    /// needed, in OK response.
+    #[tokio::test]
+    async fn test_list_procs_csv() {
+        let (cache, source) = scripted_cache();
+        source
+            .spawn(proc_info(3))
+            .spawn(ProcInfo {
+                name: "a, \"b\"".to_owned(),
+                ..proc_info(1)
+            })
+            .spawn(proc_info(2));
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let filter = routes::list_procs(Arc::clone(&cache), SharedConfig::default());
+
+        for res in [
+            request()
+                .method("GET")
+                .path("/processes?format=csv")
+                .reply(&filter)
+                .await,
+            request()
+                .method("GET")
+                .path("/processes")
+                .header("accept", "application/json;q=0.5, text/csv")
+                .reply(&filter)
+                .await,
+        ] {
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(res.headers()["content-type"]
+                .to_str()
+                .unwrap()
+                .starts_with("text/csv"));
+            assert_eq!(
+                res.body(),
+                "pid,ppid,uid,name,username\n\
+                 1,,1,\"a, \"\"b\"\"\",user1\n\
+                 2,1,0,proc2,user0\n\
+                 3,1,1,proc3,user1\n"
+            );
+        }
+    }
+
+    /// Spawn processes, refresh them, then search them as TSV, with an
+    /// unacceptable format, and with an acceptable one among others: TSV in OK
+    /// response, NOT ACCEPTABLE response, JSON in OK response.
+    #[tokio::test]
+    async fn test_search_procs_negotiated() {
+        let (cache, source) = scripted_cache();
+        source.spawn(proc_info(1)).spawn(proc_info(2));
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let filter = routes::search_procs(Arc::clone(&cache), SharedConfig::default());
+
+        let res = request()
+            .method("GET")
+            .path("/search?uid=0")
+            .header("accept", "text/tab-separated-values")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.body(),
+            "pid\tppid\tuid\tname\tusername\n2\t1\t0\tproc2\tuser0\n"
+        );
+
+        for path in ["/search?uid=0&format=png", "/search?uid=0"] {
+            let res = request()
+                .method("GET")
+                .path(path)
+                .header("accept", "image/png")
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        }
+
+        let res = request()
+            .method("GET")
+            .path("/search?uid=0")
+            .header("accept", "image/png, */*;q=0.1")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(res.body()).unwrap()).unwrap(),
+            [proc_info(2)]
+        );
+    }
+
+    /// Spawn processes, refresh them, then list them as MessagePack and CBOR:
+    /// the same processes decoded from OK responses.
+    #[tokio::test]
+    async fn test_list_procs_binary() {
+        let (cache, source) = scripted_cache();
+        source.spawn(proc_info(1)).spawn(proc_info(2));
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let filter = routes::list_procs(Arc::clone(&cache), SharedConfig::default());
+        let expected = cache.read().await.get().clone();
+
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .header("accept", "application/msgpack")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/msgpack");
+        assert_eq!(
+            rmp_serde::from_slice::<proc::CacheData>(res.body()).unwrap(),
+            expected
+        );
+
+        let res = request()
+            .method("GET")
+            .path("/processes?format=cbor")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/cbor");
+        assert_eq!(
+            ciborium::from_reader::<proc::CacheData, _>(&**res.body()).unwrap(),
+            expected
+        );
+    }
+
+    /// Spawn a process, refresh, list it twice, then spawn another one and
+    /// refresh again: identical JSON bodies for the same snapshot, the new one
+    /// listing both processes while the previous one is left untouched.
+    #[tokio::test]
+    async fn test_list_procs_shared() {
+        let (cache, source) = scripted_cache();
+        let refresh = routes::refresh_procs(Arc::clone(&cache), SharedConfig::default());
+        let filter = routes::all(&cache, &SharedConfig::default());
+        source.spawn(proc_info(1));
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&refresh)
+            .await;
+
+        let first = request()
+            .method("GET")
+            .path("/processes")
+            .reply(&filter)
+            .await;
+        let second = request()
+            .method("GET")
+            .path("/processes")
+            .reply(&filter)
+            .await;
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(first.headers()["content-type"], "application/json");
+        assert_eq!(first.body(), second.body());
+
+        source.spawn(proc_info(2));
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&refresh)
+            .await;
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .reply(&filter)
+            .await;
+        let mut procs =
+            serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(res.body()).unwrap()).unwrap();
+        procs.sort_unstable_by_key(|proc| proc.pid);
+        assert_eq!(procs, [proc_info(1), proc_info(2)]);
+
+        let res = request()
+            .method("GET")
+            .path("/snapshots/1/processes")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.body(), first.body());
+    }
+
+    /// Refresh concurrently: all OK and pushed as consecutive snapshots.
+    #[tokio::test]
+    async fn test_refresh_procs_concurrent() {
+        let (cache, _source) = scripted_cache();
+        let filter = routes::refresh_procs(Arc::clone(&cache), SharedConfig::default());
+
+        let statuses = futures_util::future::join_all((0..8).map(|_| {
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&filter)
+        }))
+        .await;
+        assert!(statuses.iter().all(|res| res.status() == StatusCode::OK));
+        assert_eq!(
+            cache
+                .read()
+                .await
+                .snapshots()
+                .map(|snap| snap.id)
+                .collect::<Vec<_>>(),
+            (1..=8).collect::<Vec<_>>()
+        );
+    }
+
+    /// List processes, then again with the validators of the first response,
+    /// refresh and list again: OK with validators, NOT MODIFIED with the same
+    /// ones, then OK with a new ETag once refreshed.
+    #[tokio::test]
+    async fn test_list_procs_conditional() {
+        let (cache, source) = scripted_cache();
+        let filter = routes::all(&cache, &SharedConfig::default());
+        source.spawn(proc_info(1));
+
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let etag = res.headers()["etag"].clone();
+        let last_modified = res.headers()["last-modified"].clone();
+        let time = {
+            let cache = cache.read().await;
+            let time = cache.current().time.duration_since(UNIX_EPOCH).unwrap();
+            format!("{:x}", time.as_nanos())
+        };
+        assert_eq!(etag, format!("\"0-{time}-json\""));
+
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .header("if-none-match", &etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert!(res.body().is_empty());
+        assert_eq!(res.headers()["etag"], etag);
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .header("if-modified-since", &last_modified)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        // Another format is another representation.
+        let res = request()
+            .method("GET")
+            .path("/processes?format=csv")
+            .header("if-none-match", &etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["etag"], format!("\"0-{time}-csv\""));
+
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        let res = request()
+            .method("GET")
+            .path("/search?pid=1")
+            .header("if-none-match", &etag)
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()["etag"].to_str().unwrap().starts_with("\"1-"));
+        assert_eq!(
+            serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(res.body()).unwrap()).unwrap(),
+            [proc_info(1)]
+        );
+    }
+
+    /// Decompresses a whole body compressed in the given encoding.
+    fn decompress(encoding: Encoding, body: &[u8]) -> Vec<u8> {
+        let mut res = Vec::new();
+        match encoding {
+            Encoding::Zstd => res = zstd::decode_all(body).unwrap(),
+            Encoding::Brotli => {
+                brotli::Decompressor::new(body, 4096)
+                    .read_to_end(&mut res)
+                    .unwrap();
+            }
+            Encoding::Gzip => {
+                flate2::read::GzDecoder::new(body)
+                    .read_to_end(&mut res)
+                    .unwrap();
+            }
+        }
+        res
+    }
+
+    /// Spawn enough processes to be worth compressing, refresh them, then list
+    /// them in each encoding and without any: the same JSON once decompressed
+    /// from OK responses with a weak ETag, the first supported encoding being
+    /// preferred, then NOT MODIFIED given back the weak ETag.
+    #[tokio::test]
+    async fn test_list_procs_compressed() {
+        let (cache, source) = scripted_cache();
+        for pid in 1..=20 {
+            source.spawn(proc_info(pid));
+        }
+        let filter = compression::compressed(
+            Encoding::ALL.to_vec(),
+            routes::all(&cache, &SharedConfig::default()),
+        );
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+
+        let plain = request()
+            .method("GET")
+            .path("/processes")
+            .header("accept-encoding", "identity")
+            .reply(&filter)
+            .await;
+        assert_eq!(plain.status(), StatusCode::OK);
+        assert!(!plain.headers().contains_key("content-encoding"));
+        assert_eq!(plain.headers()["vary"], "accept-encoding");
+
+        for (accept, encoding) in [
+            ("gzip, deflate, br, zstd", Encoding::Zstd),
+            ("zstd;q=0.5, br", Encoding::Brotli),
+            ("x-gzip", Encoding::Gzip),
+            ("*;q=0.1, zstd;q=0, br;q=0", Encoding::Gzip),
+        ] {
+            let res = request()
+                .method("GET")
+                .path("/processes")
+                .header("accept-encoding", accept)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                res.headers()["content-encoding"],
+                format!("{encoding:?}")
+                    .to_lowercase()
+                    .replace("brotli", "br")
+            );
+            assert!(res.body().len() < plain.body().len());
+            assert_eq!(decompress(encoding, res.body()), &plain.body()[..]);
+            // The compressed bytes are only weakly equivalent.
+            let etag = res.headers()["etag"].clone();
+            assert_eq!(
+                etag.as_bytes(),
+                [b"W/", plain.headers()["etag"].as_bytes()].concat()
+            );
+
+            let res = request()
+                .method("GET")
+                .path("/processes")
+                .header("accept-encoding", accept)
+                .header("if-none-match", &etag)
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+            assert_eq!(res.headers()["etag"], etag);
+        }
+    }
+
+    /// Compresses a streamed body in the given encoding, sending it an item at
+    /// a time, and checks that each item is decompressed by the given decoder
+    /// as soon as it is sent.
+    async fn assert_flushed<W: Write>(
+        encoding: Encoding,
+        mut decoder: W,
+        decoded: fn(&W) -> &[u8],
+    ) {
+        let (mut tx, body) = Body::channel();
+        let mut body = compression::compress(Some(encoding), Response::new(body)).into_body();
+        let mut expected = Vec::new();
+
+        for pid in 1..=2 {
+            let item = serde_json::to_vec(&proc_info(pid)).unwrap();
+            expected.extend_from_slice(&item);
+            tx.try_send_data(Bytes::from(item)).unwrap();
+            decoder
+                .write_all(&body.data().await.unwrap().unwrap())
+                .unwrap();
+            decoder.flush().unwrap();
+            assert_eq!(decoded(&decoder), expected, "{encoding:?}");
+        }
+        drop(tx);
+        while let Some(chunk) = body.data().await {
+            decoder.write_all(&chunk.unwrap()).unwrap();
+        }
+        decoder.flush().unwrap();
+        assert_eq!(decoded(&decoder), expected, "{encoding:?}");
+    }
+
+    /// Compress a streamed body in each encoding, sending it an item at a
+    /// time: each item decompressed as soon as it is sent.
+    #[tokio::test]
+    async fn test_compress_stream_flushed() {
+        assert_flushed(
+            Encoding::Zstd,
+            zstd::stream::write::Decoder::new(Vec::new()).unwrap(),
+            |dec| dec.get_ref(),
+        )
+        .await;
+        assert_flushed(
+            Encoding::Brotli,
+            brotli::DecompressorWriter::new(Vec::new(), 4096),
+            |dec| dec.get_ref(),
+        )
+        .await;
+        assert_flushed(
+            Encoding::Gzip,
+            flate2::write::GzDecoder::new(Vec::new()),
+            |dec| dec.get_ref(),
+        )
+        .await;
+    }
+
+    /// Refresh more times than the history can retain, then list snapshots:
+    /// only the most recent ones are listed in OK response.
+    #[tokio::test]
+    async fn test_list_snapshots_bounded() {
+        let cache: ProcCache = Arc::new(RwLock::new(CacheInner::new(2)));
+
+        for _ in 0..3 {
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default(),
+                ))
+                .await;
+        }
+
+        let res = request()
+            .method("GET")
+            .path("/snapshots")
+            .reply(&routes::list_snapshots(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let snaps =
+            serde_json::from_str::<Vec<SnapshotInfo>>(str::from_utf8(res.body()).unwrap()).unwrap();
+        assert_eq!(snaps.iter().map(|snap| snap.id).collect::<Vec<_>>(), [2, 3]);
+        assert!(snaps.iter().all(|snap| snap.count != 0));
+        assert!(snaps[0].time <= snaps[1].time);
+    }
+
+    /// Refresh processes, then fetch a retained snapshot and an evicted one:
+    /// non-empty JSON array in OK response, then NOT FOUND response.
+    #[tokio::test]
+    async fn test_snapshot_procs() {
+        let cache: ProcCache = Arc::new(RwLock::new(CacheInner::new(1)));
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        let res = request()
+            .method("GET")
+            .path("/snapshots/1/processes")
+            .reply(&routes::snapshot_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(
+            !serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(res.body()).unwrap())
+                .unwrap()
+                .is_empty()
+        );
+
+        let res = request()
+            .method("GET")
+            .path("/snapshots/0/processes")
+            .reply(&routes::snapshot_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Refresh processes, then fetch and search them at a time preceding any
+    /// snapshot and at the current time: NOT FOUND response, then non-empty
+    /// JSON array in OK response.
+    #[tokio::test]
+    async fn test_procs_at() {
+        let cache = ProcCache::default();
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let now = proc::Timestamp(std::time::SystemTime::now());
+
+        let filter = routes::all(&cache, &SharedConfig::default());
+
+        for path in ["/processes?at=0", "/search?uid=0&at=0"] {
+            let res = request().method("GET").path(path).reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+
+        for path in [
+            format!("/processes?at={now}"),
+            format!("/search?uid=0&at={now}"),
+        ] {
+            let res = request().method("GET").path(&path).reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert!(
+                !serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(res.body()).unwrap())
+                    .unwrap()
+                    .is_empty()
+            );
+        }
+    }
+
+    /// Query the diff endpoint without a base, then with an unknown one, then
+    /// comparing a snapshot to itself, then to the empty one preceding it:
+    /// BAD REQUEST and NOT FOUND JSON errors, empty differences in OK
+    /// responses, and NOT FOUND response.
+    #[tokio::test]
+    async fn test_diff_snapshots() {
+        let cache = ProcCache::default();
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let filter = routes::diff_snapshots(Arc::clone(&cache), SharedConfig::default());
+
+        let res = request().method("GET").path("/diff").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res.body()), "missing_from");
+
+        let res = request()
+            .method("GET")
+            .path("/diff?from=42")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error_code(res.body()), "not_found");
+
+        for path in ["/diff?from=1", "/diff?from=1&to=1"] {
+            let res = request().method("GET").path(path).reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                serde_json::from_str::<Diff>(str::from_utf8(res.body()).unwrap()).unwrap(),
+                Diff::default()
+            );
+        }
+
+        // The empty snapshot preceding the first refresh is not retained.
+        let res = request()
+            .method("GET")
+            .path("/diff?from=0&to=1")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Upload a base made of a modified process, an unknown one, and missing
+    /// all others: each appears in the right category in OK response. Then
+    /// upload one with a duplicate PID: BAD REQUEST response.
+    #[tokio::test]
+    async fn test_diff_uploaded() {
+        let cache = ProcCache::default();
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        let current = cache.read().await.get().clone();
+        let kept = current.iter().next().unwrap().clone();
+        let modified = ProcInfo {
+            name: format!("not-{}", kept.name),
+            ..kept.clone()
+        };
+        let unknown = ProcInfo {
+            pid: u32::MAX,
+            ppid: None,
+            uid: 0,
+            name: "unknown".to_owned(),
+            username: "root".to_owned(),
+        };
+
+        let res = request()
+            .method("POST")
+            .path("/diff")
+            .json(&[&modified, &unknown])
+            .reply(&routes::diff_uploaded(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let diff = serde_json::from_str::<Diff>(str::from_utf8(res.body()).unwrap()).unwrap();
+        assert_eq!(diff.removed, [unknown]);
+        assert_eq!(diff.modified.len(), 1);
+        assert_eq!(diff.modified[0].from, modified);
+        assert_eq!(diff.modified[0].to, kept);
+        assert_eq!(diff.added.len(), current.len() - 1);
+        assert!(!diff.added.contains(&kept));
+
+        // Processes of the same PID cannot be told apart.
+        let res = request()
+            .method("POST")
+            .path("/diff")
+            .json(&[&kept, &modified])
+            .reply(&routes::diff_uploaded(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res.body()), "duplicate_pid");
+    }
+
+    /// Refresh processes with a state directory, then restore another cache
+    /// from it: the same snapshots are observed, within the history capacity.
+    #[tokio::test]
+    async fn test_state_dir_restore() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = {
+            let mut inner = CacheInner::new(3);
+            inner
+                .persist_to(StateDir::open(dir.path()).unwrap())
+                .unwrap();
+            Arc::new(RwLock::new(inner))
+        };
+
+        for _ in 0..3 {
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default(),
+                ))
+                .await;
+        }
+
+        let mut restored = CacheInner::new(2);
+        restored
+            .persist_to(StateDir::open(dir.path()).unwrap())
+            .unwrap();
+        let cache = cache.read().await;
+
+        assert_eq!(
+            restored
+                .snapshots()
+                .map(|snap| snap.info())
+                .collect::<Vec<_>>(),
+            cache
+                .snapshots()
+                .skip(1)
+                .map(|snap| snap.info())
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(restored.get(), cache.get());
+        assert_eq!(dir.path().read_dir().unwrap().count(), 2);
+    }
+
+    /// Restore a cache from a state directory written with the previous
+    /// version of the format: the same snapshot, its processes having no
+    /// parent.
+    #[test]
+    fn test_state_dir_restore_v1() {
+        let dir = tempfile::tempdir().unwrap();
+        let time = UNIX_EPOCH + Duration::from_secs(42);
+        // The processes were encoded as pid, uid, name and username.
+        let record =
+            bincode::serialize(&(7_u64, time, vec![(1_u32, 0_u32, "init", "root")])).unwrap();
+        let mut file = b"PROCAPI\x01".to_vec();
+        file.extend(u32::try_from(record.len()).unwrap().to_le_bytes());
+        file.extend(record);
+        fs::write(dir.path().join(format!("{:020}.snap", 7)), file).unwrap();
+
+        let mut restored = CacheInner::new(2);
+        restored
+            .persist_to(StateDir::open(dir.path()).unwrap())
+            .unwrap();
+
+        let snap = restored.current();
+        assert_eq!((snap.id, snap.time), (7, time));
+        assert_eq!(
+            restored.get(),
+            &[ProcInfo {
+                pid: 1,
+                ppid: None,
+                uid: 0,
+                name: "init".to_owned(),
+                username: "root".to_owned(),
+            }]
+            .into_iter()
+            .collect()
+        );
+    }
+
+    /// Export the snapshots of a refreshed cache, then import them into an
+    /// empty one: renumbered snapshots in OK response and same processes.
+    /// Then import invalid ones: BAD REQUEST responses.
+    #[tokio::test]
+    async fn test_export_import_snapshots() {
+        let src = ProcCache::default();
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&src),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        let res = request()
+            .method("GET")
+            .path("/snapshots/1/export")
+            .reply(&routes::export_snapshots(
+                Arc::clone(&src),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let dst = ProcCache::default();
+        let filter = routes::import_snapshots(Arc::clone(&dst), SharedConfig::default());
+        for _ in 0..2 {
+            let res = request()
+                .method("POST")
+                .path("/snapshots/import")
+                .body(res.body())
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+
+        let src = src.read().await;
+        let dst = dst.read().await;
+        assert_eq!(dst.get(), src.get());
+        assert_eq!(dst.current().id, 2);
+        assert_eq!(dst.current().time, src.current().time);
+
+        let res = request()
+            .method("POST")
+            .path("/snapshots/import")
+            .body("garbage")
+            .reply(&routes::import_snapshots(
+                ProcCache::default(),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        // A record claiming the maximum length is rejected as truncated.
+        let res = request()
+            .method("POST")
+            .path("/snapshots/import")
+            .body([&b"PROCAPI\x02"[..], &(1u32 << 30).to_le_bytes()].concat())
+            .reply(&routes::import_snapshots(
+                ProcCache::default(),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(str::from_utf8(res.body()).unwrap().contains("Truncated"));
+
+        let renamed = ProcInfo {
+            name: "renamed".to_owned(),
+            ..proc_info(1)
+        };
+        let snap = proc::Snapshot::new(1, [proc_info(1), renamed].into_iter().collect());
+        let res = request()
+            .method("POST")
+            .path("/snapshots/import")
+            .body(store::encode([&snap]).unwrap())
+            .reply(&routes::import_snapshots(
+                ProcCache::default(),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(str::from_utf8(res.body()).unwrap().contains("PID 1"));
+    }
+
+    /// Record refreshes, then replay them: the same processes are observed
+    /// in the same order, then INTERNAL SERVER ERROR responses once the end of
+    /// the record is reached.
+    #[tokio::test]
+    async fn test_record_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let record = dir.path().join("record");
+        let recorded = {
+            let mut inner = CacheInner::default();
+            inner.set_source(Box::new(
+                RecordingSource::new(SysinfoSource::default(), &record).unwrap(),
+            ));
+            Arc::new(RwLock::new(inner))
+        };
+        let replayed = {
+            let mut inner = CacheInner::default();
+            let filter = routes::refresh_procs(Arc::clone(&recorded), SharedConfig::default());
+
+            for _ in 0..2 {
+                request()
+                    .method("POST")
+                    .path("/acquire_process_list")
+                    .reply(&filter)
+                    .await;
+            }
+
+            inner.set_source(Box::new(ReplaySource::open(&record).unwrap()));
+            Arc::new(RwLock::new(inner))
+        };
+        let filter = routes::refresh_procs(Arc::clone(&replayed), SharedConfig::default());
+
+        for id in 1..=2 {
+            let res = request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(
+                replayed.read().await.get(),
+                &recorded.read().await.snapshot(id).unwrap().procs
+            );
+        }
+
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(replayed.read().await.current().id, 2);
+    }
+
+    /// Spawn and refresh processes, then stream them as NDJSON, MessagePack
+    /// and CBOR sequences, asking for JSON, and in unsupported formats:
+    /// exactly the cached processes are decoded, SSE is sent for JSON, and NOT
+    /// ACCEPTABLE responses otherwise.
+    #[tokio::test]
+    async fn test_stream_procs_formats() {
+        let (cache, source) = scripted_cache();
+        source.spawn(proc_info(1)).spawn(proc_info(2));
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        let filter = routes::stream_procs(Arc::clone(&cache), SharedConfig::default());
+        let expected = cache.read().await.get().clone();
+
+        let res = request()
+            .method("GET")
+            .path("/data")
+            .header("accept", "application/x-ndjson")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/x-ndjson");
+        assert_eq!(
+            str::from_utf8(res.body())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect::<proc::CacheData>(),
+            expected
+        );
+
+        let res = request()
+            .method("GET")
+            .path("/data?format=msgpack")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut procs = proc::CacheData::new();
+        let mut de = rmp_serde::Deserializer::new(&**res.body());
+        while procs.len() < expected.len() {
+            procs.insert(serde::Deserialize::deserialize(&mut de).unwrap());
+        }
+        assert_eq!(procs, expected);
+
+        let res = request()
+            .method("GET")
+            .path("/data")
+            .header("accept", "application/cbor-seq")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/cbor-seq");
+        let mut body = &**res.body();
+        let mut procs = proc::CacheData::new();
+        while !body.is_empty() {
+            procs.insert(ciborium::from_reader(&mut body).unwrap());
+        }
+        assert_eq!(procs, expected);
+
+        let res = request()
+            .method("GET")
+            .path("/data")
+            .header("accept", "application/json, text/plain;q=0.5")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+
+        for res in [
+            request()
+                .method("GET")
+                .path("/data?format=csv")
+                .reply(&filter)
+                .await,
+            request()
+                .method("GET")
+                .path("/data")
+                .header("accept", "application/octet-stream")
+                .reply(&filter)
+                .await,
+        ] {
+            assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        }
+    }
+
+    /// Spawn processes, refresh them once successfully and once not, then
+    /// fetch the metrics: exact counts and escaped labels in OK response.
+    #[tokio::test]
+    async fn test_metrics() {
+        let (cache, source) = scripted_cache();
+        let filter = routes::refresh_procs(Arc::clone(&cache), SharedConfig::default());
+        source
+            .spawn(proc_info(1))
+            .spawn(proc_info(2))
+            .spawn(ProcInfo {
+                name: "quoted\"name".to_owned(),
+                ..proc_info(3)
+            });
+
+        for _ in 0..2 {
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&filter)
+                .await;
+            source.fail_next("Scripted failure.");
+        }
+
+        let res = request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&routes::metrics(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = str::from_utf8(res.body()).unwrap();
+        for line in [
+            "proc_api_processes 3",
+            "proc_api_user_processes{uid=\"0\",username=\"user0\"} 1",
+            "proc_api_user_processes{uid=\"1\",username=\"user1\"} 2",
+            "proc_api_name_processes{name=\"quoted\\\"name\"} 1",
+            "proc_api_snapshot_id 1",
+            "proc_api_refreshes_total 2",
+            "proc_api_refresh_failures_total 1",
+            "proc_api_refresh_duration_seconds_count 2",
+            "proc_api_sse_subscribers 0",
+            "proc_api_sse_lagged_total 0",
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {line:?}");
+        }
+        assert!(!body.contains("proc_api_process_memory_bytes"));
+    }
+
+    /// Enable per-process gauges capped to two processes, refresh the host's
+    /// processes, then fetch the metrics: two gauges of each kind.
+    #[tokio::test]
+    async fn test_metrics_proc_gauges() {
+        let cache = {
+            let mut inner = CacheInner::default();
+            inner.metrics_mut().set_proc_gauges(2);
+            Arc::new(RwLock::new(inner))
+        };
+        request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&routes::refresh_procs(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+
+        let res = request()
+            .method("GET")
+            .path("/metrics")
+            .reply(&routes::metrics(
+                Arc::clone(&cache),
+                SharedConfig::default(),
+            ))
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = str::from_utf8(res.body()).unwrap();
+        for name in [
+            "proc_api_process_memory_bytes{",
+            "proc_api_process_virtual_memory_bytes{",
+            "proc_api_process_cpu_usage_percent{",
+        ] {
+            assert_eq!(body.lines().filter(|l| l.starts_with(name)).count(), 2);
+        }
+    }
+
+    /// Start the stream, wait for the test timout: no data received.
+    #[tokio::test]
+    async fn test_stream_procs_empty() {
+        let (cache, _) = scripted_cache();
+
+        let stream = {
+            let cache = Arc::clone(&cache);
+
+            tokio::spawn(async move {
+                request()
+                    .method("GET")
+                    .path("/data")
+                    .reply(&routes::stream_procs(cache, SharedConfig::default()))
+                    .await
+            })
+        };
+
+        assert!(cache.read().await.get().is_empty());
+
+        let res = tokio::join!(stream).0.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(sse_procs(res.body()).is_empty());
+    }
+
+    /// Start the stream, spawn processes, refresh them, wait for the timeout:
+    /// exactly the spawned processes are received.
+    #[tokio::test]
+    async fn test_stream_procs_refreshed_after() {
+        let (cache, source) = scripted_cache();
+        let sync = Arc::new(Barrier::new(2));
+
+        let stream = {
+            let cache = Arc::clone(&cache);
+            let sync = Arc::clone(&sync);
+
+            tokio::spawn(async move {
+                let filter = routes::stream_procs(cache, SharedConfig::default());
+                let fut = request().method("GET").path("/data").reply(&filter);
+                sync.wait().await;
+                fut.await
+            })
+        };
+
+        sync.wait().await;
+        source.spawn(proc_info(1)).spawn(proc_info(2));
+        assert_eq!(
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default()
+                ))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(cache.read().await.get().len(), 2);
+
+        let res = tokio::join!(stream).0.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut procs = sse_procs(res.body());
+        procs.sort_unstable_by_key(|proc| proc.pid);
+        assert_eq!(procs, [proc_info(1), proc_info(2)]);
+    }
+
+    /// Spawn and refresh processes, start SSE and NDJSON streams, make one exit
+    /// and spawn another, refresh them, wait for the timeout: the exited one
+    /// is received as a `removed` SSE event, and not at all as NDJSON.
+    #[tokio::test]
+    async fn test_stream_procs_removed() {
+        let (cache, source) = scripted_cache();
+        let sync = Arc::new(Barrier::new(3));
+        source.spawn(proc_info(1)).spawn(proc_info(2));
+        CacheInner::refresh(&cache).await.unwrap();
+
+        let streams = ["sse", "ndjson"].map(|format| {
+            let cache = Arc::clone(&cache);
+            let sync = Arc::clone(&sync);
+
+            tokio::spawn(async move {
+                let filter = routes::stream_procs(cache, SharedConfig::default());
+                let fut = request()
+                    .method("GET")
+                    .path(&format!("/data?format={format}"))
+                    .reply(&filter);
+                sync.wait().await;
+                fut.await
+            })
+        });
+
+        sync.wait().await;
+        source.exit(1).spawn(proc_info(3));
+        CacheInner::refresh(&cache).await.unwrap();
+
+        let [sse, ndjson] = streams;
+        let res = sse.await.unwrap();
+        let mut events = sse_events(res.body());
+        events[..2].sort_unstable_by_key(|(_, proc)| proc.pid);
+        events[2..].sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        assert_eq!(
+            events,
+            [
+                ("message".to_owned(), proc_info(1)),
+                ("message".to_owned(), proc_info(2)),
+                ("message".to_owned(), proc_info(3)),
+                ("removed".to_owned(), proc_info(1)),
+            ]
+        );
+
+        let res = ndjson.await.unwrap();
+        let mut procs = str::from_utf8(res.body())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str::<ProcInfo>(line).unwrap().pid)
+            .collect::<Vec<_>>();
+        procs.sort_unstable();
+        assert_eq!(procs, [1, 2, 3]);
+    }
+
+    /// Spawn and refresh processes first, then open the stream, wait for the
+    /// timeout: exactly the cached processes are observed.
+    #[tokio::test]
+    async fn test_stream_procs_refreshed_first() {
+        let (cache, source) = scripted_cache();
+
+        source.spawn(proc_info(1)).spawn(proc_info(2));
+        assert_eq!(
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default()
+                ))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(cache.read().await.get().len(), 2);
+
+        let stream = {
+            let cache = Arc::clone(&cache);
+
+            tokio::spawn(async move {
+                request()
+                    .method("GET")
+                    .path("/data")
+                    .reply(&routes::stream_procs(cache, SharedConfig::default()))
+                    .await
+            })
+        };
+
+        let res = tokio::join!(stream).0.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut procs = sse_procs(res.body());
+        procs.sort_unstable_by_key(|proc| proc.pid);
+        assert_eq!(procs, [proc_info(1), proc_info(2)]);
+    }
+
+    /// Spawn and refresh a process first, then open the stream, spawn another
+    /// one, make the first exit, refresh again, wait for the timeout: the
+    /// cached process then only the newly-spawned one are observed.
+    #[tokio::test]
+    async fn test_stream_procs_refreshed_first_and_after() {
+        let (cache, source) = scripted_cache();
+        let sync = Arc::new(Barrier::new(2));
+
+        source.spawn(proc_info(1));
+        assert_eq!(
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default()
+                ))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+
+        let stream = {
+            let cache = Arc::clone(&cache);
+            let sync = Arc::clone(&sync);
+
+            tokio::spawn(async move {
+                let filter = routes::stream_procs(cache, SharedConfig::default());
+                let fut = request().method("GET").path("/data").reply(&filter);
+                sync.wait().await;
+                fut.await
+            })
+        };
+
+        sync.wait().await;
+        time::sleep(Duration::from_millis(500)).await;
+        source.spawn(proc_info(2)).exit(1);
+        assert_eq!(
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default()
+                ))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(cache.read().await.get().len(), 1);
+
+        let res = tokio::join!(stream).0.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(sse_procs(res.body()), [proc_info(1), proc_info(2)]);
+    }
+
+    /// Open the stream, spawn and refresh a process, wait for the timeout,
+    /// refresh again: observe the process, then a working refresh once the
+    /// stream is closed.
+    #[tokio::test]
+    async fn test_stream_procs_close_refreshed_after() {
+        let (cache, source) = scripted_cache();
+        let sync = Arc::new(Barrier::new(2));
+
+        let stream = {
+            let cache = Arc::clone(&cache);
+            let sync = Arc::clone(&sync);
+
+            tokio::spawn(async move {
+                let filter = routes::stream_procs(cache, SharedConfig::default());
+                let fut = request().method("GET").path("/data").reply(&filter);
+                sync.wait().await;
+                fut.await
+            })
+        };
+
+        sync.wait().await;
+        source.spawn(proc_info(1));
+        assert_eq!(
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default()
+                ))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+
+        let res = tokio::join!(stream).0.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(sse_procs(res.body()), [proc_info(1)]);
+
+        source.spawn(proc_info(2));
+        assert_eq!(
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .reply(&routes::refresh_procs(
+                    Arc::clone(&cache),
+                    SharedConfig::default()
+                ))
+                .await
+                .status(),
+            StatusCode::OK
+        );
+        assert_eq!(cache.read().await.get().len(), 2);
+    }
+
+    /// Repeat multiple times: open a stream, spawn and refresh a process,
+    /// close the stream, spawn and refresh another, open another stream and
+    /// close it right after. The new process should be observed by the first
+    /// stream and all cached processes by the second one at each iteration.
+    #[tokio::test]
+    async fn test_stream_procs_close_reopen_refreshed() {
+        let (cache, source) = scripted_cache();
+        let sync = Arc::new(Barrier::new(2));
+
+        for i in 0..3 {
+            let stream = {
+                let cache = Arc::clone(&cache);
+                let sync = Arc::clone(&sync);
+
+                tokio::spawn(async move {
+                    let filter = routes::stream_procs(cache, SharedConfig::default());
+                    let fut = request().method("GET").path("/data").reply(&filter);
+                    sync.wait().await;
+                    fut.await
+                })
+            };
+
+            sync.wait().await;
+            source.spawn(proc_info(2 * i));
+            assert_eq!(
+                request()
+                    .method("POST")
+                    .path("/acquire_process_list")
+                    .reply(&routes::refresh_procs(
+                        Arc::clone(&cache),
+                        SharedConfig::default()
+                    ))
+                    .await
+                    .status(),
+                StatusCode::OK
+            );
+
+            let res = tokio::join!(stream).0.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let procs = sse_procs(res.body());
+            assert_eq!(procs.len(), 2 * i as usize + 1);
+            assert_eq!(procs.last(), Some(&proc_info(2 * i)));
+
+            source.spawn(proc_info(2 * i + 1));
+            assert_eq!(
+                request()
+                    .method("POST")
+                    .path("/acquire_process_list")
+                    .reply(&routes::refresh_procs(
+                        Arc::clone(&cache),
+                        SharedConfig::default()
+                    ))
+                    .await
+                    .status(),
+                StatusCode::OK
+            );
+
+            let stream = {
+                let cache = Arc::clone(&cache);
+
+                tokio::spawn(async move {
+                    request()
+                        .method("GET")
+                        .path("/data")
+                        .reply(&routes::stream_procs(cache, SharedConfig::default()))
+                        .await
+                })
+            };
+
+            let res = tokio::join!(stream).0.unwrap();
+            assert_eq!(res.status(), StatusCode::OK);
+            let mut procs = sse_procs(res.body());
+            procs.sort_unstable_by_key(|proc| proc.pid);
+            assert_eq!(procs, (0..=2 * i + 1).map(proc_info).collect::<Vec<_>>());
+        }
+    }
+
+    /// Builds a certificate for `localhost` with the given common name, able
+    /// to issue other ones if requested.
+    fn tls_cert(name: &str, authority: bool) -> rcgen::Certificate {
+        let mut params = rcgen::CertificateParams::new(vec!["localhost".to_owned()]);
+        params
+            .distinguished_name
+            .push(rcgen::DnType::CommonName, name);
+        if authority {
+            params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        }
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    /// Writes the given certificate issued by the given authority, and its
+    /// key, to the files the server of [`tls_serve`] is loaded from.
+    fn tls_write(dir: &Path, cert: &rcgen::Certificate, ca: &rcgen::Certificate) {
+        fs::write(
+            dir.join("cert.pem"),
+            cert.serialize_pem_with_signer(ca).unwrap(),
+        )
+        .unwrap();
+        fs::write(dir.join("key.pem"), cert.serialize_private_key_pem()).unwrap();
+        fs::write(dir.join("ca.pem"), ca.serialize_pem().unwrap()).unwrap();
+    }
+
+    /// Serves the subject of the client at `/peer` over TLS, using the files
+    /// written by [`tls_write`] and requiring client certificates if asked.
+    async fn tls_serve(dir: &Path, mutual: bool) -> (Arc<Tls>, SocketAddr) {
+        let tls = Tls::load(
+            dir.join("cert.pem"),
+            dir.join("key.pem"),
+            mutual.then(|| dir.join("ca.pem")),
+        )
+        .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let filter = warp::path("peer")
+            .and(server::peer())
+            .map(|peer: server::Peer| peer.subject.unwrap_or_default());
+
+        tokio::spawn(server::serve_tcp(listener, Some(Arc::clone(&tls)), filter));
+        (tls, addr)
+    }
+
+    /// Requests `/peer` over TLS, trusting the given authority and presenting
+    /// the given client certificate issued by it if any. `None` means that
+    /// the connection failed.
+    async fn tls_get(
+        addr: SocketAddr,
+        ca: &rcgen::Certificate,
+        client: Option<&rcgen::Certificate>,
+    ) -> Option<String> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore};
+
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(&Certificate(ca.serialize_der().unwrap()))
+            .unwrap();
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match client {
+            None => builder.with_no_client_auth(),
+            Some(client) => builder
+                .with_client_auth_cert(
+                    vec![Certificate(client.serialize_der_with_signer(ca).unwrap())],
+                    PrivateKey(client.serialize_private_key_der()),
+                )
+                .unwrap(),
+        };
+
+        let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .ok()?;
+        stream
+            .write_all(b"GET /peer HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .ok()?;
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.ok()?;
+        Some(res)
+    }
+
+    /// Serve over mutual TLS, then request with and without a client
+    /// certificate: OK response with the subject of the certificate, and a
+    /// failed connection.
+    #[tokio::test]
+    async fn test_tls_client_subject() {
+        let dir = tempfile::tempdir().unwrap();
+        let ca = tls_cert("ca", true);
+        tls_write(dir.path(), &tls_cert("server", false), &ca);
+        let (_tls, addr) = tls_serve(dir.path(), true).await;
+
+        let res = tls_get(addr, &ca, Some(&tls_cert("client", false)))
+            .await
+            .unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+        assert!(res.ends_with("\r\n\r\nCN=client"), "{res}");
+
+        assert_eq!(tls_get(addr, &ca, None).await, None);
+    }
+
+    /// Serve over TLS, then replace the certificate by one issued by another
+    /// authority and reload it: OK responses when trusting the authority of
+    /// the current certificate, but a failed connection for the previous one.
+    #[tokio::test]
+    async fn test_tls_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let (old_ca, new_ca) = (tls_cert("old", true), tls_cert("new", true));
+        tls_write(dir.path(), &tls_cert("server", false), &old_ca);
+        let (tls, addr) = tls_serve(dir.path(), false).await;
+
+        let res = tls_get(addr, &old_ca, None).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+
+        tls_write(dir.path(), &tls_cert("server", false), &new_ca);
+        tls.reload().unwrap();
+        let res = tls_get(addr, &new_ca, None).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+        assert_eq!(tls_get(addr, &old_ca, None).await, None);
+    }
+
+    /// Serve over TLS, then connect without starting the handshake: connection
+    /// closed by the server after a while.
+    #[tokio::test(start_paused = true)]
+    async fn test_tls_handshake_timeout() {
+        use tokio::io::AsyncReadExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        tls_write(
+            dir.path(),
+            &tls_cert("server", false),
+            &tls_cert("ca", true),
+        );
+        let (_tls, addr) = tls_serve(dir.path(), false).await;
+
+        let start = time::Instant::now();
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let read = time::timeout(Duration::from_secs(20), stream.read_u8()).await;
+        assert!(read.unwrap().is_err());
+        assert!(start.elapsed() >= Duration::from_secs(10));
+    }
+
+    /// Leave a stale socket behind, bind over it with a mode, then request
+    /// the server: OK response with the UID of the test, and binding again
+    /// fails as long as the socket is in use, as it does over another file.
+    #[tokio::test]
+    async fn test_unix_socket() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("api.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+
+        let listener = server::bind_unix(&path, Some(0o600), None).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        // Only the socket is left, not the directory it was bound in.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        let filter = warp::path("peer")
+            .and(server::peer())
+            .map(|peer: server::Peer| peer.cred.unwrap().uid().to_string());
+        tokio::spawn(server::serve_unix(listener, filter));
+
+        let mut stream = tokio::net::UnixStream::connect(&path).await.unwrap();
+        stream
+            .write_all(b"GET /peer HTTP/1.1\r\nhost: localhost\r\nconnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        stream.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"), "{res}");
+        assert!(
+            res.ends_with(&format!("\r\n\r\n{}", fs::metadata(&path).unwrap().uid())),
+            "{res}"
+        );
+
+        assert!(server::bind_unix(&path, None, None).is_err());
+        let file = dir.path().join("file");
+        fs::write(&file, "").unwrap();
+        assert!(server::bind_unix(&file, None, None).is_err());
+        assert!(file.exists());
+    }
+
+    /// Require tokens, then request without any, with an unknown one, with
+    /// one lacking the scope of the route and with one granting it:
+    /// UNAUTHORIZED, UNAUTHORIZED, FORBIDDEN with JSON bodies, then OK.
+    #[tokio::test]
+    async fn test_auth_scopes() {
+        let (cache, _source) = scripted_cache();
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[
+                    {"token": "reader", "scopes": ["read"]},
+                    {"token": "refresher", "name": "cron", "scopes": ["read", "refresh"]}
+                ]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        let filter = routes::all(&cache, &Arc::new(config));
+        let refresh = |token: &str| {
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .header("authorization", format!("Bearer {token}"))
+        };
+
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(res.headers()["www-authenticate"], "Bearer");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(res.body()).unwrap()["code"],
+            "unauthorized"
+        );
+        let res = refresh("unknown").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+        let res = refresh("reader").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            res.headers()["www-authenticate"],
+            "Bearer error=\"insufficient_scope\", scope=\"refresh\""
+        );
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(res.body()).unwrap()["code"],
+            "forbidden"
+        );
+        let res = request()
+            .method("GET")
+            .path("/data")
+            .header("authorization", "Bearer refresher")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        assert_eq!(
+            refresh("refresher").reply(&filter).await.status(),
+            StatusCode::OK
+        );
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .header("authorization", "bearer reader")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = request()
+            .method("GET")
+            .path("/unknown")
+            .header("authorization", "Bearer reader")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Only show callers their own processes, then list and stream them as
+    /// identified by tokens, client certificate subjects, or not at all: only
+    /// those of the caller's user, all of them for admins, FORBIDDEN for
+    /// unidentified callers.
+    #[tokio::test]
+    async fn test_own_processes() {
+        let (cache, source) = scripted_cache();
+        source
+            .spawn(proc_info(1))
+            .spawn(proc_info(2))
+            .spawn(proc_info(3));
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[
+                    {"token": "one", "uid": 1, "scopes": ["read", "stream"]},
+                    {"token": "admin", "admin": true, "scopes": ["read", "refresh"]},
+                    {"token": "anonymous", "scopes": ["read"]}
+                ]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        config.restrict_visibility(
+            [(
+                "CN=zero".to_owned(),
+                auth::Identity {
+                    uid: Some(0),
+                    admin: false,
+                },
+            )]
+            .into_iter()
+            .collect(),
+        );
+        let filter = routes::all(&cache, &Arc::new(config));
+        let list = |token: &str| {
+            request()
+                .method("GET")
+                .path("/processes")
+                .header("authorization", format!("Bearer {token}"))
+        };
+        let pids = |body: &[u8]| {
+            let mut pids = serde_json::from_slice::<Vec<ProcInfo>>(body)
+                .unwrap()
+                .into_iter()
+                .map(|proc| proc.pid)
+                .collect::<Vec<_>>();
+            pids.sort();
+            pids
+        };
+
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .header("authorization", "Bearer admin")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = list("one").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(pids(res.body()), [1, 3]);
+        let one = res.headers()["etag"].clone();
+        let res = list("admin").reply(&filter).await;
+        assert_eq!(pids(res.body()), [1, 2, 3]);
+        assert_ne!(res.headers()["etag"], one);
+        let res = list("admin")
+            .header("if-none-match", one.clone())
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = list("anonymous")
+            .extension(server::Peer {
+                subject: Some("CN=zero".to_owned()),
+                ..Default::default()
+            })
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(pids(res.body()), [2]);
+        let res = list("anonymous").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(res.body()).unwrap()["code"],
+            "unidentified"
+        );
+
+        let res = request()
+            .method("GET")
+            .path("/search?name=proc2")
+            .header("authorization", "Bearer one")
+            .reply(&filter)
+            .await;
+        assert!(pids(res.body()).is_empty());
+        let res = request()
+            .method("GET")
+            .path("/data?format=ndjson")
+            .header("authorization", "Bearer one")
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            str::from_utf8(res.body())
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str::<ProcInfo>(line).unwrap().pid)
+                .collect::<std::collections::BTreeSet<_>>(),
+            [1, 3].into()
+        );
+    }
+
+    /// List, compare, export and import snapshots, and read metrics, while
+    /// owning processes of user 1 or as an admin: only the processes of the
+    /// user are counted, compared and exported, and FORBIDDEN for imports and
+    /// metrics.
+    #[tokio::test]
+    async fn test_own_snapshots() {
+        let (cache, source) = scripted_cache();
+        source
+            .spawn(proc_info(1))
+            .spawn(proc_info(2))
+            .spawn(proc_info(3));
+        CacheInner::refresh(&cache).await.unwrap();
+        source
+            .exit(2)
+            .exit(3)
+            .spawn(proc_info(4))
+            .spawn(proc_info(5));
+        CacheInner::refresh(&cache).await.unwrap();
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[
+                    {"token": "one", "uid": 1, "scopes": ["read", "refresh"]},
+                    {"token": "admin", "admin": true, "scopes": ["read", "refresh"]}
+                ]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        config.restrict_visibility(Subjects::default());
+        let filter = routes::all(&cache, &Arc::new(config));
+        let get = |token: &str, path: &str| {
+            request()
+                .method("GET")
+                .path(path)
+                .header("authorization", format!("Bearer {token}"))
+        };
+        let pids = |procs: &[ProcInfo]| {
+            let mut pids = procs.iter().map(|proc| proc.pid).collect::<Vec<_>>();
+            pids.sort();
+            pids
+        };
+
+        for (token, counts) in [("one", [2, 2]), ("admin", [3, 3])] {
+            let res = get(token, "/snapshots").reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let snaps = serde_json::from_slice::<Vec<SnapshotInfo>>(res.body()).unwrap();
+            assert_eq!(
+                snaps.iter().map(|snap| snap.count).collect::<Vec<_>>(),
+                counts,
+                "{token}"
+            );
+        }
+
+        let res = get("one", "/diff?from=1").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let diff = serde_json::from_slice::<Diff>(res.body()).unwrap();
+        assert_eq!(pids(&diff.added), [5]);
+        assert_eq!(pids(&diff.removed), [3]);
+        let res = request()
+            .method("POST")
+            .path("/diff")
+            .header("authorization", "Bearer one")
+            .json(&[proc_info(2), proc_info(3)])
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let diff = serde_json::from_slice::<Diff>(res.body()).unwrap();
+        assert_eq!(pids(&diff.added), [1, 5]);
+        assert_eq!(pids(&diff.removed), [3]);
+
+        let res = get("one", "/snapshots/export").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let export = res.body().clone();
+        let snaps = store::decode(&export[..]).unwrap();
+        assert_eq!(
+            snaps
+                .iter()
+                .map(|snap| pids(&snap.procs.iter().cloned().collect::<Vec<_>>()))
+                .collect::<Vec<_>>(),
+            [vec![1, 3], vec![1, 5]]
+        );
+
+        let res = get("one", "/metrics").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(res.body()), "admin_required");
+        let res = get("admin", "/metrics").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let import = |token: &str| {
+            request()
+                .method("POST")
+                .path("/snapshots/import")
+                .header("authorization", format!("Bearer {token}"))
+                .body(export.clone())
+        };
+        let res = import("one").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(res.body()), "admin_required");
+        assert_eq!(cache.read().await.current().id, 2);
+        let res = import("admin").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    /// Parse rates: counts per period, with or without its number.
+    #[test]
+    fn test_rate_parse() {
+        assert_eq!(
+            "10/min".parse::<Rate>().unwrap(),
+            "10/1m".parse::<Rate>().unwrap()
+        );
+        assert_eq!(
+            "2/30s".parse::<Rate>().unwrap(),
+            "2/30000ms".parse::<Rate>().unwrap()
+        );
+        for rate in ["10", "0/min", "10/0s", "x/min", "10/fortnight"] {
+            assert!(rate.parse::<Rate>().is_err(), "{rate}");
+        }
+    }
+
+    /// Limit refreshes and searches to 2 per minute, then exceed them with a
+    /// client but not another one: TOO_MANY_REQUESTS with a JSON body and a
+    /// Retry-After of 30 seconds for the first client only.
+    #[tokio::test]
+    async fn test_rate_limits() {
+        let (cache, _source) = scripted_cache();
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[
+                    {"token": "one", "scopes": ["read", "refresh"]},
+                    {"token": "two", "scopes": ["read", "refresh"]}
+                ]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        config.set_limits(Limits::new(
+            Some("2/min".parse().unwrap()),
+            Some("2/min".parse().unwrap()),
+            None,
+        ));
+        let filter = routes::all(&cache, &Arc::new(config));
+        let refresh = |token: &str| {
+            request()
+                .method("POST")
+                .path("/acquire_process_list")
+                .header("authorization", format!("Bearer {token}"))
+        };
+        let search = |token: &str| {
+            request()
+                .method("GET")
+                .path("/search?uid=0")
+                .header("authorization", format!("Bearer {token}"))
+        };
+
+        for _ in 0..2 {
+            assert_eq!(refresh("one").reply(&filter).await.status(), StatusCode::OK);
+        }
+        let res = refresh("one").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "30");
+        assert_eq!(
+            serde_json::from_slice::<serde_json::Value>(res.body()).unwrap()["code"],
+            "too_many_requests"
+        );
+        assert_eq!(refresh("two").reply(&filter).await.status(), StatusCode::OK);
+
+        for _ in 0..2 {
+            assert_eq!(search("one").reply(&filter).await.status(), StatusCode::OK);
+        }
+        assert_eq!(
+            search("one").reply(&filter).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            request()
+                .method("GET")
+                .path("/processes")
+                .header("authorization", "Bearer one")
+                .reply(&filter)
+                .await
+                .status(),
+            StatusCode::OK
+        );
+
+        let metrics = request()
+            .method("GET")
+            .path("/metrics")
+            .header("authorization", "Bearer one")
+            .reply(&filter)
+            .await;
+        assert!(str::from_utf8(metrics.body())
+            .unwrap()
+            .contains("proc_api_limited_requests_total 2\n"));
+    }
+
+    /// Limit streams to one per client, then open one and another one while it
+    /// is still open, then another one after it ended: TOO_MANY_REQUESTS only
+    /// for the second one.
+    #[tokio::test]
+    async fn test_stream_limits() {
+        let (cache, _source) = scripted_cache();
+        let mut config = Config::default();
+        config.set_limits(Limits::new(None, None, Some(1)));
+        let filter = routes::all(&cache, &Arc::new(config));
+
+        let stream = {
+            let filter = filter.clone();
+
+            tokio::spawn(async move { request().method("GET").path("/data").reply(&filter).await })
+        };
+        time::sleep(Duration::from_millis(100)).await;
+
+        let res = request().method("GET").path("/data").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "5");
+
+        assert_eq!(stream.await.unwrap().status(), StatusCode::OK);
+        let res = request().method("GET").path("/data").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    /// Request an unknown path, a known one with the wrong method, an invalid
+    /// query and a refresh failing: NOT FOUND, METHOD NOT ALLOWED, BAD REQUEST
+    /// and INTERNAL SERVER ERROR JSON errors, the latter carrying its cause.
+    #[tokio::test]
+    async fn test_errors() {
+        let (cache, source) = scripted_cache();
+        let filter = routes::all(&cache, &SharedConfig::default());
+
+        let res = request()
+            .method("GET")
+            .path("/unknown")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert_eq!(error_code(res.body()), "not_found");
+        assert_eq!(res.headers()["content-type"], "application/json");
+
+        let res = request()
+            .method("GET")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(error_code(res.body()), "method_not_allowed");
+        let res = request()
+            .method("DELETE")
+            .path("/processes")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+
+        let res = request()
+            .method("GET")
+            .path("/search?pid=abc")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res.body()), "invalid_query");
+
+        let res = request()
+            .method("GET")
+            .path("/processes?format=xml")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        let error = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        assert_eq!(error["details"]["supported"][0], "json");
+
+        source.fail_next("Scripted failure.");
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let error = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        assert_eq!(error["code"], "refresh_failed");
+        assert!(error["message"]
+            .as_str()
+            .unwrap()
+            .contains("Scripted failure."));
+    }
+
+    /// Fetch the OpenAPI document, compare its paths to those of the routes,
+    /// then request each path with each method and below it: OK response,
+    /// each route documented, only the documented methods reaching a route and
+    /// undocumented paths not found.
+    #[tokio::test]
+    async fn test_openapi() {
+        let (cache, _source) = scripted_cache();
+        CacheInner::refresh(&cache).await.unwrap();
+        let filter = routes::all(&cache, &SharedConfig::default());
+
+        let res = request()
+            .method("GET")
+            .path("/openapi.json")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let spec = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        assert_eq!(spec["openapi"], "3.0.3");
+        for schema in ["ProcInfo", "SnapshotInfo", "Diff", "Error", "Timestamp"] {
+            assert!(
+                spec["components"]["schemas"][schema].is_object(),
+                "{schema}"
+            );
+        }
+        let search = &spec["paths"]["/search"]["get"]["parameters"];
+        for param in ["pid", "uid", "name", "username", "at", "format"] {
+            assert!(
+                search
+                    .as_array()
+                    .unwrap()
+                    .iter()
+                    .any(|search| search["name"] == param),
+                "{param}"
+            );
+        }
+
+        // Every route built in `routes` is documented, whatever its parameters
+        // are named.
+        let documented = spec["paths"].as_object().unwrap();
+        let pattern = |path: &str| {
+            path.split('/')
+                .map(|seg| if seg.starts_with('{') { "{}" } else { seg })
+                .collect::<Vec<_>>()
+                .join("/")
+        };
+        let patterns = documented
+            .keys()
+            .map(|path| pattern(path))
+            .collect::<std::collections::BTreeSet<_>>();
+        let source = include_str!("routes.rs");
+        let mut routed = vec!["/".to_owned()];
+        for (_, rest) in source
+            .match_indices("warp::path!(")
+            .map(|(i, _)| source.split_at(i + 12))
+        {
+            let segments = &rest[..rest.find(')').unwrap()];
+            routed.push(
+                segments
+                    .split('/')
+                    .map(|seg| match seg.trim().strip_prefix('"') {
+                        Some(seg) => format!("/{}", seg.trim_end_matches('"')),
+                        None => "/{}".to_owned(),
+                    })
+                    .collect(),
+            );
+        }
+        for path in routed {
+            assert!(patterns.contains(&path), "{path}");
+        }
+
+        // Each documented endpoint reaches a route, while other methods do not
+        // and other paths are not found.
+        for (path, item) in documented {
+            let path = path.replace("{id}", "1").replace("{pid}", "0");
+            for method in ["GET", "POST", "PUT", "DELETE"] {
+                let res = request().method(method).path(&path).reply(&filter).await;
+
+                if item.get(method.to_lowercase()).is_some() {
+                    assert_ne!(
+                        res.status(),
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "{method} {path}"
+                    );
+                    assert_ne!(res.status(), StatusCode::NOT_FOUND, "{method} {path}");
+                } else {
+                    assert_eq!(
+                        res.status(),
+                        StatusCode::METHOD_NOT_ALLOWED,
+                        "{method} {path}"
+                    );
+                }
+
+                let res = request()
+                    .method(method)
+                    .path(&format!("{}/undocumented", path.trim_end_matches('/')))
+                    .reply(&filter)
+                    .await;
+                assert_eq!(res.status(), StatusCode::NOT_FOUND, "{method} {path}");
+                assert_eq!(error_code(res.body()), "not_found", "{method} {path}");
+            }
+        }
+    }
+
+    /// Fetch the dashboard without a token: OK HTML page using the endpoints
+    /// of the API, without loading anything from elsewhere.
+    #[tokio::test]
+    async fn test_dashboard() {
+        let (cache, _source) = scripted_cache();
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(r#"[{"token": "t", "scopes": ["read"]}]"#)
+                .unwrap()
+                .into_iter()
+                .collect(),
+        );
+        let filter = routes::all(&cache, &Arc::new(config));
+
+        let res = request().method("GET").path("/").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(res.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html"));
+        let page = str::from_utf8(res.body()).unwrap();
+        for endpoint in [
+            "\"processes\"",
+            "`search?",
+            "\"data\"",
+            "\"acquire_process_list\"",
+        ] {
+            assert!(page.contains(endpoint), "{endpoint}");
+        }
+        assert!(!page.contains("://"));
+
+        let res = request()
+            .method("GET")
+            .path("/index.html")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    /// Query processes through GraphQL: filtered, sorted and paginated ones,
+    /// then one with its parent, children and user resolved, each in an OK
+    /// response, while too complex or aliased queries get errors and an
+    /// invalid body is a BAD REQUEST JSON error.
+    #[tokio::test]
+    async fn test_graphql() {
+        let (cache, source) = scripted_cache();
+        for pid in 1..=7 {
+            source.spawn(proc_info(pid));
+        }
+        CacheInner::refresh(&cache).await.unwrap();
+        let filter = routes::all(&cache, &SharedConfig::default());
+        let graphql = |query: &str| {
+            request()
+                .method("POST")
+                .path("/graphql")
+                .json(&serde_json::json!({ "query": query }))
+        };
+        let pids = |nodes: &serde_json::Value| {
+            nodes
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|node| node["pid"].as_u64().unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let mut after = String::new();
+        for (page, next) in [([7, 5], true), ([3, 1], false)] {
+            let res = graphql(&format!(
+                r#"{{ processes(filter: {{ uid: 1 }}, sort: {{ field: PID, descending: true }},
+                      first: 2{after}) {{
+                    nodes {{ pid }}
+                    pageInfo {{ hasNextPage endCursor }}
+                }} }}"#
+            ))
+            .reply(&filter)
+            .await;
+            assert_eq!(res.status(), StatusCode::OK);
+            let body = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+            let procs = &body["data"]["processes"];
+            assert_eq!(pids(&procs["nodes"]), page, "{body}");
+            assert_eq!(procs["pageInfo"]["hasNextPage"], next);
+            after = format!(
+                r#", after: "{}""#,
+                procs["pageInfo"]["endCursor"].as_str().unwrap()
+            );
+        }
+
+        let res = graphql(
+            r#"{ process(pid: 2) {
+                name
+                parent { pid parent { pid } }
+                children { pid }
+                user { username processes { pid } }
+            } }"#,
+        )
+        .reply(&filter)
+        .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        let proc = &body["data"]["process"];
+        assert_eq!(proc["name"], "proc2");
+        assert_eq!(proc["parent"]["pid"], 1);
+        assert!(proc["parent"]["parent"].is_null());
+        assert_eq!(pids(&proc["children"]), [4, 5]);
+        assert_eq!(proc["user"]["username"], "user0");
+        assert_eq!(pids(&proc["user"]["processes"]), [2, 4, 6]);
+
+        let res = graphql("{ process(pid: 8) { pid } }").reply(&filter).await;
+        let body = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        assert!(body["data"]["process"].is_null());
+        let res = graphql("{ nope }").reply(&filter).await;
+        let body = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        assert!(body["errors"][0]["message"].is_string());
+
+        // Nested lists and aliases multiply the processes resolved.
+        let res = graphql("{ processes { nodes { children { children { children { pid } } } } } }")
+            .reply(&filter)
+            .await;
+        let body = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        assert!(body["data"].is_null(), "{body}");
+        assert!(body["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("complex"));
+        let aliased = (0..17)
+            .map(|i| format!("p{i}: process(pid: 1) {{ pid }}"))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let res = graphql(&format!("{{ {aliased} }}")).reply(&filter).await;
+        let body = serde_json::from_slice::<serde_json::Value>(res.body()).unwrap();
+        assert!(body["data"].is_null(), "{body}");
+        assert!(body["errors"][0]["message"]
+            .as_str()
+            .unwrap()
+            .contains("aliases"));
+
+        let res = request()
+            .method("POST")
+            .path("/graphql")
+            .body("query")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res.body()), "invalid_body");
+    }
+
+    /// Subscribe to the processes spawned and exited over a GraphQL WebSocket
+    /// while owning processes of user 1, then refresh: only the processes of
+    /// the user are received, as spawned then exited.
+    #[tokio::test]
+    async fn test_graphql_subscription() {
+        let (cache, source) = scripted_cache();
+        source.spawn(proc_info(2)).spawn(proc_info(3));
+        CacheInner::refresh(&cache).await.unwrap();
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[{"token": "one", "uid": 1, "scopes": ["stream"]}]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        config.restrict_visibility(Subjects::default());
+        let filter = routes::all(&cache, &Arc::new(config));
+
+        let mut ws = warp::test::ws()
+            .path("/graphql")
+            .header("authorization", "Bearer one")
+            .header("sec-websocket-protocol", "graphql-transport-ws")
+            .handshake(filter)
+            .await
+            .unwrap();
+        ws.send_text(r#"{"type": "connection_init"}"#).await;
+        let ack = ws.recv().await.unwrap();
+        assert!(ack.to_str().unwrap().contains("connection_ack"));
+        ws.send_text(
+            r#"{"id": "1", "type": "subscribe", "payload": {
+                "query": "subscription { processEvents { kind process { pid } } }"
+            }}"#,
+        )
+        .await;
+        time::sleep(Duration::from_millis(100)).await;
+
+        source.exit(3).spawn(proc_info(4)).spawn(proc_info(5));
+        CacheInner::refresh(&cache).await.unwrap();
+        let mut events = Vec::new();
+        for _ in 0..2 {
+            let msg = ws.recv().await.unwrap();
+            let msg = serde_json::from_str::<serde_json::Value>(msg.to_str().unwrap()).unwrap();
+            assert_eq!(msg["type"], "next", "{msg}");
+            let event = &msg["payload"]["data"]["processEvents"];
+            events.push((
+                event["kind"].as_str().unwrap().to_owned(),
+                event["process"]["pid"].as_u64().unwrap(),
+            ));
+        }
+        events.sort();
+        assert_eq!(
+            events,
+            [("EXITED".to_owned(), 3), ("SPAWNED".to_owned(), 5)]
+        );
+    }
+
+    /// Send signals to processes while disabled, without the scope, to a
+    /// process of another user, to an unknown one, then as allowed: FORBIDDEN,
+    /// FORBIDDEN, NOT FOUND and NOT FOUND JSON errors, then OK with the signal
+    /// sent and the process exited at the next refresh.
+    #[tokio::test]
+    async fn test_signal_procs() {
+        let (cache, source) = scripted_cache();
+        source.spawn(proc_info(1)).spawn(proc_info(2));
+        CacheInner::refresh(&cache).await.unwrap();
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[
+                    {"token": "one", "uid": 1, "scopes": ["signal"]},
+                    {"token": "reader", "uid": 1, "scopes": ["read"]}
+                ]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        config.restrict_visibility(Subjects::default());
+        config.allow_signals();
+        let filter = routes::all(&cache, &Arc::new(config));
+        let signal = |token: &str, path: &str| {
+            request()
+                .method("POST")
+                .path(path)
+                .header("authorization", format!("Bearer {token}"))
+        };
+
+        let res = signal("one", "/processes/1/signal?signal=TERM")
+            .reply(&routes::all(&cache, &SharedConfig::default()))
+            .await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert_eq!(error_code(res.body()), "signals_disabled");
+
+        for (token, path, status, code) in [
+            (
+                "reader",
+                "/processes/1/signal?signal=TERM",
+                StatusCode::FORBIDDEN,
+                "forbidden",
+            ),
+            (
+                "one",
+                "/processes/1/signal?signal=NOPE",
+                StatusCode::BAD_REQUEST,
+                "invalid_query",
+            ),
+            (
+                "one",
+                "/processes/2/signal?signal=TERM",
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+            (
+                "one",
+                "/processes/3/signal?signal=TERM",
+                StatusCode::NOT_FOUND,
+                "not_found",
+            ),
+        ] {
+            let res = signal(token, path).reply(&filter).await;
+            assert_eq!(res.status(), status, "{path}");
+            assert_eq!(error_code(res.body()), code, "{path}");
+        }
+        assert!(source.signals().is_empty());
+
+        for signal_name in ["STOP", "TERM"] {
+            let res = signal("one", &format!("/processes/1/signal?signal={signal_name}"))
+                .reply(&filter)
+                .await;
+            assert_eq!(res.status(), StatusCode::OK);
+        }
+        assert_eq!(
+            source.signals(),
+            [(1, model::Signal::Stop), (1, model::Signal::Term)]
+        );
+        // Still cached until the next refresh, but no longer running.
+        let res = signal("one", "/processes/1/signal?signal=TERM")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        CacheInner::refresh(&cache).await.unwrap();
+        assert_eq!(
+            cache.read().await.get(),
+            &[proc_info(2)].into_iter().collect()
+        );
+    }
+
+    /// Refresh, list, search and watch processes over gRPC, sharing the cache
+    /// with the HTTP routes, then without the token required by another
+    /// server: processes as with HTTP, events of the refreshes of either, then
+    /// denied calls.
+    #[cfg(feature = "grpc")]
+    #[tokio::test]
+    async fn test_grpc() {
+        use grpc::proto::proc_api_client::ProcApiClient;
+        use grpc::proto::process_event::Kind;
+        use grpc::proto::{
+            ListProcessesRequest, ProcessEvent, ProcessList, RefreshRequest, SearchRequest,
+            WatchRequest,
+        };
+        use tonic::Code;
+
+        let (cache, source) = scripted_cache();
+        for pid in 1..=4 {
+            source.spawn(proc_info(pid));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(grpc::serve(
+            listener,
+            Arc::clone(&cache),
+            SharedConfig::default(),
+        ));
+        let mut client = ProcApiClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        let filter = routes::all(&cache, &SharedConfig::default());
+        let pids = |list: ProcessList| {
+            list.processes
+                .into_iter()
+                .map(|proc| proc.pid)
+                .collect::<Vec<_>>()
+        };
+
+        client.refresh(RefreshRequest {}).await.unwrap();
+        let res = request()
+            .method("GET")
+            .path("/processes")
+            .reply(&filter)
+            .await;
+        assert_eq!(
+            serde_json::from_slice::<model::CacheData>(res.body()).unwrap(),
+            (1..=4).map(proc_info).collect()
+        );
+        let list = client
+            .list_processes(ListProcessesRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(pids(list.clone()), [1, 2, 3, 4]);
+        assert_eq!(list.processes[1].ppid, Some(1));
+        assert_eq!(list.processes[1].username, "user0");
+        let found = client
+            .search(SearchRequest {
+                uid: Some(0),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+        assert_eq!(pids(found.into_inner()), [2, 4]);
+        let err = client.search(SearchRequest::default()).await.unwrap_err();
+        assert_eq!(err.code(), Code::InvalidArgument);
+
+        let mut events = client.watch(WatchRequest {}).await.unwrap().into_inner();
+        // The changes of a refresh are not ordered.
+        async fn next(
+            events: &mut tonic::Streaming<ProcessEvent>,
+            count: usize,
+        ) -> Vec<(Kind, u32)> {
+            let mut received = Vec::new();
+            for _ in 0..count {
+                let event = events.message().await.unwrap().unwrap();
+                received.push((event.kind(), event.process.unwrap().pid));
+            }
+            received.sort();
+            received
+        }
+        assert_eq!(
+            next(&mut events, 4).await,
+            (1..=4).map(|pid| (Kind::Spawned, pid)).collect::<Vec<_>>()
+        );
+        source.exit(4).spawn(proc_info(5));
+        let res = request()
+            .method("POST")
+            .path("/acquire_process_list")
+            .reply(&filter)
+            .await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let changes = next(&mut events, 2).await;
+        assert_eq!(changes, [(Kind::Spawned, 5), (Kind::Exited, 4)]);
+
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[{"token": "reader", "scopes": ["read"]}]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(grpc::serve(listener, Arc::clone(&cache), Arc::new(config)));
+        let mut client = ProcApiClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap();
+        let err = client
+            .list_processes(ListProcessesRequest {})
+            .await
+            .unwrap_err();
+        assert_eq!(err.code(), Code::Unauthenticated);
+        let mut request = tonic::Request::new(RefreshRequest {});
+        request
+            .metadata_mut()
+            .insert("authorization", "Bearer reader".parse().unwrap());
+        let err = client.refresh(request).await.unwrap_err();
+        assert_eq!(err.code(), Code::PermissionDenied);
+    }
+
+    /// Serves all the routes of the given cache, configured as given, on a
+    /// local TCP port, returning the base URL of the server.
+    #[cfg(feature = "client")]
+    async fn http_serve(cache: &ProcCache, config: Config) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let filter = routes::all(cache, &Arc::new(config));
+
+        tokio::spawn(server::serve_tcp(listener, None, filter));
+        format!("http://{addr}/")
+    }
+
+    /// Spawn processes, then use the client to refresh, list and search them,
+    /// with and without the required token: typed processes, then typed API
+    /// errors.
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_client() {
+        let (cache, source) = scripted_cache();
+        for pid in 1..=4 {
+            source.spawn(proc_info(pid));
+        }
+        let client = client::Client::new(http_serve(&cache, Config::default()).await);
+
+        assert!(client.processes().await.unwrap().is_empty());
+        client.refresh().await.unwrap();
+        assert_eq!(
+            client.processes().await.unwrap(),
+            (1..=4).map(proc_info).collect()
+        );
+        let query = model::SearchQuery {
+            uid: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            client.search(&query).await.unwrap(),
+            [proc_info(2), proc_info(4)].into_iter().collect()
+        );
+        match client.search(&model::SearchQuery::default()).await {
+            Err(client::ClientError::Api { status, body }) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert_eq!(body.code, "missing_filter");
+            }
+            res => panic!("Unexpected result: {res:?}"),
+        }
+
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[{"token": "reader", "scopes": ["read"]}]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        let client = client::Client::new(http_serve(&cache, config).await);
+        match client.processes().await {
+            Err(client::ClientError::Api { status, body }) => {
+                assert_eq!(status, StatusCode::UNAUTHORIZED);
+                assert_eq!(body.code, "unauthorized");
+            }
+            res => panic!("Unexpected result: {res:?}"),
+        }
+        let client = client.with_token("reader");
+        assert_eq!(client.processes().await.unwrap().len(), 4);
+        match client.refresh().await {
+            Err(client::ClientError::Api { status, body }) => {
+                assert_eq!(status, StatusCode::FORBIDDEN);
+                assert_eq!(body.code, "forbidden");
+            }
+            res => panic!("Unexpected result: {res:?}"),
+        }
+    }
+
+    /// Subscribe with the client, refresh new processes, refresh exited ones,
+    /// then let the server end the stream: connected, cached, refreshed and
+    /// removed processes, then reconnected with the remaining ones again.
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_client_subscribe() {
+        use futures_util::StreamExt;
+
+        let (cache, source) = scripted_cache();
+        source.spawn(proc_info(1));
+        CacheInner::refresh(&cache).await.unwrap();
+        let client = client::Client::new(http_serve(&cache, Config::default()).await);
+        let mut events = Box::pin(client.subscribe().map(Result::unwrap));
+
+        assert_eq!(events.next().await, Some(client::Event::Connected));
+        assert_eq!(
+            events.next().await,
+            Some(client::Event::Process(proc_info(1)))
+        );
+        source.spawn(proc_info(2)).spawn(proc_info(3));
+        client.refresh().await.unwrap();
+        let mut procs = vec![events.next().await.unwrap(), events.next().await.unwrap()];
+        procs.sort_unstable_by_key(|event| match event {
+            client::Event::Process(proc) | client::Event::Removed(proc) => proc.pid,
+            client::Event::Connected => 0,
+        });
+        assert_eq!(
+            procs,
+            [2, 3].map(|pid| client::Event::Process(proc_info(pid)))
+        );
+        source.exit(1);
+        client.refresh().await.unwrap();
+        assert_eq!(
+            events.next().await,
+            Some(client::Event::Removed(proc_info(1)))
+        );
+
+        // The stream ends after `SSE_TOUT` without refreshes.
+        assert_eq!(events.next().await, Some(client::Event::Connected));
+        let mut procs = vec![events.next().await.unwrap(), events.next().await.unwrap()];
+        procs.sort_unstable_by_key(|event| match event {
+            client::Event::Process(proc) | client::Event::Removed(proc) => proc.pid,
+            client::Event::Connected => 0,
+        });
+        assert_eq!(
+            procs,
+            [2, 3].map(|pid| client::Event::Process(proc_info(pid)))
+        );
+    }
+
+    /// Aggregates two local servers and an unreachable one, requiring tokens
+    /// and limiting listings, searches and streams: processes tagged with their host and
+    /// the unreachable one reported, in searches and in the multiplexed
+    /// stream, UNAUTHORIZED without a token and TOO_MANY_REQUESTS beyond the
+    /// limits.
+    #[cfg(feature = "client")]
+    #[tokio::test]
+    async fn test_federation() {
+        use federation::Upstream;
+        use futures_util::StreamExt;
+        use model::{AggregatedProcs, HostProcInfo, UpstreamError};
+
+        let mut upstreams = Vec::new();
+        let mut sources = Vec::new();
+        for host in ["a", "b"] {
+            let (cache, source) = scripted_cache();
+            source.spawn(proc_info(1)).spawn(proc_info(2));
+            CacheInner::refresh(&cache).await.unwrap();
+            upstreams.push(Upstream::new(
+                host,
+                client::Client::new(http_serve(&cache, Config::default()).await),
+            ));
+            sources.push((cache, source));
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = format!("http://{}/", listener.local_addr().unwrap());
+        drop(listener);
+        upstreams.push(Upstream::new("dead", client::Client::new(dead)));
+        let mut config = Config::default();
+        config.set_tokens(
+            serde_json::from_str::<Vec<auth::Grant>>(
+                r#"[{"token": "t", "scopes": ["read", "stream"]}]"#,
+            )
+            .unwrap()
+            .into_iter()
+            .collect(),
+        );
+        config.set_limits(Limits::new(None, Some("3/min".parse().unwrap()), Some(1)));
+        let filter = federation::routes(upstreams, &Arc::new(config));
+        let get = |path: &str| {
+            request()
+                .method("GET")
+                .path(path)
+                .header("authorization", "Bearer t")
+        };
+        let tagged = |host: &str, pid| HostProcInfo {
+            host: host.to_owned(),
+            proc: proc_info(pid),
+        };
+
+        for path in ["/processes", "/search?uid=0", "/data"] {
+            let res = request().method("GET").path(path).reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{path}");
+        }
+
+        let res = get("/search?uid=0").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let procs = serde_json::from_slice::<AggregatedProcs>(res.body()).unwrap();
+        assert_eq!(procs.processes, [tagged("a", 2), tagged("b", 2)]);
+        assert_eq!(
+            procs.errors.keys().map(String::as_str).collect::<Vec<_>>(),
+            ["dead"]
+        );
+        assert_eq!(procs.errors["dead"].code, "unreachable");
+
+        let res = get("/processes").reply(&filter).await;
+        let procs = serde_json::from_slice::<AggregatedProcs>(res.body()).unwrap();
+        assert_eq!(procs.processes.len(), 4);
+
+        let res = get("/search").reply(&filter).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(error_code(res.body()), "missing_filter");
+        for path in ["/processes", "/search?uid=0"] {
+            let res = get(path).reply(&filter).await;
+            assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS, "{path}");
+        }
+
+        // The stream never ends, as upstream streams are reconnected: read
+        // it from a server until the expected events are received.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/data", listener.local_addr().unwrap());
+        tokio::spawn(server::serve_tcp(listener, None, filter));
+        let stream = || reqwest::Client::new().get(&url).bearer_auth("t").send();
+        let res = stream().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let mut chunks = res.bytes_stream();
+        let mut body = String::new();
+        let mut procs = Vec::new();
+        let mut errors = Vec::new();
+        let mut refreshed = false;
+
+        time::timeout(Duration::from_secs(10), async {
+            while procs.len() < 5 || errors.is_empty() {
+                body.push_str(str::from_utf8(&chunks.next().await.unwrap().unwrap()).unwrap());
+                while let Some(end) = body.find("\n\n") {
+                    let event = body.drain(..end + 2).collect::<String>();
+                    let Some(data) = event.lines().find_map(|line| line.strip_prefix("data:"))
+                    else {
+                        continue;
+                    };
+                    match event.lines().find_map(|line| line.strip_prefix("event:")) {
+                        None => procs.push(serde_json::from_str::<HostProcInfo>(data).unwrap()),
+                        Some("upstream_error") => {
+                            errors.push(serde_json::from_str::<UpstreamError>(data).unwrap())
+                        }
+                        Some(_) => {}
+                    }
+                    if procs.len() == 4 && !refreshed {
+                        refreshed = true;
+                        let (cache, source) = &sources[1];
+                        source.spawn(proc_info(3));
+                        CacheInner::refresh(cache).await.unwrap();
+                    }
+                }
+            }
+        })
+        .await
+        .unwrap();
+        procs.sort_unstable_by(|a, b| (&a.host, a.proc.pid).cmp(&(&b.host, b.proc.pid)));
+        assert_eq!(
+            procs,
+            [
+                tagged("a", 1),
+                tagged("a", 2),
+                tagged("b", 1),
+                tagged("b", 2),
+                tagged("b", 3)
+            ]
+        );
+        assert_eq!(errors[0].host, "dead");
+        assert_eq!(errors[0].error.code, "unreachable");
+        let res = stream().await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        drop(chunks);
+        time::sleep(Duration::from_millis(100)).await;
+        let res = stream().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
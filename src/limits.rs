@@ -0,0 +1,380 @@
+//! This module defines the limits on the requests of each client: the rates at
+//! which it can refresh the cache and search processes, and the number of
+//! streams it can be subscribed to at once.
+//!
+//! Clients are told apart by their bearer token if authentication is enabled,
+//! otherwise by the subject of their client certificate, their credentials on
+//! the Unix socket or their IP address, see [`Client`].
+
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, Context};
+use futures_util::future;
+use warp::http::header::RETRY_AFTER;
+use warp::http::StatusCode;
+use warp::reject;
+use warp::{Filter, Rejection};
+
+use crate::auth;
+use crate::config::{Config, SharedConfig};
+use crate::error::ApiError;
+use crate::server::{self, Peer};
+
+/// Number of clients above which those that have not been limited for a
+/// whole period are forgotten.
+const PRUNE_THRESHOLD: usize = 1024;
+/// Delay after which clients exceeding their concurrent streams are told to
+/// retry, as when one of their streams ends cannot be known.
+const STREAM_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// A maximum number of requests per period of time, e.g. `10/min`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate {
+    count: u32,
+    period: Duration,
+}
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (count, period) = s
+            .split_once('/')
+            .ok_or_else(|| anyhow!("Expected <count>/<period>, e.g. 10/min."))?;
+        let count = count.parse().context("Invalid count.")?;
+        // Accept `min` as well as `1min`.
+        let period = humantime::parse_duration(period)
+            .or_else(|_| humantime::parse_duration(&format!("1{period}")))
+            .context("Invalid period.")?;
+
+        if count == 0 || period.is_zero() {
+            return Err(anyhow!("The count and period must not be zero."));
+        }
+        Ok(Self { count, period })
+    }
+}
+
+/// How a client is told apart from the others.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Client {
+    /// The name of its token, or the token itself if unnamed.
+    Token(String),
+    /// The subject of its client certificate.
+    Subject(String),
+    /// Its user on the Unix socket.
+    Uid(u32),
+    /// Its IP address.
+    Ip(IpAddr),
+    /// Nothing: all such clients share their limits.
+    Unknown,
+}
+
+impl Client {
+    /// Identifies the client of a request from its `Authorization` header and
+    /// its connection.
+    fn identify(config: &Config, header: Option<&str>, peer: Peer) -> Self {
+        let grant = config
+            .tokens()
+            .and_then(|tokens| tokens.get(auth::bearer(header)?));
+
+        if let Some(grant) = grant {
+            Self::Token(
+                grant
+                    .name
+                    .clone()
+                    .unwrap_or_else(|| grant.token().to_owned()),
+            )
+        } else if let Some(subject) = peer.subject {
+            Self::Subject(subject)
+        } else if let Some(cred) = peer.cred {
+            Self::Uid(cred.uid())
+        } else if let Some(addr) = peer.addr {
+            Self::Ip(addr.ip())
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            // Do not leak unnamed tokens in logs.
+            Self::Token(_) => f.write_str("token"),
+            Self::Subject(subject) => write!(f, "{subject}"),
+            Self::Uid(uid) => write!(f, "uid {uid}"),
+            Self::Ip(ip) => write!(f, "{ip}"),
+            Self::Unknown => f.write_str("unknown client"),
+        }
+    }
+}
+
+/// An endpoint whose requests are limited in rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Endpoint {
+    Refresh,
+    Search,
+}
+
+/// The limits of the server, all disabled by default.
+#[derive(Debug, Default)]
+pub struct Limits {
+    refresh: Option<RateLimiter>,
+    search: Option<RateLimiter>,
+    streams: Option<StreamLimiter>,
+    limited: AtomicU64,
+}
+
+impl Limits {
+    /// Builds the limits of the given rates of refreshes and searches, and of
+    /// the given number of concurrent streams, per client.
+    pub fn new(refresh: Option<Rate>, search: Option<Rate>, streams: Option<usize>) -> Self {
+        Self {
+            refresh: refresh.map(RateLimiter::new),
+            search: search.map(RateLimiter::new),
+            streams: streams.map(|max| StreamLimiter {
+                max,
+                counts: Arc::default(),
+            }),
+            limited: AtomicU64::default(),
+        }
+    }
+
+    /// Returns the number of requests rejected for exceeding the limits of
+    /// their client so far.
+    pub fn limited(&self) -> u64 {
+        self.limited.load(Ordering::Relaxed)
+    }
+
+    /// Records a request rejected for exceeding the limits of its client.
+    fn record_limited(&self) {
+        self.limited.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A limiter of the rate of requests of each client, as token buckets holding
+/// at most the count of the rate and refilled over its period.
+#[derive(Debug)]
+struct RateLimiter {
+    rate: Rate,
+    buckets: Mutex<HashMap<Client, Bucket>>,
+}
+
+/// The requests a client can still make, as of some instant.
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: Rate) -> Self {
+        Self {
+            rate,
+            buckets: Mutex::default(),
+        }
+    }
+
+    /// Returns the tokens of the given bucket refilled up to the given instant.
+    fn refilled(&self, bucket: Bucket, now: Instant) -> f64 {
+        let refill = now.duration_since(bucket.updated).as_secs_f64()
+            / self.rate.period.as_secs_f64()
+            * f64::from(self.rate.count);
+        (bucket.tokens + refill).min(f64::from(self.rate.count))
+    }
+
+    /// Takes a token from the bucket of the given client, or returns how long
+    /// to wait until one is available.
+    fn acquire(&self, client: Client) -> Result<(), Duration> {
+        let now = Instant::now();
+        let full = f64::from(self.rate.count);
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.refilled(*bucket, now) < full);
+        }
+        let bucket = buckets.entry(client).or_insert(Bucket {
+            tokens: full,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(*bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(self.rate.period.mul_f64((1.0 - bucket.tokens) / full))
+        }
+    }
+}
+
+/// The number of concurrent streams of each client that has some.
+type StreamCounts = Arc<Mutex<HashMap<Client, usize>>>;
+
+/// A limiter of the number of concurrent streams of each client.
+#[derive(Debug)]
+struct StreamLimiter {
+    max: usize,
+    counts: StreamCounts,
+}
+
+impl StreamLimiter {
+    /// Counts a new stream of the given client, unless it has too many.
+    fn acquire(&self, client: Client) -> Option<StreamPermit> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(client.clone()).or_default();
+
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(StreamPermit(Some((client, Arc::clone(&self.counts)))))
+    }
+}
+
+/// A stream counted in the limits of its client until it is dropped.
+#[derive(Debug, Default)]
+pub struct StreamPermit(Option<(Client, StreamCounts)>);
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        if let Some((client, counts)) = self.0.take() {
+            let mut counts = counts.lock().unwrap();
+
+            if let Some(count) = counts.get_mut(&client) {
+                *count -= 1;
+                if *count == 0 {
+                    counts.remove(&client);
+                }
+            }
+        }
+    }
+}
+
+/// Why a request was rejected by the limits: it can be retried after the
+/// given delay.
+#[derive(Debug)]
+pub struct Limited {
+    retry_after: Duration,
+}
+
+impl Limited {
+    /// Returns the number of seconds after which the request can be retried,
+    /// rounded up so that retrying then succeeds.
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs() + u64::from(self.retry_after.subsec_nanos() > 0)
+    }
+}
+
+/// Extracts the `Authorization` header and the [`Peer`] of the request, which
+/// identify its [`Client`].
+fn client_info(
+) -> impl Filter<Extract = (Option<String>, Peer), Error = std::convert::Infallible> + Clone {
+    warp::header::optional::<String>("authorization")
+        .or(warp::any().map(|| None))
+        .unify()
+        .and(server::peer())
+}
+
+/// Counts a request of the client identified by the given `Authorization`
+/// header and peer to the given endpoint in its rate limit, if enabled.
+pub fn acquire_rate(
+    config: &Config,
+    endpoint: Endpoint,
+    header: Option<&str>,
+    peer: Peer,
+) -> Result<(), Limited> {
+    let limits = config.limits();
+    let limiter = match endpoint {
+        Endpoint::Refresh => &limits.refresh,
+        Endpoint::Search => &limits.search,
+    };
+    let Some(limiter) = limiter else {
+        return Ok(());
+    };
+    let client = Client::identify(config, header, peer);
+
+    limiter.acquire(client.clone()).map_err(|retry_after| {
+        debug!("Limited {endpoint:?} request of {client}.");
+        limits.record_limited();
+        Limited { retry_after }
+    })
+}
+
+/// Counts a stream of the client identified by the given `Authorization`
+/// header and peer in its limits, if enabled, as long as the returned
+/// [`StreamPermit`] is alive.
+pub fn acquire_stream(
+    config: &Config,
+    header: Option<&str>,
+    peer: Peer,
+) -> Result<StreamPermit, Limited> {
+    let limits = config.limits();
+    let Some(limiter) = &limits.streams else {
+        return Ok(StreamPermit::default());
+    };
+    let client = Client::identify(config, header, peer);
+
+    limiter.acquire(client.clone()).ok_or_else(|| {
+        debug!("Limited stream of {client}.");
+        limits.record_limited();
+        Limited {
+            retry_after: STREAM_RETRY_AFTER,
+        }
+    })
+}
+
+/// Filter limiting the rate of the requests of each client to the given
+/// endpoint, if enabled.
+///
+/// See also: [`acquire_rate`].
+pub fn rate(
+    endpoint: Endpoint,
+    config: SharedConfig,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    client_info()
+        .and_then(move |header: Option<String>, peer: Peer| {
+            future::ready(
+                acquire_rate(&config, endpoint, header.as_deref(), peer)
+                    .map_err(|err| reject::custom(ApiError::from(err))),
+            )
+        })
+        .untuple_one()
+}
+
+/// Filter counting the stream of the request in the limits of its client, if
+/// enabled, as long as the extracted [`StreamPermit`] is alive.
+///
+/// See also: [`acquire_stream`].
+pub fn stream(
+    config: SharedConfig,
+) -> impl Filter<Extract = (StreamPermit,), Error = Rejection> + Clone {
+    client_info().and_then(move |header: Option<String>, peer: Peer| {
+        future::ready(
+            acquire_stream(&config, header.as_deref(), peer)
+                .map_err(|err| reject::custom(ApiError::from(err))),
+        )
+    })
+}
+
+impl From<Limited> for ApiError {
+    /// Replies `429 Too Many Requests` with a `Retry-After` header.
+    fn from(limited: Limited) -> Self {
+        let seconds = limited.retry_after_secs();
+
+        ApiError::new(
+            StatusCode::TOO_MANY_REQUESTS,
+            "too_many_requests",
+            format!("Too many requests: retry in {seconds} seconds."),
+        )
+        .with_details(serde_json::json!({ "retry_after": seconds }))
+        .with_header(RETRY_AFTER, seconds.into())
+    }
+}
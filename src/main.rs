@@ -1,36 +1,226 @@
-//! Main module: CLI parsing, server launching, and integration testing.
+//! Main module: CLI parsing, server launching and client commands.
 
 use std::env;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
 
+use anyhow::Result;
 use clap::Parser;
-use warp::Filter;
-#[macro_use]
-extern crate log;
-
-mod proc;
-use proc::ProcCache;
-mod handlers;
-mod routes;
+use futures_util::future::{self, FutureExt};
+use tokio::net::TcpListener;
+use tokio::sync::RwLock;
+
+use proc_api::auth::{Subjects, Tokens};
+use proc_api::compression::{self, Encoding};
+use proc_api::config::{Config, SharedConfig};
+use proc_api::limits::{Limits, Rate};
+use proc_api::proc::{CacheInner, ProcCache};
+use proc_api::routes;
+use proc_api::server::{self, SocketOwner, Tls};
+use proc_api::source::{RecordingSource, ReplaySource, SysinfoSource};
+use proc_api::store::StateDir;
+
+#[cfg(feature = "client")]
+mod commands;
+#[cfg(feature = "tui")]
+mod tui;
 
 /// `"proc_api"`
 const CRATE_NAME: &str = env!("CARGO_CRATE_NAME");
 
-/// Launch an HTTP server exposing the current host's process information.
+/// Launch an HTTP server exposing the current host's process information, or
+/// query a remote one.
 #[derive(clap::Parser, Debug, PartialEq, Eq)]
-#[command(author, version, about, long_about)]
+#[command(
+    author,
+    version,
+    about,
+    long_about,
+    args_conflicts_with_subcommands = true
+)]
 pub struct CliArgs {
-    /// The interface address to bind the server socket to.
-    #[arg(short, long, default_value = "127.0.0.1")]
-    pub addr: IpAddr,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// The options of `serve`, which is run when no command is given.
+    #[command(flatten)]
+    pub serve: ServeArgs,
+}
+
+/// The commands of the CLI.
+#[derive(clap::Subcommand, Debug, PartialEq, Eq)]
+pub enum Command {
+    /// Launch an HTTP server exposing the current host's process information,
+    /// the default.
+    Serve(Box<ServeArgs>),
+    /// Print the processes of a server as a table.
+    #[cfg(feature = "client")]
+    Ps(commands::PsArgs),
+    /// Print the processes spawned and exited on a server as they are
+    /// discovered by its refreshes.
+    #[cfg(feature = "client")]
+    Watch(commands::RemoteArgs),
+    /// Refresh the processes cached by a server.
+    #[cfg(feature = "client")]
+    Refresh(commands::RemoteArgs),
+    /// Print the processes of a server as a tree of parents and children.
+    #[cfg(feature = "client")]
+    Tree(commands::RemoteArgs),
+    /// Browse the processes of a server interactively, like `top`.
+    #[cfg(feature = "tui")]
+    Tui(tui::TuiArgs),
+    /// Launch an HTTP server aggregating the processes of other servers.
+    #[cfg(feature = "client")]
+    Aggregate(AggregateArgs),
+}
+
+/// The options of the `serve` command.
+#[derive(clap::Args, Debug, PartialEq, Eq)]
+pub struct ServeArgs {
+    /// The interface address to bind the server socket to [default:
+    /// 127.0.0.1].
+    #[arg(short, long)]
+    pub addr: Option<IpAddr>,
+    /// The port number to listen on [default: 8080].
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// The port number to serve the gRPC service on as well, at the same
+    /// address. Not served over TLS, hence not available with `--tls-cert`.
+    #[cfg(feature = "grpc")]
+    #[arg(long, value_name = "PORT", conflicts_with_all = ["tls_cert", "client_ca"])]
+    pub grpc_port: Option<u16>,
+    /// A Unix socket to listen on instead of the TCP address and port, unless
+    /// either of them is given explicitly as well.
+    #[arg(long, value_name = "PATH")]
+    pub unix_socket: Option<PathBuf>,
+    /// The permission mode to give to the Unix socket, in octal.
+    #[arg(long, value_name = "MODE", value_parser = server::parse_mode, requires = "unix_socket")]
+    pub unix_socket_mode: Option<u32>,
+    /// The owner to give to the Unix socket, as numeric IDs.
+    #[arg(long, value_name = "UID[:GID]", requires = "unix_socket")]
+    pub unix_socket_owner: Option<SocketOwner>,
+    /// The number of process snapshots to retain in the cache's history,
+    /// including the current one.
+    #[arg(long, default_value_t = CacheInner::HISTORY_CAP)]
+    pub history: usize,
+    /// A directory where to persist the snapshots of the history, in order to
+    /// restore them at startup.
+    #[arg(long)]
+    pub state_dir: Option<PathBuf>,
+    /// A file where to record each refresh of the cache, in order to replay
+    /// them later on using `--replay`.
+    #[arg(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+    /// A file recorded using `--record` from which to replay the refreshes
+    /// instead of collecting the host's processes: each refresh advances to
+    /// the next recorded one.
+    #[arg(long, value_name = "FILE", conflicts_with = "record")]
+    pub replay: Option<PathBuf>,
+    /// Expose per-process resource gauges in `GET /metrics` for at most this
+    /// number of processes, the ones using the most memory. Disabled if zero.
+    #[arg(long, value_name = "COUNT", default_value = "0")]
+    pub metrics_procs: usize,
+    /// The encodings in which responses can be compressed, negotiated with
+    /// the `Accept-Encoding` header, by order of preference.
+    #[arg(
+        long,
+        value_name = "ENCODINGS",
+        value_enum,
+        value_delimiter = ',',
+        default_value = "zstd,br,gzip"
+    )]
+    pub compression: Vec<Encoding>,
+    /// Never compress responses.
+    #[arg(long, conflicts_with = "compression")]
+    pub no_compression: bool,
+    /// A JSON file containing the bearer tokens that requests must carry, each
+    /// granting scopes among `read`, `refresh`, `stream` and `signal`.
+    #[arg(long, value_name = "FILE")]
+    pub tokens: Option<PathBuf>,
+    /// Only show callers their own processes, identifying them by the `uid` of
+    /// their token, the subject of their client certificate or their Unix
+    /// socket credentials. Root and `admin` callers see all of them.
+    #[arg(long)]
+    pub own_processes: bool,
+    /// A JSON file mapping the subjects of client certificates to the `uid`
+    /// of their holder or to `admin`, when showing callers their own processes.
+    #[arg(long, value_name = "FILE", requires = "own_processes")]
+    pub subjects: Option<PathBuf>,
+    /// Let callers send signals to the processes they can see, given a token
+    /// granting the `signal` scope, hence only along with `--tokens`.
+    #[arg(long, requires = "tokens")]
+    pub allow_signals: bool,
+    /// The maximum rate of refreshes per client, as `<count>/<period>`, e.g.
+    /// `10/min`. Clients are told apart by their token, client certificate,
+    /// Unix socket user or IP address.
+    #[arg(long, value_name = "RATE")]
+    pub refresh_rate: Option<Rate>,
+    /// The maximum rate of searches per client, like `--refresh-rate`.
+    #[arg(long, value_name = "RATE")]
+    pub search_rate: Option<Rate>,
+    /// The maximum number of concurrent streams per client.
+    #[arg(long, value_name = "COUNT")]
+    pub max_streams: Option<usize>,
+    /// A PEM file containing the certificate chain to serve over TLS with,
+    /// reloaded on `SIGHUP` along with the other TLS files.
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// A PEM file containing the private key of the TLS certificate.
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// A PEM file containing the certificate authorities that clients must
+    /// present a certificate issued by, enabling mutual TLS.
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub client_ca: Option<PathBuf>,
+}
+
+/// The options of the `aggregate` command.
+#[cfg(feature = "client")]
+#[derive(clap::Args, Debug, PartialEq, Eq)]
+pub struct AggregateArgs {
+    /// The interface address to bind the server socket to [default:
+    /// 127.0.0.1].
+    #[arg(short, long)]
+    pub addr: Option<IpAddr>,
     /// The port number to listen on.
-    #[arg(short, long, default_value = "8080")]
+    #[arg(short, long, default_value_t = 8080)]
     pub port: u16,
+    /// An upstream server to aggregate, as `<host>[:<port>]` or as a URL like
+    /// `--host`, tagging its processes. Can be given multiple times.
+    #[arg(long = "upstream", value_name = "HOST", required = true)]
+    pub upstreams: Vec<String>,
+    /// The bearer token to authenticate with to the upstream servers, if they
+    /// require one.
+    #[arg(long, env = "PROC_API_UPSTREAM_TOKEN", hide_env_values = true)]
+    pub upstream_token: Option<String>,
+    /// A JSON file containing the bearer tokens that requests must carry, like
+    /// for `serve`, granting the `read` and `stream` scopes.
+    #[arg(long, value_name = "FILE")]
+    pub tokens: Option<PathBuf>,
+    /// The maximum rate of listings and searches per client together, as
+    /// `<count>/<period>`, e.g. `10/min`, each being sent to all the upstream
+    /// servers.
+    #[arg(long, value_name = "RATE")]
+    pub search_rate: Option<Rate>,
+    /// The maximum number of concurrent streams per client.
+    #[arg(long, value_name = "COUNT")]
+    pub max_streams: Option<usize>,
+    /// A PEM file containing the certificate chain to serve over TLS with,
+    /// reloaded on `SIGHUP` along with the other TLS files.
+    #[arg(long, value_name = "FILE", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// A PEM file containing the private key of the TLS certificate.
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// A PEM file containing the certificate authorities that clients must
+    /// present a certificate issued by, enabling mutual TLS.
+    #[arg(long, value_name = "FILE", requires = "tls_cert")]
+    pub client_ca: Option<PathBuf>,
 }
 
-/// Start the server on the given address and port.
+/// Run the given command, serving by default.
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<()> {
     let args = CliArgs::parse();
 
     // Use INFO as a default.
@@ -39,405 +229,389 @@ async fn main() {
     }
 
     pretty_env_logger::init_timed();
-    warp::serve(routes::all(&ProcCache::default()).with(warp::log(CRATE_NAME)))
-        .run((args.addr, args.port))
-        .await;
+    match args
+        .command
+        .unwrap_or_else(|| Command::Serve(Box::new(args.serve)))
+    {
+        Command::Serve(args) => serve(*args).await,
+        #[cfg(feature = "client")]
+        Command::Ps(args) => commands::ps(args).await,
+        #[cfg(feature = "client")]
+        Command::Watch(args) => commands::watch(args).await,
+        #[cfg(feature = "client")]
+        Command::Refresh(args) => commands::refresh(args).await,
+        #[cfg(feature = "client")]
+        Command::Tree(args) => commands::tree(args).await,
+        #[cfg(feature = "tui")]
+        Command::Tui(args) => tui::tui(args).await,
+        #[cfg(feature = "client")]
+        Command::Aggregate(args) => aggregate(args).await,
+    }
 }
 
-/// Basic integration tests.
-#[cfg(test)]
-mod tests {
-    use std::str;
-    use std::sync::Arc;
-
-    use tokio::sync::Barrier;
-    use tokio::time::{self, Duration};
-    use warp::http::StatusCode;
-    use warp::test::request;
+/// Start the server on the given address and port.
+async fn serve(args: ServeArgs) -> Result<()> {
+    let mut inner = CacheInner::new(args.history);
+    inner.metrics_mut().set_proc_gauges(args.metrics_procs);
 
-    use super::*;
-    use proc::ProcInfo;
-
-    /// Fetch processes without refreshing them first: empty JSON array in OK
-    /// response.
-    #[tokio::test]
-    async fn test_list_procs_empty() {
-        let res = request()
-            .method("GET")
-            .path("/processes")
-            .reply(&routes::list_procs(ProcCache::default()))
-            .await;
-
-        assert_eq!(res.status(), StatusCode::OK);
-        assert_eq!(res.body(), "[]");
+    if let Some(state_dir) = args.state_dir {
+        inner.persist_to(StateDir::open(state_dir)?)?;
     }
-
-    /// Refresh processes: empty OK response, non-empty cache.
-    #[tokio::test]
-    async fn test_refresh_procs() {
-        let cache = ProcCache::default();
-        let res = request()
-            .method("POST")
-            .path("/acquire_process_list")
-            .reply(&routes::refresh_procs(Arc::clone(&cache)))
-            .await;
-
-        assert_eq!(res.status(), StatusCode::OK);
-        assert_eq!(res.body(), "");
-        assert!(!cache.read().await.get().is_empty());
+    if let Some(record) = args.record {
+        inner.set_source(Box::new(RecordingSource::new(
+            SysinfoSource::default(),
+            record,
+        )?));
     }
-
-    /// Refresh processes, then fetch them: non-empty JSON array in OK response.
-    #[tokio::test]
-    async fn test_list_procs_refreshed() {
-        let cache = ProcCache::default();
-        request()
-            .method("POST")
-            .path("/acquire_process_list")
-            .reply(&routes::refresh_procs(Arc::clone(&cache)))
-            .await;
-        let res = request()
-            .method("GET")
-            .path("/processes")
-            .reply(&routes::list_procs(Arc::clone(&cache)))
-            .await;
-
-        assert_eq!(res.status(), StatusCode::OK);
-        assert!(
-            !serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(&**res.body()).unwrap())
-                .unwrap()
-                .is_empty()
-        );
+    if let Some(replay) = args.replay {
+        inner.set_source(Box::new(ReplaySource::open(replay)?));
     }
 
-    /// Query the search endpoint without parameters: empty body in BAD REQUEST
-    /// response.
-    #[tokio::test]
-    async fn test_search_procs_empty_noparams_is_badrequest() {
-        let res = request()
-            .method("GET")
-            .path("/search")
-            .reply(&routes::search_procs(ProcCache::default()))
-            .await;
-
-        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
-        assert_eq!(res.body(), "");
-    }
+    let mut config = Config::default();
 
-    /// Query the search endpoint without parameters even after refreshing
-    /// the cache: empty body in BAD REQUEST response.
-    #[tokio::test]
-    async fn test_search_procs_refreshed_noparams_is_badrequest() {
-        let cache = ProcCache::default();
-        request()
-            .method("POST")
-            .path("/acquire_process_list")
-            .reply(&routes::refresh_procs(Arc::clone(&cache)))
-            .await;
-        let res = request()
-            .method("GET")
-            .path("/search")
-            .reply(&routes::search_procs(Arc::clone(&cache)))
-            .await;
-
-        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
-        assert_eq!(res.body(), "");
+    if let Some(tokens) = args.tokens {
+        config.set_tokens(Tokens::load(tokens)?);
     }
-
-    /// Search for root processes without refreshing the cache first: empty
-    /// JSON array in OK response.
-    #[tokio::test]
-    async fn test_search_procs_empty() {
-        let res = request()
-            .method("GET")
-            .path("/search?uid=0")
-            .reply(&routes::search_procs(ProcCache::default()))
-            .await;
-
-        assert_eq!(res.status(), StatusCode::OK);
-        assert_eq!(res.body(), "[]");
+    if args.allow_signals {
+        config.allow_signals();
     }
-
-    /// Search for root processes by refreshing the cache first: non-empty JSON
-    /// array in OK response.
-    #[tokio::test]
-    async fn test_search_procs_refreshed() {
-        let cache = ProcCache::default();
-        request()
-            .method("POST")
-            .path("/acquire_process_list")
-            .reply(&routes::refresh_procs(Arc::clone(&cache)))
-            .await;
-        let res = request()
-            .method("GET")
-            .path("/search?uid=0")
-            .reply(&routes::search_procs(Arc::clone(&cache)))
-            .await;
-
-        assert_eq!(res.status(), StatusCode::OK);
-        assert!(
-            !serde_json::from_str::<Vec<ProcInfo>>(str::from_utf8(&**res.body()).unwrap())
-                .unwrap()
-                .is_empty()
-        );
+    config.set_limits(Limits::new(
+        args.refresh_rate,
+        args.search_rate,
+        args.max_streams,
+    ));
+    if args.own_processes {
+        config.restrict_visibility(match args.subjects {
+            Some(subjects) => Subjects::load(subjects)?,
+            None => Subjects::default(),
+        });
     }
 
-    /// Start the stream, wait for the test timout: no data received.
-    #[tokio::test]
-    async fn test_stream_procs_empty() {
-        let cache = ProcCache::default();
+    let encodings = if args.no_compression {
+        Vec::new()
+    } else {
+        args.compression
+    };
 
-        let stream = {
-            let cache = Arc::clone(&cache);
+    let tls = load_tls(args.tls_cert, args.tls_key, args.client_ca)?;
 
-            tokio::spawn(async move {
-                request()
-                    .method("GET")
-                    .path("/data")
-                    .reply(&routes::stream_procs(cache))
-                    .await
-            })
-        };
+    let cache: ProcCache = Arc::new(RwLock::new(inner));
+    let config: SharedConfig = Arc::new(config);
+    let filter = compression::compressed(encodings, routes::all(&cache, &config));
+    let mut servers = Vec::new();
 
-        assert!(cache.read().await.get().is_empty());
+    if let Some(path) = args.unix_socket.as_deref() {
+        let listener = server::bind_unix(path, args.unix_socket_mode, args.unix_socket_owner)?;
+        servers.push(server::serve_unix(listener, filter.clone()).boxed());
+    }
+    let ip = args.addr.unwrap_or(Ipv4Addr::LOCALHOST.into());
 
-        let res = tokio::join!(stream).0.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        assert!(str::from_utf8(res.body()).unwrap().lines().take(2).count() == 0);
+    if args.unix_socket.is_none() || args.addr.is_some() || args.port.is_some() {
+        let listener = TcpListener::bind((ip, args.port.unwrap_or(8080))).await?;
+        servers.push(server::serve_tcp(listener, tls, filter).boxed());
+    }
+    #[cfg(feature = "grpc")]
+    if let Some(port) = args.grpc_port {
+        let listener = TcpListener::bind((ip, port)).await?;
+        servers.push(proc_api::grpc::serve(listener, cache, config).boxed());
     }
 
-    /// Start the stream, refresh processes, wait for the timeout: at least one
-    /// process received.
-    #[tokio::test]
-    async fn test_stream_procs_refreshed_after() {
-        let cache = ProcCache::default();
-        let sync = Arc::new(Barrier::new(2));
-
-        let stream = {
-            let cache = Arc::clone(&cache);
-            let sync = Arc::clone(&sync);
-
-            tokio::spawn(async move {
-                let filter = routes::stream_procs(cache);
-                let fut = request().method("GET").path("/data").reply(&filter);
-                sync.wait().await;
-                fut.await
-            })
-        };
-
-        sync.wait().await;
-        assert_eq!(
-            request()
-                .method("POST")
-                .path("/acquire_process_list")
-                .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                .await
-                .status(),
-            StatusCode::OK
-        );
-        assert!(!cache.read().await.get().is_empty());
+    future::try_join_all(servers).await?;
+    Ok(())
+}
 
-        let res = tokio::join!(stream).0.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        assert!(str::from_utf8(res.body()).unwrap().lines().take(2).count() == 2);
-    }
+/// Load the TLS configuration of the given files, if any, reloading it on
+/// `SIGHUP`.
+fn load_tls(
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+    client_ca: Option<PathBuf>,
+) -> Result<Option<Arc<Tls>>> {
+    let (Some(cert), Some(key)) = (cert, key) else {
+        return Ok(None);
+    };
+    let tls = Tls::load(cert, key, client_ca)?;
+    tls.reload_on_hangup()?;
+    Ok(Some(tls))
+}
 
-    /// Refresh the processes first, then open the stream, wait for the timeout:
-    /// at least one process is observed.
-    #[tokio::test]
-    async fn test_stream_procs_refreshed_first() {
-        let cache = ProcCache::default();
+/// Start the aggregator of the given upstream servers on the given address and
+/// port, authenticating and limiting its clients as configured.
+#[cfg(feature = "client")]
+async fn aggregate(args: AggregateArgs) -> Result<()> {
+    use proc_api::client::Client;
+    use proc_api::federation::{self, Upstream};
+
+    // Shared by all the upstream clients, so that streams reconnecting to
+    // unreachable servers fail in time too.
+    let http = reqwest::Client::builder()
+        .connect_timeout(federation::UPSTREAM_TOUT)
+        .build()?;
+    let upstreams = args
+        .upstreams
+        .into_iter()
+        .map(|host| {
+            let client = Client::with_http_client(commands::base_url(&host)?, http.clone());
+            let client = match &args.upstream_token {
+                Some(token) => client.with_token(token),
+                None => client,
+            };
+            Ok(Upstream::new(host, client))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let mut config = Config::default();
 
-        assert_eq!(
-            request()
-                .method("POST")
-                .path("/acquire_process_list")
-                .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                .await
-                .status(),
-            StatusCode::OK
-        );
+    if let Some(tokens) = args.tokens {
+        config.set_tokens(Tokens::load(tokens)?);
+    }
+    config.set_limits(Limits::new(None, args.search_rate, args.max_streams));
 
-        let cache_len = cache.read().await.get().len();
-        assert!(cache_len != 0);
+    let tls = load_tls(args.tls_cert, args.tls_key, args.client_ca)?;
+    let filter = federation::routes(upstreams, &Arc::new(config));
+    let ip = args.addr.unwrap_or(Ipv4Addr::LOCALHOST.into());
+    let listener = TcpListener::bind((ip, args.port)).await?;
 
-        let stream = {
-            let cache = Arc::clone(&cache);
+    server::serve_tcp(listener, tls, filter).await
+}
 
-            tokio::spawn(async move {
-                request()
-                    .method("GET")
-                    .path("/data")
-                    .reply(&routes::stream_procs(cache))
-                    .await
-            })
-        };
+/// Tests of the CLI and of the rendering of the client commands.
+#[cfg(all(test, feature = "client"))]
+mod tests {
+    use proc_api::model::{CacheData, ProcInfo};
 
-        assert!(cache.read().await.get().len() == cache_len);
+    use super::*;
 
-        let res = tokio::join!(stream).0.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        assert!(str::from_utf8(res.body()).unwrap().lines().take(2).count() == 2);
+    /// Builds a process named after its PID and parent.
+    fn proc_info(pid: u32, ppid: Option<u32>) -> ProcInfo {
+        ProcInfo {
+            pid,
+            ppid,
+            uid: 1000,
+            name: format!("proc{pid}"),
+            username: "alice".to_owned(),
+        }
     }
 
-    /// Refresh the processes first, then open the stream, refresh again, wait
-    /// for the timeout: at least one process is observed after each action.
-    #[tokio::test]
-    async fn test_stream_procs_refreshed_first_and_after() {
-        let cache = ProcCache::default();
-        let sync = Arc::new(Barrier::new(2));
+    /// Parse no command, `serve` and client commands: serving by default,
+    /// exactly the given options, and no server options before commands.
+    #[test]
+    fn test_cli_commands() {
+        let args = CliArgs::try_parse_from([CRATE_NAME, "--port", "9090"]).unwrap();
+        assert_eq!(args.command, None);
+        assert_eq!(args.serve.port, Some(9090));
 
+        let args = CliArgs::try_parse_from([CRATE_NAME, "serve", "--port", "9090"]).unwrap();
+        assert!(matches!(args.command, Some(Command::Serve(serve)) if serve.port == Some(9090)));
+
+        let args =
+            CliArgs::try_parse_from([CRATE_NAME, "ps", "--host", "db:81", "--user", "postgres"])
+                .unwrap();
         assert_eq!(
-            request()
-                .method("POST")
-                .path("/acquire_process_list")
-                .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                .await
-                .status(),
-            StatusCode::OK
-        );
-        assert!(!cache.read().await.get().is_empty());
-
-        let stream = {
-            let cache = Arc::clone(&cache);
-            let sync = Arc::clone(&sync);
-
-            tokio::spawn(async move {
-                let filter = routes::stream_procs(cache);
-                let fut = request().method("GET").path("/data").reply(&filter);
-                sync.wait().await;
-                fut.await
-            })
-        };
-
-        sync.wait().await;
-        time::sleep(Duration::from_millis(500)).await;
-        assert_eq!(
-            request()
-                .method("POST")
-                .path("/acquire_process_list")
-                .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                .await
-                .status(),
-            StatusCode::OK
+            args.command,
+            Some(Command::Ps(commands::PsArgs {
+                remote: commands::RemoteArgs {
+                    host: "db:81".to_owned(),
+                    token: None,
+                },
+                user: Some("postgres".to_owned()),
+                name: None,
+                ppid: None,
+            }))
         );
-        assert!(!cache.read().await.get().is_empty());
+        assert!(CliArgs::try_parse_from([CRATE_NAME, "--port", "9090", "tree"]).is_err());
+
+        let args = CliArgs::try_parse_from([
+            CRATE_NAME,
+            "aggregate",
+            "--upstream",
+            "db:81",
+            "--upstream",
+            "https://web.example.com",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Aggregate(AggregateArgs { upstreams, port: 8080, .. }))
+                if upstreams == ["db:81", "https://web.example.com"]
+        ));
+        assert!(CliArgs::try_parse_from([CRATE_NAME, "aggregate"]).is_err());
+        let args = CliArgs::try_parse_from([
+            CRATE_NAME,
+            "aggregate",
+            "--upstream",
+            "db:81",
+            "--tokens",
+            "tokens.json",
+            "--max-streams",
+            "2",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+        ])
+        .unwrap();
+        assert!(matches!(
+            args.command,
+            Some(Command::Aggregate(AggregateArgs {
+                tokens: Some(_),
+                max_streams: Some(2),
+                tls_cert: Some(_),
+                ..
+            }))
+        ));
+        assert!(CliArgs::try_parse_from([
+            CRATE_NAME,
+            "aggregate",
+            "--upstream",
+            "db:81",
+            "--tls-cert",
+            "cert.pem",
+        ])
+        .is_err());
+
+        // Anyone reaching the server could signal its processes otherwise.
+        assert!(CliArgs::try_parse_from([CRATE_NAME, "--allow-signals"]).is_err());
+        assert!(CliArgs::try_parse_from([
+            CRATE_NAME,
+            "--allow-signals",
+            "--tokens",
+            "tokens.json"
+        ])
+        .is_ok());
+
+        // The gRPC service would leak tokens sent in plaintext otherwise.
+        #[cfg(feature = "grpc")]
+        assert!(CliArgs::try_parse_from([
+            CRATE_NAME,
+            "--grpc-port",
+            "9091",
+            "--tls-cert",
+            "cert.pem",
+            "--tls-key",
+            "key.pem",
+        ])
+        .is_err());
+    }
 
-        let res = tokio::join!(stream).0.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        assert!(str::from_utf8(res.body()).unwrap().lines().take(2).count() == 2);
+    /// Turn hosts into base URLs: default scheme and port, URLs as they are.
+    #[test]
+    fn test_base_url() {
+        for (host, url) in [
+            ("db", "http://db:8080/"),
+            ("db:81", "http://db:81/"),
+            ("[::1]", "http://[::1]:8080/"),
+            ("https://db.example.com", "https://db.example.com"),
+        ] {
+            assert_eq!(commands::base_url(host).unwrap(), url, "{host}");
+        }
     }
 
-    /// Open the stream, refresh processes, wait for the timeout, refresh again:
-    /// observe at least one process each time.
-    #[tokio::test]
-    async fn test_stream_procs_close_refreshed_after() {
-        let cache = ProcCache::default();
-        let sync = Arc::new(Barrier::new(2));
-
-        let stream = {
-            let cache = Arc::clone(&cache);
-            let sync = Arc::clone(&sync);
-
-            tokio::spawn(async move {
-                let filter = routes::stream_procs(cache);
-                let fut = request().method("GET").path("/data").reply(&filter);
-                sync.wait().await;
-                fut.await
-            })
-        };
-
-        sync.wait().await;
+    /// Render processes as a table: aligned columns, sorted by PID.
+    #[test]
+    fn test_table() {
+        let procs = [proc_info(10, Some(1)), proc_info(1, None)]
+            .into_iter()
+            .collect::<CacheData>();
+
         assert_eq!(
-            request()
-                .method("POST")
-                .path("/acquire_process_list")
-                .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                .await
-                .status(),
-            StatusCode::OK
+            commands::table(&procs),
+            concat!(
+                "PID PPID  UID USER  NAME\n",
+                "  1    - 1000 alice proc1\n",
+                " 10    1 1000 alice proc10\n",
+            )
         );
-        assert!(!cache.read().await.get().is_empty());
+    }
 
-        let res = tokio::join!(stream).0.unwrap();
-        assert_eq!(res.status(), StatusCode::OK);
-        assert!(str::from_utf8(res.body()).unwrap().lines().take(2).count() == 2);
+    /// Render processes as a tree: children under their parent, orphans as
+    /// roots, sorted by PID.
+    #[test]
+    fn test_tree() {
+        let procs = [
+            proc_info(1, None),
+            proc_info(2, Some(1)),
+            proc_info(3, Some(2)),
+            proc_info(4, Some(1)),
+            proc_info(5, Some(42)),
+        ]
+        .into_iter()
+        .collect::<CacheData>();
 
         assert_eq!(
-            request()
-                .method("POST")
-                .path("/acquire_process_list")
-                .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                .await
-                .status(),
-            StatusCode::OK
+            commands::tree_lines(&procs),
+            "1 proc1 (alice)\n\
+             ├─ 2 proc2 (alice)\n\
+             │  └─ 3 proc3 (alice)\n\
+             └─ 4 proc4 (alice)\n\
+             5 proc5 (alice)\n"
         );
-        assert!(!cache.read().await.get().is_empty());
     }
 
-    /// Repeat multiple times: open a stream, refresh processes, close the
-    /// stream, refresh, open another stream and close it right after. At least
-    /// one process should be observed at each step and iteration.
-    #[tokio::test]
-    async fn test_stream_procs_close_reopen_refreshed() {
-        let cache = ProcCache::default();
-        let sync = Arc::new(Barrier::new(2));
-
+    /// Sort, filter and select processes in the terminal UI, and send signals
+    /// to the selected one through its menu.
+    #[cfg(feature = "tui")]
+    #[test]
+    fn test_tui_app() {
+        use crossterm::event::{KeyCode, KeyEvent};
+        use proc_api::client::Event;
+        use proc_api::model::Signal;
+        use tui::{Action, App};
+
+        let mut app = App::new("db");
+        let press = |app: &mut App, code| app.handle_key(KeyEvent::from(code));
+        let pids = |app: &App| app.rows().iter().map(|proc| proc.pid).collect::<Vec<_>>();
+
+        app.resync(
+            [proc_info(3, Some(1)), proc_info(1, None)]
+                .into_iter()
+                .collect(),
+        );
+        let mut bob = proc_info(2, Some(1));
+        bob.username = "bob".to_owned();
+        app.apply(Event::Process(bob));
+        assert_eq!(pids(&app), [1, 2, 3]);
+
+        // Sort by PPID, then reversed, ties kept by PID.
+        press(&mut app, KeyCode::Char('s'));
+        assert_eq!(pids(&app), [1, 2, 3]);
+        press(&mut app, KeyCode::Char('r'));
+        assert_eq!(pids(&app), [3, 2, 1]);
+
+        // Filter by user, case-insensitively.
+        for code in [KeyCode::Char('u'), KeyCode::Char('B'), KeyCode::Enter] {
+            assert_eq!(press(&mut app, code), None);
+        }
+        assert_eq!(pids(&app), [2]);
+        press(&mut app, KeyCode::Char('c'));
+        assert_eq!(pids(&app), [3, 2, 1]);
+
+        // The selection follows the process, not its row.
+        press(&mut app, KeyCode::Down);
+        press(&mut app, KeyCode::Down);
+        assert_eq!(app.selected(), Some(2));
+        press(&mut app, KeyCode::Char('r'));
+        assert_eq!(app.selected(), Some(2));
+
+        // TERM is preselected, other signals are picked with the arrows.
+        press(&mut app, KeyCode::Char('k'));
+        assert_eq!(
+            press(&mut app, KeyCode::Enter),
+            Some(Action::Signal(2, Signal::Term))
+        );
+        // The last signal stays selected past the end of the menu.
+        press(&mut app, KeyCode::Char('k'));
         for _ in 0..3 {
-            let stream = {
-                let cache = Arc::clone(&cache);
-                let sync = Arc::clone(&sync);
-
-                tokio::spawn(async move {
-                    let filter = routes::stream_procs(cache);
-                    let fut = request().method("GET").path("/data").reply(&filter);
-                    sync.wait().await;
-                    fut.await
-                })
-            };
-
-            sync.wait().await;
-            assert_eq!(
-                request()
-                    .method("POST")
-                    .path("/acquire_process_list")
-                    .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                    .await
-                    .status(),
-                StatusCode::OK
-            );
-            assert!(!cache.read().await.get().is_empty());
-
-            let res = tokio::join!(stream).0.unwrap();
-            assert_eq!(res.status(), StatusCode::OK);
-            assert!(str::from_utf8(res.body()).unwrap().lines().take(2).count() == 2);
-
-            assert_eq!(
-                request()
-                    .method("POST")
-                    .path("/acquire_process_list")
-                    .reply(&routes::refresh_procs(Arc::clone(&cache)))
-                    .await
-                    .status(),
-                StatusCode::OK
-            );
-            assert!(!cache.read().await.get().is_empty());
-
-            let stream = {
-                let cache = Arc::clone(&cache);
-
-                tokio::spawn(async move {
-                    request()
-                        .method("GET")
-                        .path("/data")
-                        .reply(&routes::stream_procs(cache))
-                        .await
-                })
-            };
-
-            let res = tokio::join!(stream).0.unwrap();
-            assert_eq!(res.status(), StatusCode::OK);
-            assert!(str::from_utf8(res.body()).unwrap().lines().take(2).count() == 2);
+            press(&mut app, KeyCode::Down);
         }
+        assert_eq!(
+            press(&mut app, KeyCode::Enter),
+            Some(Action::Signal(2, Signal::Stop))
+        );
+
+        // Removing an exited process, but not a newer one of the same PID.
+        app.apply(Event::Removed(proc_info(2, None)));
+        app.apply(Event::Removed(proc_info(3, Some(1))));
+        assert_eq!(pids(&app), [1, 2]);
+        assert_eq!(press(&mut app, KeyCode::Char('q')), Some(Action::Quit));
     }
 }
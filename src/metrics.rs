@@ -0,0 +1,302 @@
+//! This module defines the metrics of the server and their rendering in the
+//! Prometheus text exposition format.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::proc::{CacheInner, ProcResources};
+
+/// Counters about the operation of the server, updated as it goes.
+///
+/// Atomics are used so that the counters can also be updated while only
+/// holding the cache's read lock.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    refreshes: AtomicU64,
+    refresh_failures: AtomicU64,
+    refresh_nanos: AtomicU64,
+    last_refresh_nanos: AtomicU64,
+    lagged: AtomicU64,
+    /// Maximum number of processes to expose resource gauges for, if any.
+    proc_gauges: usize,
+}
+
+impl Metrics {
+    /// Enables the per-process resource gauges for at most `cap` processes,
+    /// the ones using the most memory, or disables them if zero.
+    pub fn set_proc_gauges(&mut self, cap: usize) {
+        self.proc_gauges = cap;
+    }
+
+    /// Returns the maximum number of processes to expose resource gauges for,
+    /// zero meaning they are disabled.
+    pub fn proc_gauges(&self) -> usize {
+        self.proc_gauges
+    }
+
+    /// Records a refresh of the cache that took the given time.
+    pub fn record_refresh(&self, elapsed: Duration, success: bool) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.refreshes.fetch_add(1, Ordering::Relaxed);
+        self.refresh_nanos.fetch_add(nanos, Ordering::Relaxed);
+        self.last_refresh_nanos.store(nanos, Ordering::Relaxed);
+
+        if !success {
+            self.refresh_failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a subscriber of the cache's channel that lagged behind.
+    pub fn record_lagged(&self) {
+        self.lagged.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Renders all the metrics of the given cache and of the limits of the given
+/// configuration, with the resource gauges of the given processes.
+pub fn render(cache: &CacheInner, config: &Config, resources: &[ProcResources]) -> String {
+    let metrics = cache.metrics();
+    let procs = cache.get();
+    let mut out = Exposition(String::new());
+
+    out.header("proc_api_processes", "gauge", "Number of cached processes.");
+    out.sample("proc_api_processes", &[], procs.len());
+
+    let mut per_user = HashMap::<_, usize>::new();
+    let mut per_name = HashMap::<_, usize>::new();
+    for proc in procs {
+        *per_user.entry((proc.uid, &proc.username)).or_default() += 1;
+        *per_name.entry(&proc.name).or_default() += 1;
+    }
+
+    out.header(
+        "proc_api_user_processes",
+        "gauge",
+        "Number of cached processes per user.",
+    );
+    let mut per_user = per_user.into_iter().collect::<Vec<_>>();
+    per_user.sort_unstable();
+    for ((uid, username), count) in per_user {
+        out.sample(
+            "proc_api_user_processes",
+            &[("uid", &uid), ("username", username)],
+            count,
+        );
+    }
+
+    out.header(
+        "proc_api_name_processes",
+        "gauge",
+        "Number of cached processes per name.",
+    );
+    let mut per_name = per_name.into_iter().collect::<Vec<_>>();
+    per_name.sort_unstable();
+    for (name, count) in per_name {
+        out.sample("proc_api_name_processes", &[("name", name)], count);
+    }
+
+    out.header(
+        "proc_api_snapshot_id",
+        "gauge",
+        "ID of the current snapshot.",
+    );
+    out.sample("proc_api_snapshot_id", &[], cache.current().id);
+    out.header(
+        "proc_api_snapshots",
+        "gauge",
+        "Number of snapshots retained in the history.",
+    );
+    out.sample("proc_api_snapshots", &[], cache.snapshots().count());
+
+    out.header(
+        "proc_api_refreshes_total",
+        "counter",
+        "Number of refreshes of the cache, failed ones included.",
+    );
+    out.sample(
+        "proc_api_refreshes_total",
+        &[],
+        metrics.refreshes.load(Ordering::Relaxed),
+    );
+    out.header(
+        "proc_api_refresh_failures_total",
+        "counter",
+        "Number of failed refreshes of the cache.",
+    );
+    out.sample(
+        "proc_api_refresh_failures_total",
+        &[],
+        metrics.refresh_failures.load(Ordering::Relaxed),
+    );
+    out.header(
+        "proc_api_refresh_duration_seconds",
+        "summary",
+        "Time spent refreshing the cache.",
+    );
+    out.sample(
+        "proc_api_refresh_duration_seconds_sum",
+        &[],
+        seconds(metrics.refresh_nanos.load(Ordering::Relaxed)),
+    );
+    out.sample(
+        "proc_api_refresh_duration_seconds_count",
+        &[],
+        metrics.refreshes.load(Ordering::Relaxed),
+    );
+    out.header(
+        "proc_api_last_refresh_duration_seconds",
+        "gauge",
+        "Time spent by the last refresh of the cache.",
+    );
+    out.sample(
+        "proc_api_last_refresh_duration_seconds",
+        &[],
+        seconds(metrics.last_refresh_nanos.load(Ordering::Relaxed)),
+    );
+
+    out.header(
+        "proc_api_sse_subscribers",
+        "gauge",
+        "Number of clients currently subscribed to the stream.",
+    );
+    out.sample("proc_api_sse_subscribers", &[], cache.receiver_count());
+    out.header(
+        "proc_api_sse_lagged_total",
+        "counter",
+        "Number of stream subscribers disconnected for lagging behind.",
+    );
+    out.sample(
+        "proc_api_sse_lagged_total",
+        &[],
+        metrics.lagged.load(Ordering::Relaxed),
+    );
+
+    out.header(
+        "proc_api_limited_requests_total",
+        "counter",
+        "Number of requests rejected for exceeding the limits of their client.",
+    );
+    out.sample(
+        "proc_api_limited_requests_total",
+        &[],
+        config.limits().limited(),
+    );
+
+    if metrics.proc_gauges != 0 {
+        render_resources(&mut out, cache, resources);
+    }
+
+    out.0
+}
+
+/// Renders the per-process resource gauges, labeled with the cached
+/// information of each process.
+fn render_resources(out: &mut Exposition, cache: &CacheInner, resources: &[ProcResources]) {
+    let procs = cache
+        .get()
+        .iter()
+        .map(|proc| (proc.pid, proc))
+        .collect::<HashMap<_, _>>();
+    let mut resources = resources
+        .iter()
+        .filter_map(|res| procs.get(&res.pid).map(|&proc| (proc, res)))
+        .collect::<Vec<_>>();
+    resources
+        .sort_unstable_by(|(a, ra), (b, rb)| rb.memory.cmp(&ra.memory).then(a.pid.cmp(&b.pid)));
+    resources.truncate(cache.metrics().proc_gauges);
+
+    let gauges: [(&str, &str, ResourceGauge); 3] = [
+        (
+            "proc_api_process_memory_bytes",
+            "Resident memory of the process.",
+            |res| res.memory as f64,
+        ),
+        (
+            "proc_api_process_virtual_memory_bytes",
+            "Virtual memory of the process.",
+            |res| res.virtual_memory as f64,
+        ),
+        (
+            "proc_api_process_cpu_usage_percent",
+            "CPU usage of the process since the previous collection.",
+            |res| f64::from(res.cpu_usage),
+        ),
+    ];
+
+    for (name, help, value) in gauges {
+        out.header(name, "gauge", help);
+
+        for &(proc, res) in &resources {
+            out.sample(
+                name,
+                &[
+                    ("pid", &proc.pid),
+                    ("name", &proc.name),
+                    ("username", &proc.username),
+                ],
+                value(res),
+            );
+        }
+    }
+}
+
+/// Extracts the value of a per-process resource gauge.
+type ResourceGauge = fn(&ProcResources) -> f64;
+
+/// Converts nanoseconds to seconds.
+fn seconds(nanos: u64) -> f64 {
+    Duration::from_nanos(nanos).as_secs_f64()
+}
+
+/// Accumulates the text of the exposition.
+struct Exposition(String);
+
+impl Exposition {
+    /// Writes the help and type lines of a metric family.
+    fn header(&mut self, name: &str, kind: &str, help: &str) {
+        // Writing to a string does not fail.
+        let _ = writeln!(self.0, "# HELP {name} {help}");
+        let _ = writeln!(self.0, "# TYPE {name} {kind}");
+    }
+
+    /// Writes a sample line.
+    fn sample(&mut self, name: &str, labels: &[(&str, &dyn Display)], value: impl Display) {
+        self.0.push_str(name);
+
+        if !labels.is_empty() {
+            self.0.push('{');
+
+            for (i, (label, val)) in labels.iter().enumerate() {
+                if i != 0 {
+                    self.0.push(',');
+                }
+                let _ = write!(self.0, "{label}=\"{}\"", Escaped(val));
+            }
+
+            self.0.push('}');
+        }
+
+        let _ = writeln!(self.0, " {value}");
+    }
+}
+
+/// Escapes a label value as required by the exposition format.
+struct Escaped<'a>(&'a dyn Display);
+
+impl Display for Escaped<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for c in self.0.to_string().chars() {
+            match c {
+                '\\' => f.write_str("\\\\")?,
+                '"' => f.write_str("\\\"")?,
+                '\n' => f.write_str("\\n")?,
+                c => f.write_char(c)?,
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,323 @@
+//! This module defines the model of the API: the data exchanged with it, shared
+//! by the server and the [`crate::client`].
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "server")]
+use schemars::{
+    gen::SchemaGenerator,
+    schema::{InstanceType, Metadata, Schema, SchemaObject},
+    JsonSchema,
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+/// A set of processes, as listed by the API.
+pub type CacheData = HashSet<ProcInfo>;
+
+/// Summary of a snapshot: its identification and its number of processes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(JsonSchema))]
+pub struct SnapshotInfo {
+    pub id: u64,
+    pub time: Timestamp,
+    pub count: usize,
+}
+
+/// A [`SystemTime`] exchanged through the API as an RFC 3339 date, with full
+/// precision so that listed snapshot times can be used as is in queries.
+///
+/// Deserializing also accepts a number of seconds since the Unix epoch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub SystemTime);
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        humantime::format_rfc3339_nanos(self.0).fmt(f)
+    }
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "server")]
+impl JsonSchema for Timestamp {
+    fn schema_name() -> String {
+        "Timestamp".to_owned()
+    }
+
+    fn json_schema(_: &mut SchemaGenerator) -> Schema {
+        SchemaObject {
+            instance_type: Some(InstanceType::String.into()),
+            format: Some("date-time".to_owned()),
+            metadata: Some(Box::new(Metadata {
+                description: Some(
+                    "An RFC 3339 date, or a number of seconds since the Unix epoch in queries."
+                        .to_owned(),
+                ),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+
+        match raw.parse::<u64>() {
+            Ok(secs) => Ok(Self(UNIX_EPOCH + Duration::from_secs(secs))),
+            Err(_) => humantime::parse_rfc3339_weak(&raw)
+                .map(Self)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Common information representing a process as handled via the API.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(JsonSchema))]
+#[cfg_attr(
+    feature = "server",
+    derive(async_graphql::SimpleObject),
+    graphql(name = "Process", complex)
+)]
+pub struct ProcInfo {
+    /// Identifier of the process.
+    pub pid: u32,
+    /// Identifier of the parent process, if any.
+    pub ppid: Option<u32>,
+    /// Identifier of the user running the process.
+    pub uid: u32,
+    /// Name of the process' executable.
+    pub name: String,
+    /// Name of the user running the process.
+    pub username: String,
+}
+
+impl ProcInfo {
+    /// Names of the fields, in the order of their serialization.
+    pub const FIELDS: [&'static str; 5] = ["pid", "ppid", "uid", "name", "username"];
+}
+
+/// The parameters of a search of processes, at least one filter being
+/// required.
+///
+/// Basically an all-optional version of [`ProcInfo`], plus the selection of a
+/// past snapshot.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(JsonSchema))]
+pub struct SearchQuery {
+    /// Only selects the process of the given identifier.
+    pub pid: Option<u32>,
+    /// Only selects the children of the process of the given identifier.
+    pub ppid: Option<u32>,
+    /// Only selects the processes of the given user identifier.
+    pub uid: Option<u32>,
+    /// Only selects the processes of the given name.
+    pub name: Option<String>,
+    /// Only selects the processes of the given user name.
+    pub username: Option<String>,
+    /// Selects the snapshot that was current at the given time instead of the
+    /// current one.
+    pub at: Option<Timestamp>,
+}
+
+impl SearchQuery {
+    /// Tells whether the given process matches all the filters of the query.
+    pub fn matches(&self, proc: &ProcInfo) -> bool {
+        // Logic for each filter:
+        //  * None means not received in the request => true;
+        //  * Some(match) means filter matches => true;
+        //  * Some(_) means filters does not match => false;
+        // AND them all to reach usual search functionnality.
+        (self.pid.is_none() || self.pid == Some(proc.pid))
+            && (self.ppid.is_none() || self.ppid == proc.ppid)
+            && (self.uid.is_none() || self.uid == Some(proc.uid))
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| name == proc.name.as_str())
+            && self
+                .username
+                .as_ref()
+                .is_none_or(|username| username == proc.username.as_str())
+    }
+}
+
+/// A signal that can be sent to a process through the API, named as by
+/// `kill -s`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(JsonSchema))]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Signal {
+    Hup,
+    Int,
+    Quit,
+    Kill,
+    Usr1,
+    Usr2,
+    Term,
+    Cont,
+    Stop,
+}
+
+impl Signal {
+    /// All the signals, by increasing number on Linux.
+    pub const ALL: [Self; 9] = [
+        Self::Hup,
+        Self::Int,
+        Self::Quit,
+        Self::Kill,
+        Self::Usr1,
+        Self::Usr2,
+        Self::Term,
+        Self::Cont,
+        Self::Stop,
+    ];
+
+    /// Returns the name of the signal, without the `SIG` prefix.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Hup => "HUP",
+            Self::Int => "INT",
+            Self::Quit => "QUIT",
+            Self::Kill => "KILL",
+            Self::Usr1 => "USR1",
+            Self::Usr2 => "USR2",
+            Self::Term => "TERM",
+            Self::Cont => "CONT",
+            Self::Stop => "STOP",
+        }
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// The differences between two sets of processes, matched by PID.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(JsonSchema))]
+pub struct Diff {
+    /// Processes whose PID is only present in the new set.
+    pub added: Vec<ProcInfo>,
+    /// Processes whose PID is only present in the old set.
+    pub removed: Vec<ProcInfo>,
+    /// Processes whose PID is present in both sets, but with other differing
+    /// information.
+    pub modified: Vec<Modification>,
+}
+
+/// The two versions of a process that changed between two sets.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "server", derive(JsonSchema))]
+pub struct Modification {
+    pub from: ProcInfo,
+    pub to: ProcInfo,
+}
+
+/// Returns a PID shared by several of the given processes, if any: sets
+/// compared by PID, e.g. by [`Diff::between`], must not have any.
+pub fn duplicate_pid(procs: &CacheData) -> Option<u32> {
+    let mut pids = HashSet::with_capacity(procs.len());
+    procs
+        .iter()
+        .map(|proc| proc.pid)
+        .find(|&pid| !pids.insert(pid))
+}
+
+impl Diff {
+    /// Computes the differences going from `old` to `new`, each list being
+    /// sorted by PID, which must be unique in each set: see [`duplicate_pid`].
+    pub fn between(old: &CacheData, new: &CacheData) -> Self {
+        debug!("Computing difference...");
+        let old = old
+            .iter()
+            .map(|proc| (proc.pid, proc))
+            .collect::<HashMap<_, _>>();
+        let new = new
+            .iter()
+            .map(|proc| (proc.pid, proc))
+            .collect::<HashMap<_, _>>();
+        let mut diff = Self::default();
+
+        for (pid, &to) in &new {
+            match old.get(pid) {
+                None => diff.added.push(to.clone()),
+                Some(&from) if from != to => diff.modified.push(Modification {
+                    from: from.clone(),
+                    to: to.clone(),
+                }),
+                Some(_) => {}
+            }
+        }
+
+        diff.removed.extend(
+            old.iter()
+                .filter(|(pid, _)| !new.contains_key(pid))
+                .map(|(_, &proc)| proc.clone()),
+        );
+
+        diff.added.sort_unstable_by_key(|proc| proc.pid);
+        diff.removed.sort_unstable_by_key(|proc| proc.pid);
+        diff.modified.sort_unstable_by_key(|modif| modif.to.pid);
+        debug!("Done computing difference.");
+        diff
+    }
+}
+
+/// The body of the errors replied by the API.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[cfg_attr(
+    feature = "server",
+    derive(JsonSchema),
+    schemars(rename = "Error", description = "An error replied by the API.")
+)]
+pub struct ErrorBody {
+    /// Machine-readable code of the error, e.g. `not_found`.
+    pub code: String,
+    /// Human-readable description of the error.
+    pub message: String,
+    /// Details depending on the code of the error.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub details: Option<Value>,
+}
+
+/// A process of one of the upstream servers of an aggregator, tagged with the
+/// host it was listed by.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct HostProcInfo {
+    /// The upstream server, as configured in the aggregator.
+    pub host: String,
+    #[serde(flatten)]
+    pub proc: ProcInfo,
+}
+
+/// The processes listed by the upstream servers of an aggregator, sorted by
+/// host and PID, along with the errors of the upstream servers that could not
+/// list theirs.
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct AggregatedProcs {
+    pub processes: Vec<HostProcInfo>,
+    /// The errors by host, e.g. `unreachable` when a server could not be
+    /// connected to.
+    pub errors: BTreeMap<String, ErrorBody>,
+}
+
+/// An error of an upstream server of an aggregator, streamed as an
+/// `upstream_error` event.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UpstreamError {
+    pub host: String,
+    pub error: ErrorBody,
+}
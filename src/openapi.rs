@@ -0,0 +1,493 @@
+//! This module defines the OpenAPI 3 document describing the API, served by
+//! [`crate::routes::openapi`].
+//!
+//! The operations are described here, next to the [`crate::routes`] they
+//! document, while the schemas of the exchanged data and the query parameters
+//! are generated from their types, so that they cannot drift apart.
+
+use std::sync::OnceLock;
+
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+use crate::format::Format;
+use crate::model::{Diff, ErrorBody, ProcInfo, SnapshotInfo};
+use crate::routes::{AtQuery, DiffQuery, SearchQuery, SignalQuery};
+
+/// Returns the OpenAPI document of the API, built once.
+pub fn document() -> &'static Value {
+    static DOCUMENT: OnceLock<Value> = OnceLock::new();
+    DOCUMENT.get_or_init(build)
+}
+
+/// Builds the OpenAPI document of the API.
+fn build() -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let procs = json!({ "type": "array", "items": gen.subschema_for::<ProcInfo>() });
+    let snapshots = json!({ "type": "array", "items": gen.subschema_for::<SnapshotInfo>() });
+    let diff = gen.subschema_for::<Diff>();
+    gen.subschema_for::<ErrorBody>();
+
+    let at = query_params::<AtQuery>(&mut gen);
+    let search = query_params::<SearchQuery>(&mut gen);
+    let diff_query = query_params::<DiffQuery>(&mut gen);
+    let pid = json!({
+        "name": "pid",
+        "in": "path",
+        "required": true,
+        "description": "The PID of a cached process.",
+        "schema": { "type": "integer", "format": "uint32", "minimum": 0 },
+    });
+    let mut signal = vec![pid];
+    signal.extend(query_params::<SignalQuery>(&mut gen));
+    let id = json!({
+        "name": "id",
+        "in": "path",
+        "required": true,
+        "description": "The ID of a retained snapshot.",
+        "schema": { "type": "integer", "format": "uint64", "minimum": 0 },
+    });
+    let list_params = |params: &[Value]| {
+        let mut params = params.to_vec();
+        params.push(format_param(Format::LISTS));
+        params
+    };
+    let list_responses = |extra: Value| {
+        let mut responses = json!({
+            "200": {
+                "description": "The processes, in the negotiated format.",
+                "headers": {
+                    "ETag": { "schema": { "type": "string" } },
+                    "Last-Modified": { "schema": { "type": "string" } },
+                },
+                "content": list_content(&procs),
+            },
+            "304": { "description": "The client already has the processes." },
+            "404": error_response("The snapshot is not retained."),
+            "406": error_response("No supported format is acceptable."),
+        });
+        responses
+            .as_object_mut()
+            .unwrap()
+            .extend(extra.as_object().cloned().unwrap_or_default());
+        responses
+    };
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": env!("CARGO_PKG_NAME"),
+            "description": "REST API to a host's processes.",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": {
+            "/acquire_process_list": {
+                "post": operation(
+                    "refreshProcesses",
+                    "Refreshes the cached processes.",
+                    Some("refresh"),
+                    json!([]),
+                    json!({
+                        "200": { "description": "The cache was refreshed." },
+                        "429": error_response("Too many refreshes."),
+                        "500": error_response("The processes could not be collected."),
+                    }),
+                ),
+            },
+            "/processes/{pid}/signal": {
+                "post": operation(
+                    "signalProcess",
+                    "Sends a signal to a cached process visible to the caller, if the server allows it.",
+                    Some("signal"),
+                    Value::from(signal),
+                    json!({
+                        "200": { "description": "The signal was sent." },
+                        "403": error_response("Signals are not allowed by the server."),
+                        "404": error_response("The process is not cached, not visible, or no longer running."),
+                        "500": error_response("The signal could not be sent."),
+                    }),
+                ),
+            },
+            "/processes": {
+                "get": operation(
+                    "listProcesses",
+                    "Lists the cached processes, optionally from a past snapshot.",
+                    Some("read"),
+                    Value::from(list_params(&at)),
+                    list_responses(json!({})),
+                ),
+            },
+            "/search": {
+                "get": operation(
+                    "searchProcesses",
+                    "Lists the cached processes matching all the given filters, at least one being required.",
+                    Some("read"),
+                    Value::from(list_params(&search)),
+                    list_responses(json!({
+                        "400": error_response("No filter was given."),
+                        "429": error_response("Too many searches."),
+                    })),
+                ),
+            },
+            "/data": {
+                "get": operation(
+                    "streamProcesses",
+                    "Streams the cached processes, then the ones discovered and removed by each refresh.",
+                    Some("stream"),
+                    json!([format_param(Format::STREAMS)]),
+                    json!({
+                        "200": {
+                            "description": "The stream of processes, in the negotiated format, or SSE if only unsupported textual formats are acceptable.",
+                            "content": stream_content(),
+                        },
+                        "406": error_response("No supported format is acceptable."),
+                        "429": error_response("Too many concurrent streams."),
+                    }),
+                ),
+            },
+            "/snapshots": {
+                "get": operation(
+                    "listSnapshots",
+                    "Lists the retained snapshots, from the oldest to the current one.",
+                    Some("read"),
+                    json!([]),
+                    json!({
+                        "200": {
+                            "description": "The summaries of the snapshots.",
+                            "content": { "application/json": { "schema": snapshots } },
+                        },
+                    }),
+                ),
+            },
+            "/snapshots/{id}/processes": {
+                "get": operation(
+                    "listSnapshotProcesses",
+                    "Lists the processes of a retained snapshot.",
+                    Some("read"),
+                    Value::from(list_params(std::slice::from_ref(&id))),
+                    list_responses(json!({})),
+                ),
+            },
+            "/snapshots/export": {
+                "get": operation(
+                    "exportSnapshots",
+                    "Exports all the retained snapshots.",
+                    Some("read"),
+                    json!([]),
+                    export_responses(),
+                ),
+            },
+            "/snapshots/{id}/export": {
+                "get": operation(
+                    "exportSnapshot",
+                    "Exports a retained snapshot.",
+                    Some("read"),
+                    json!([id]),
+                    export_responses(),
+                ),
+            },
+            "/snapshots/import": {
+                "post": with_body(
+                    operation(
+                        "importSnapshots",
+                        "Imports exported snapshots into the cache.",
+                        Some("refresh"),
+                        json!([]),
+                        json!({
+                            "200": {
+                                "description": "The summaries of the imported snapshots.",
+                                "content": { "application/json": { "schema": snapshots } },
+                            },
+                            "400": error_response("The snapshots are invalid."),
+                            "403": error_response("Processes are only visible to their owner and the caller is not an admin."),
+                            "413": error_response("The snapshots are too large."),
+                            "429": error_response("Too many refreshes."),
+                        }),
+                    ),
+                    "application/octet-stream",
+                    json!({ "type": "string", "format": "binary" }),
+                ),
+            },
+            "/diff": {
+                "get": operation(
+                    "diffSnapshots",
+                    "Compares two retained snapshots.",
+                    Some("read"),
+                    Value::from(diff_query.clone()),
+                    diff_responses(&diff),
+                ),
+                "post": with_body(
+                    operation(
+                        "diffUploaded",
+                        "Compares the uploaded processes to a retained snapshot.",
+                        Some("read"),
+                        Value::from(
+                            diff_query
+                                .into_iter()
+                                .filter(|param| param["name"] == "to")
+                                .collect::<Vec<_>>(),
+                        ),
+                        diff_responses(&diff),
+                    ),
+                    "application/json",
+                    procs,
+                ),
+            },
+            "/metrics": {
+                "get": operation(
+                    "metrics",
+                    "Exposes the metrics of the server in the Prometheus text format.",
+                    Some("read"),
+                    json!([]),
+                    json!({
+                        "200": {
+                            "description": "The metrics.",
+                            "content": { "text/plain": { "schema": { "type": "string" } } },
+                        },
+                        "403": error_response("Processes are only visible to their owner and the caller is not an admin."),
+                    }),
+                ),
+            },
+            "/graphql": {
+                "get": operation(
+                    "graphqlSubscriptions",
+                    "Serves GraphQL subscriptions over a WebSocket, with the `graphql-transport-ws` or `graphql-ws` protocol.",
+                    Some("stream"),
+                    json!([]),
+                    json!({
+                        "101": { "description": "The connection is upgraded to a WebSocket." },
+                        "400": error_response("The request is not a WebSocket upgrade."),
+                        "429": error_response("Too many streams."),
+                    }),
+                ),
+                "post": with_body(
+                    operation(
+                        "graphql",
+                        "Executes a GraphQL query over the current snapshot.",
+                        Some("read"),
+                        json!([]),
+                        json!({
+                            "200": {
+                                "description": "The GraphQL response, with its errors if any.",
+                                "content": { "application/json": { "schema": { "type": "object" } } },
+                            },
+                            "400": error_response("The request is not a GraphQL one."),
+                            "429": error_response("Too many searches."),
+                        }),
+                    ),
+                    "application/json",
+                    json!({
+                        "type": "object",
+                        "required": ["query"],
+                        "properties": {
+                            "query": { "type": "string" },
+                            "operationName": { "type": "string" },
+                            "variables": { "type": "object" },
+                        },
+                    }),
+                ),
+            },
+            "/openapi.json": {
+                "get": operation(
+                    "openapi",
+                    "Describes the API as this OpenAPI document.",
+                    None,
+                    json!([]),
+                    json!({
+                        "200": {
+                            "description": "The OpenAPI document.",
+                            "content": { "application/json": { "schema": { "type": "object" } } },
+                        },
+                    }),
+                ),
+            },
+            "/": {
+                "get": operation(
+                    "dashboard",
+                    "Serves the web dashboard browsing the processes.",
+                    None,
+                    json!([]),
+                    json!({
+                        "200": {
+                            "description": "The page of the dashboard.",
+                            "content": { "text/html": { "schema": { "type": "string" } } },
+                        },
+                    }),
+                ),
+            },
+        },
+        "components": {
+            "schemas": gen.take_definitions(),
+            "securitySchemes": {
+                "bearer": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "A token granting the scope of the operation, when authentication is enabled.",
+                },
+            },
+        },
+    })
+}
+
+/// Builds an operation, requiring the given scope if any, and replying the
+/// given responses or an error.
+fn operation(
+    id: &str,
+    summary: &str,
+    scope: Option<&str>,
+    parameters: Value,
+    mut responses: Value,
+) -> Value {
+    responses["default"] = error_response("An error, e.g. an unauthorized request.");
+    let mut operation = json!({
+        "operationId": id,
+        "summary": summary,
+        "parameters": parameters,
+        "responses": responses,
+    });
+
+    if let Some(scope) = scope {
+        operation["security"] = json!([{ "bearer": [] }]);
+        operation["x-scope"] = json!(scope);
+    } else {
+        operation["security"] = json!([]);
+    }
+    operation
+}
+
+/// Adds the given required request body to the given operation.
+fn with_body(mut operation: Value, media_type: &str, schema: Value) -> Value {
+    operation["requestBody"] = json!({
+        "required": true,
+        "content": { media_type: { "schema": schema } },
+    });
+    operation
+}
+
+/// Generates the query parameters of the fields of the given type.
+fn query_params<T: JsonSchema>(gen: &mut SchemaGenerator) -> Vec<Value> {
+    let schema = serde_json::to_value(gen.root_schema_for::<T>().schema).unwrap();
+    let required = schema["required"].as_array().cloned().unwrap_or_default();
+
+    schema["properties"]
+        .as_object()
+        .into_iter()
+        .flatten()
+        .map(|(name, property)| {
+            let mut property = property.as_object().cloned().unwrap_or_default();
+            let description = property.remove("description").unwrap_or_default();
+            // Absent parameters are not null: unwrap the optional schemas.
+            property.remove("nullable");
+            if let Some([schema]) = property
+                .get("allOf")
+                .and_then(Value::as_array)
+                .map(Vec::as_slice)
+            {
+                property = schema.as_object().cloned().unwrap_or_default();
+            }
+
+            json!({
+                "name": name,
+                "in": "query",
+                "required": required.contains(&json!(name)),
+                "description": description,
+                "schema": property,
+            })
+        })
+        .collect()
+}
+
+/// Builds the `format` query parameter selecting one of the given formats
+/// instead of the `Accept` header.
+fn format_param(formats: &[Format]) -> Value {
+    json!({
+        "name": "format",
+        "in": "query",
+        "required": false,
+        "description": "Selects the format of the reply instead of the Accept header.",
+        "schema": {
+            "type": "string",
+            "enum": formats.iter().map(|format| format.name()).collect::<Vec<_>>(),
+        },
+    })
+}
+
+/// Builds the content of process lists, in each of the [`Format::LISTS`].
+fn list_content(procs: &Value) -> Value {
+    Format::LISTS
+        .iter()
+        .map(|&format| {
+            let schema = match format {
+                Format::Json => procs.clone(),
+                Format::Csv | Format::Tsv => json!({ "type": "string" }),
+                _ => json!({ "type": "string", "format": "binary" }),
+            };
+            (format.media_type().to_owned(), json!({ "schema": schema }))
+        })
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+/// Builds the content of process streams, in each of the [`Format::STREAMS`],
+/// each item being a process.
+fn stream_content() -> Value {
+    Format::STREAMS
+        .iter()
+        .map(|&format| {
+            let content = match format {
+                Format::EventStream => json!({
+                    "schema": { "type": "string" },
+                    "x-events": {
+                        "message": {
+                            "description": "A process, as the JSON data of the event.",
+                            "schema": { "$ref": "#/components/schemas/ProcInfo" },
+                        },
+                        "removed": {
+                            "description": "A process that exited, as the JSON data of the event.",
+                            "schema": { "$ref": "#/components/schemas/ProcInfo" },
+                        },
+                    },
+                }),
+                Format::Ndjson => json!({
+                    "schema": { "type": "string" },
+                    "x-item-schema": { "$ref": "#/components/schemas/ProcInfo" },
+                }),
+                _ => json!({ "schema": { "type": "string", "format": "binary" } }),
+            };
+            (format.stream_media_type().to_owned(), content)
+        })
+        .collect::<Map<_, _>>()
+        .into()
+}
+
+/// Builds the responses of snapshot exports.
+fn export_responses() -> Value {
+    json!({
+        "200": {
+            "description": "The snapshots, in the on-disk format of the server.",
+            "content": { "application/octet-stream": { "schema": { "type": "string", "format": "binary" } } },
+        },
+        "404": error_response("The snapshot is not retained."),
+    })
+}
+
+/// Builds the responses of comparisons.
+fn diff_responses(diff: &impl serde::Serialize) -> Value {
+    json!({
+        "200": {
+            "description": "The differences between the processes.",
+            "content": { "application/json": { "schema": diff } },
+        },
+        "400": error_response("The snapshots to compare are invalid."),
+        "404": error_response("A snapshot is not retained."),
+    })
+}
+
+/// Builds a response replying an error.
+fn error_response(description: &str) -> Value {
+    json!({
+        "description": description,
+        "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Error" } } },
+    })
+}
@@ -1,102 +1,453 @@
 //! This module defines the fundamentals of the API: how to collect current
 //! processes and how to store them in a common cache.
 
-use std::collections::HashSet;
-use std::sync::Arc;
+use std::collections::VecDeque;
+use std::sync::{Arc, OnceLock};
+use std::time::{Instant, SystemTime};
 
 use anyhow::{anyhow, Result};
-use serde::Serialize;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
 use sysinfo::{PidExt, ProcessExt, System, SystemExt, UserExt};
-use tokio::sync::{broadcast, RwLock};
+use tokio::sync::{broadcast, Mutex, RwLock};
+use tokio::task;
+
+use crate::metrics::Metrics;
+use crate::model::Signal;
+use crate::source::{ProcSource, SysinfoSource};
+use crate::store::StateDir;
+
+// The model used to be defined here: keep it available from here.
+pub use crate::model::{CacheData, Diff, Modification, ProcInfo, SnapshotInfo, Timestamp};
 
 /// A read-write lock-synchronized cache for processes.
 ///
 /// Instantiate using [`Default`].
 pub type ProcCache = Arc<RwLock<CacheInner>>;
-type CacheData = HashSet<ProcInfo>;
+/// A [`ProcSource`] shared between the cache and its refreshes.
+pub type SharedSource = Arc<Mutex<Box<dyn ProcSource>>>;
 
-/// The effective storage for the [`ProcCache`]: a [`ProcInfo`] set and a
+/// The effective storage for the [`ProcCache`]: a bounded history of
+/// [`Snapshot`]s, the most recent one being the current process data, the
+/// [`ProcSource`] refreshing it, the [`Metrics`] of its operation, and a
 /// [`broadcast::channel`] as a means to support the streaming SSE endpoint.
 ///
 /// The channel is an mpmc in order to only use it as an spmc. Messages are
-/// vectors of [`ProcInfo`]s so that the need for synchronization can be
+/// vectors of [`Change`]s so that the need for synchronization can be
 /// avoided as much as possible and so that the channel's capacity can be
 /// bounded to the actual number of concurrent communications between the
 /// refresh and stream endpoint handlers.
+///
+/// Snapshots are immutable and shared: readers only need to hold the lock
+/// while cloning the [`Arc`] of the one they want, and refreshes while
+/// pushing a new one.
 #[derive(Debug)]
 pub struct CacheInner {
-    cache: CacheData,
-    channel: broadcast::Sender<Vec<ProcInfo>>,
+    history: VecDeque<Arc<Snapshot>>,
+    history_cap: usize,
+    /// The snapshot current before the first refresh: empty and not retained.
+    empty: Arc<Snapshot>,
+    state: Option<Arc<StateDir>>,
+    source: SharedSource,
+    metrics: Metrics,
+    channel: broadcast::Sender<Vec<Change>>,
 }
 
-/// Instantiates the cache with an empty storage and a channel with an arbitrary
-/// but constant capacity.
+/// Instantiates the cache with an empty storage, a history and a channel with
+/// arbitrary but constant capacities.
 impl Default for CacheInner {
     fn default() -> Self {
-        debug!("Cache built.");
-        Self {
-            cache: CacheData::default(),
-            channel: broadcast::channel(Self::CHAN_CAP).0,
-        }
+        Self::new(Self::HISTORY_CAP)
     }
 }
 
 impl CacheInner {
     /// Arbitrary default capacity for the channel backing the streaming.
     const CHAN_CAP: usize = 16;
+    /// Arbitrary default number of snapshots retained in the history.
+    pub const HISTORY_CAP: usize = 16;
+
+    /// Instantiates the cache with an empty storage and a history retaining at
+    /// most `history_cap` snapshots, the current one included. A capacity of
+    /// zero is treated as one, as the current snapshot is always kept.
+    ///
+    /// Processes are collected using the [`SysinfoSource`] by default.
+    pub fn new(history_cap: usize) -> Self {
+        debug!("Cache built.");
+        let history_cap = history_cap.max(1);
+
+        Self {
+            history: VecDeque::with_capacity(history_cap),
+            history_cap,
+            empty: Arc::new(Snapshot::new(0, CacheData::default())),
+            state: None,
+            source: Arc::new(Mutex::new(Box::new(SysinfoSource::default()))),
+            metrics: Metrics::default(),
+            channel: broadcast::channel(Self::CHAN_CAP).0,
+        }
+    }
+
+    /// Collects processes using the given source from now on.
+    pub fn set_source(&mut self, source: Box<dyn ProcSource>) {
+        debug!("Cache source set to {source:?}.");
+        self.source = Arc::new(Mutex::new(source));
+    }
+
+    /// Persists the history to the given directory from now on, first
+    /// restoring it from the snapshots already persisted there, if any.
+    ///
+    /// Persisted snapshots that do not fit in the history are removed.
+    pub fn persist_to(&mut self, state: StateDir) -> Result<()> {
+        let mut loaded = state.load()?;
+        let kept = loaded.split_off(loaded.len().saturating_sub(self.history_cap));
+
+        for snap in loaded {
+            state.remove(snap.id)?;
+        }
+        if !kept.is_empty() {
+            info!(
+                "Restored {} snapshots from the state directory.",
+                kept.len()
+            );
+            self.history = kept.into_iter().map(Arc::new).collect();
+        }
+
+        self.state = Some(Arc::new(state));
+        Ok(())
+    }
+
+    /// Returns the metrics of the cache's operation.
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// Returns the metrics of the cache's operation, in order to configure them.
+    pub fn metrics_mut(&mut self) -> &mut Metrics {
+        &mut self.metrics
+    }
+
+    /// Collects the current resource usage of processes from the cache's
+    /// source, on a thread where blocking is acceptable and without holding
+    /// the cache's lock.
+    ///
+    /// See also: [`ProcSource::resources`].
+    pub async fn resources(cache: &ProcCache) -> Result<Vec<ProcResources>> {
+        let source = Arc::clone(&cache.read().await.source);
+        let source = source.lock_owned().await;
+        task::spawn_blocking(move || source.resources()).await?
+    }
+
+    /// Sends the given signal to the given process using the cache's source,
+    /// on a thread where blocking is acceptable and without holding the
+    /// cache's lock. Returns whether the process still existed.
+    ///
+    /// See also: [`ProcSource::signal`].
+    pub async fn signal(cache: &ProcCache, proc: ProcInfo, signal: Signal) -> Result<bool> {
+        let source = Arc::clone(&cache.read().await.source);
+        let source = source.lock_owned().await;
+        task::spawn_blocking(move || source.signal(&proc, signal)).await?
+    }
 
     /// Returns the currently-cached process data.
     pub fn get(&self) -> &CacheData {
         debug!("Cache read.");
-        &self.cache
+        &self.current().procs
     }
 
-    /// Refresh the cache by collecting all processes currently running on the
-    /// host again.
+    /// Returns the most recent snapshot, the one backing [`Self::get`], or an
+    /// empty one of ID 0 before the first refresh, which is not retained.
+    pub fn current(&self) -> &Arc<Snapshot> {
+        self.history.back().unwrap_or(&self.empty)
+    }
+
+    /// Iterates over the retained snapshots, from the oldest to the current one.
+    pub fn snapshots(&self) -> impl Iterator<Item = &Arc<Snapshot>> {
+        self.history.iter()
+    }
+
+    /// Returns the retained snapshot with the given ID, if any.
+    pub fn snapshot(&self, id: u64) -> Option<&Arc<Snapshot>> {
+        self.history.iter().find(|snap| snap.id == id)
+    }
+
+    /// Returns the retained snapshot that was current at the given time, i.e.
+    /// the most recent one taken no later than `time`, if any.
     ///
-    /// The cache storage is entirely overwritten with the new data. If the
-    /// channel has no receiver, then only that is done, otherwise new processes
-    /// will be sent to all currently-subscribed receivers of the channel by
-    /// computing the set difference between the new cache and the old one, but
-    /// the cache is still completely overwritten in the end.
-    pub fn refresh(&mut self) -> Result<()> {
+    /// Imported snapshots keep their original time, so the history is not
+    /// necessarily sorted by time.
+    pub fn snapshot_at(&self, time: SystemTime) -> Option<&Arc<Snapshot>> {
+        self.history
+            .iter()
+            .filter(|snap| snap.time <= time)
+            .max_by_key(|snap| snap.time)
+    }
+
+    /// Appends the given snapshot to the history, thus making it the current
+    /// one, and evicts the oldest ones if needed, returning how to persist
+    /// that if enabled.
+    fn push(&mut self, snap: Snapshot) -> Persistence {
+        debug!("Pushing snapshot {}.", snap.id);
+        let snap = Arc::new(snap);
+        let mut persistence = Persistence {
+            state: self.state.clone(),
+            saved: Some(Arc::clone(&snap)),
+            removed: Vec::new(),
+        };
+
+        while self.history.len() >= self.history_cap {
+            let old = self.history.pop_front().unwrap();
+            persistence.removed.push(old.id);
+        }
+
+        self.history.push_back(snap);
+        persistence
+    }
+
+    /// Refresh the cache by collecting all processes from its source again,
+    /// i.e. by default the ones currently running on the host.
+    ///
+    /// The collection is done on a thread where blocking is acceptable and
+    /// without holding the cache's lock, which is only taken in order to push
+    /// the new data as a new snapshot that becomes the current one, the
+    /// previous ones being kept in the history up to its capacity. If the
+    /// channel has no receiver, then only that is done, otherwise new and
+    /// exited processes will be sent to all currently-subscribed receivers of
+    /// the channel by computing the set differences between the new snapshot
+    /// and the previous one.
+    pub async fn refresh(cache: &ProcCache) -> Result<()> {
         debug!("Refreshing cache...");
+        let start = Instant::now();
+        // Hold the source until the new snapshot is pushed, so that concurrent
+        // refreshes are pushed in the order of their collections.
+        let source = Arc::clone(&cache.read().await.source);
+        let mut source = source.lock_owned().await;
+        let (_source, procs) = task::spawn_blocking(move || {
+            let procs = source.collect();
+            (source, procs)
+        })
+        .await?;
+
+        let res = {
+            let mut cache = cache.write().await;
+            let res = procs.and_then(|procs| {
+                let id = cache.current().id + 1;
+                cache.update(Snapshot::new(id, procs))
+            });
+            cache.metrics.record_refresh(start.elapsed(), res.is_ok());
+            res
+        };
+        // Still holding the source: persisted in the order of the collections.
+        res?.run().await;
+        debug!("Done refreshing.");
+        Ok(())
+    }
+
+    /// Makes the given snapshots, typically exported from another host, the
+    /// current one in turn as if they had just been collected by
+    /// [`Self::refresh`], persisting them afterwards without holding the
+    /// cache's lock.
+    ///
+    /// They are renumbered in order to fit in the history, but keep their
+    /// original time. Returns the summaries of the snapshots as imported.
+    pub async fn import(cache: &ProcCache, snaps: Vec<Snapshot>) -> Result<Vec<SnapshotInfo>> {
+        let mut infos = Vec::with_capacity(snaps.len());
+        let mut persistences = Vec::with_capacity(snaps.len());
+        let res = {
+            let mut cache = cache.write().await;
+            snaps.into_iter().try_for_each(|mut snap| {
+                snap.id = cache.current().id + 1;
+                debug!("Importing snapshot as {}...", snap.id);
+                infos.push(snap.info());
+                persistences.push(cache.update(snap)?);
+                Ok(())
+            })
+        };
+
+        // Also persist the snapshots imported before a failure.
+        for persistence in persistences {
+            persistence.run().await;
+        }
+        res.map(|()| infos)
+    }
 
+    /// Pushes the new snapshot and notifies the channel's receivers of the
+    /// processes it added and removed, returning how to persist that.
+    fn update(&mut self, snap: Snapshot) -> Result<Persistence> {
         // Use the receiver count as an indicator of the current mode of
         // operation: 0 means blocking, anything else means streaming.
         if self.channel.receiver_count() == 0 {
-            debug!("No receivers: replace cache.");
-            self.cache = ProcInfo::collect_all()?;
+            debug!("No receivers: push snapshot.");
+            Ok(self.push(snap))
         } else {
             debug!("At least one receiver:");
-            let old = self.cache.clone();
-            debug!("Replacing cache...");
-            self.cache = ProcInfo::collect_all()?;
+            let old = self.get();
+            let changes = snap
+                .procs
+                .difference(old)
+                .cloned()
+                .map(Change::Added)
+                .chain(old.difference(&snap.procs).cloned().map(Change::Removed))
+                .collect();
+            debug!("Pushing snapshot...");
+            let persistence = self.push(snap);
             debug!("Sending difference to channel...");
-            self.channel
-                .send(self.cache.difference(&old).cloned().collect())?;
+            self.channel.send(changes)?;
             debug!("Difference sent.");
+            Ok(persistence)
         }
+    }
 
-        debug!("Done refreshing.");
-        Ok(())
+    /// Returns the number of receivers currently subscribed to the backing
+    /// channel.
+    pub fn receiver_count(&self) -> usize {
+        self.channel.receiver_count()
     }
 
     /// Generates a new receiver by subscribing to the backing channel.
-    pub fn subscribe(&self) -> broadcast::Receiver<Vec<ProcInfo>> {
+    pub fn subscribe(&self) -> broadcast::Receiver<Vec<Change>> {
         debug!("Subscribed to cache channel.");
         self.channel.subscribe()
     }
 }
 
-/// Common information representing a process as handled via the API.
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize)]
-#[cfg_attr(test, derive(serde::Deserialize))]
-pub struct ProcInfo {
+/// The changes made to the history by [`CacheInner::push`], to persist once
+/// the cache's lock is released so that readers do not wait for the disk.
+#[must_use]
+struct Persistence {
+    state: Option<Arc<StateDir>>,
+    saved: Option<Arc<Snapshot>>,
+    removed: Vec<u64>,
+}
+
+impl Persistence {
+    /// Saves the new snapshot and removes the evicted ones, if the history is
+    /// persisted, on a thread where blocking is acceptable. Failures are only
+    /// logged: the snapshots are still in memory.
+    async fn run(self) {
+        let Self {
+            state: Some(state),
+            saved,
+            removed,
+        } = self
+        else {
+            return;
+        };
+
+        let res = task::spawn_blocking(move || {
+            if let Some(snap) = saved {
+                if let Err(err) = state.save(&snap) {
+                    warn!("Unable to persist snapshot {}: {err:#}", snap.id);
+                }
+            }
+            for id in removed {
+                if let Err(err) = state.remove(id) {
+                    warn!("Unable to remove persisted snapshot {id}: {err:#}");
+                }
+            }
+        })
+        .await;
+
+        if let Err(err) = res {
+            warn!("Unable to persist the history: {err}");
+        }
+    }
+}
+
+/// A change of the cached processes made by a refresh, as sent to the
+/// receivers of the cache's channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// A process that was not in the previous snapshot.
+    Added(ProcInfo),
+    /// A process of the previous snapshot that is no longer there, i.e. that
+    /// exited.
+    Removed(ProcInfo),
+}
+
+impl Change {
+    /// Returns the process that changed.
+    pub fn proc(&self) -> &ProcInfo {
+        match self {
+            Self::Added(proc) | Self::Removed(proc) => proc,
+        }
+    }
+}
+
+/// The process data collected by a single refresh of the cache, identified by
+/// a sequential ID and timestamped with the time of its collection.
+///
+/// The ID is thus the generation of the cache that the snapshot made current,
+/// which is what [`crate::conditional`] requests are keyed on.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: u64,
+    pub time: SystemTime,
+    pub procs: CacheData,
+    /// The JSON serialization of the processes, computed by the first reader
+    /// needing it and then shared by all the following ones.
+    #[serde(skip)]
+    json: OnceLock<Bytes>,
+}
+
+impl Snapshot {
+    /// Builds a snapshot of the given data, timestamped with the current time.
+    pub fn new(id: u64, procs: CacheData) -> Self {
+        Self {
+            id,
+            time: SystemTime::now(),
+            procs,
+            json: OnceLock::new(),
+        }
+    }
+
+    /// Returns the processes serialized as a JSON array.
+    pub fn json(&self) -> Bytes {
+        self.json
+            .get_or_init(|| {
+                debug!("Serializing snapshot {}...", self.id);
+                // Serializing plain data to JSON does not fail.
+                serde_json::to_vec(&self.procs).unwrap().into()
+            })
+            .clone()
+    }
+
+    /// Returns a copy of the snapshot, with the same ID and time, only keeping
+    /// the processes matching the given predicate.
+    pub fn filtered(&self, mut keep: impl FnMut(&ProcInfo) -> bool) -> Self {
+        Self {
+            id: self.id,
+            time: self.time,
+            procs: self
+                .procs
+                .iter()
+                .filter(|&proc| keep(proc))
+                .cloned()
+                .collect(),
+            json: OnceLock::new(),
+        }
+    }
+
+    /// Returns the summary of the snapshot as exposed by the API.
+    pub fn info(&self) -> SnapshotInfo {
+        SnapshotInfo {
+            id: self.id,
+            time: Timestamp(self.time),
+            count: self.procs.len(),
+        }
+    }
+}
+
+/// Resource usage of a process at some point in time, not cached as it changes
+/// continuously.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcResources {
     pub pid: u32,
-    pub uid: u32,
-    pub name: String,
-    pub username: String,
+    /// Resident memory, in bytes.
+    pub memory: u64,
+    /// Virtual memory, in bytes.
+    pub virtual_memory: u64,
+    /// CPU usage since the previous collection, in percent of a single CPU.
+    pub cpu_usage: f32,
 }
 
 impl ProcInfo {
@@ -113,6 +464,7 @@ impl ProcInfo {
                 .ok_or_else(|| anyhow!("Process {pid} does not have an associated user."))?;
             res.insert(Self {
                 pid: pid.as_u32(),
+                ppid: proc.parent().map(|ppid| ppid.as_u32()),
                 uid: **uid,
                 username: sys
                     .get_user_by_id(uid)
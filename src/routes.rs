@@ -5,32 +5,78 @@
 use std::convert::Infallible;
 use std::sync::Arc;
 
+use schemars::JsonSchema;
 use serde::Deserialize;
 use warp::Filter;
 
+use crate::auth::{self, Scope};
+use crate::conditional;
+use crate::config::SharedConfig;
+use crate::error;
+use crate::format::{self, Format};
+use crate::graphql;
 use crate::handlers;
-use crate::proc::ProcCache;
+use crate::limits::{self, Endpoint};
+use crate::model::Signal;
+use crate::proc::{CacheData, ProcCache, Timestamp};
+use crate::server;
+
+pub use crate::model::SearchQuery;
 
 /// Global route that dispatches to all the other effective routes defined in
-/// the [module](`self`).
+/// the [module](`self`) over the given cache, each requiring its [`Scope`] if
+/// authentication is enabled by the given configuration, and some limited per
+/// client if limits are. Rejected requests are replied with JSON errors by
+/// [`error::recover`].
 pub fn all(
     cache: &ProcCache,
+    config: &SharedConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    list_procs(Arc::clone(cache))
-        .or(refresh_procs(Arc::clone(cache)))
-        .or(search_procs(Arc::clone(cache)))
-        .or(stream_procs(Arc::clone(cache)))
+    list_procs(Arc::clone(cache), Arc::clone(config))
+        .or(refresh_procs(Arc::clone(cache), Arc::clone(config)))
+        .or(signal_proc(Arc::clone(cache), Arc::clone(config)))
+        .or(search_procs(Arc::clone(cache), Arc::clone(config)))
+        .or(stream_procs(Arc::clone(cache), Arc::clone(config)))
+        .or(list_snapshots(Arc::clone(cache), Arc::clone(config)))
+        .or(snapshot_procs(Arc::clone(cache), Arc::clone(config)))
+        .or(diff_snapshots(Arc::clone(cache), Arc::clone(config)))
+        .or(diff_uploaded(Arc::clone(cache), Arc::clone(config)))
+        .or(export_snapshots(Arc::clone(cache), Arc::clone(config)))
+        .or(import_snapshots(Arc::clone(cache), Arc::clone(config)))
+        .or(metrics(Arc::clone(cache), Arc::clone(config)))
+        .or(graphql_subscriptions(Arc::clone(cache), Arc::clone(config)))
+        .or(graphql(Arc::clone(cache), Arc::clone(config)))
+        .or(openapi())
+        .or(dashboard())
+        .recover(error::recover)
+}
+
+/// Defines the acceptable parameters for the [`list_procs`] query.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AtQuery {
+    /// Selects the snapshot that was current at the given time instead of the
+    /// current one.
+    pub at: Option<Timestamp>,
 }
 
 /// Route defining the read-only endpoint retrieving currently-cached processes
-/// and returning a JSON array with the requested information.
+/// and returning an array with the requested information, optionally from a
+/// past snapshot, in the format negotiated by [`format::negotiate`], unless
+/// the [`conditional::conditions`] of the request show that the client already
+/// has it.
 ///
 /// See also: [`handlers::list_procs`].
 pub fn list_procs(
     cache: ProcCache,
+    config: SharedConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path("processes")
+    warp::path!("processes")
         .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(warp::query::<AtQuery>())
+        .and(format::negotiate(Format::LISTS))
+        .and(conditional::conditions())
+        .and(auth::visibility(config))
         .and(with_cache(cache))
         .and_then(handlers::list_procs)
 }
@@ -40,22 +86,41 @@ pub fn list_procs(
 /// See also: [`handlers::refresh_procs`].
 pub fn refresh_procs(
     cache: ProcCache,
+    config: SharedConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path("acquire_process_list")
+    warp::path!("acquire_process_list")
         .and(warp::post())
+        .and(auth::require(Scope::Refresh, Arc::clone(&config)))
+        .and(limits::rate(Endpoint::Refresh, config))
+        .and(server::peer())
         .and(with_cache(cache))
         .and_then(handlers::refresh_procs)
 }
 
-/// Defines the acceptable parameters for the [`search_procs`] query.
+/// Defines the acceptable parameters for the [`signal_proc`] query.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SignalQuery {
+    /// The signal to send.
+    pub signal: Signal,
+}
+
+/// Route defining the POST endpoint sending a signal to a cached process, if
+/// allowed by the server.
 ///
-/// Basically an all-optional version of [`crate::proc::ProcInfo`].
-#[derive(Debug, Deserialize)]
-pub struct SearchQuery {
-    pub pid: Option<u32>,
-    pub uid: Option<u32>,
-    pub name: Option<String>,
-    pub username: Option<String>,
+/// See also: [`handlers::signal_proc`].
+pub fn signal_proc(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("processes" / u32 / "signal")
+        .and(warp::post())
+        .and(auth::require(Scope::Signal, Arc::clone(&config)))
+        .and(warp::query::<SignalQuery>())
+        .and(auth::visibility(Arc::clone(&config)))
+        .and(server::peer())
+        .and(with_cache(cache))
+        .and(with_config(config))
+        .and_then(handlers::signal_proc)
 }
 
 /// Route defining the read-only endpoint equivalent of [`list_procs`], but
@@ -64,27 +129,255 @@ pub struct SearchQuery {
 /// See also: [`handlers::search_procs`].
 pub fn search_procs(
     cache: ProcCache,
+    config: SharedConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path("search")
+    warp::path!("search")
         .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(limits::rate(Endpoint::Search, Arc::clone(&config)))
         .and(warp::query::<SearchQuery>())
+        .and(format::negotiate(Format::LISTS))
+        .and(conditional::conditions())
+        .and(auth::visibility(config))
         .and(with_cache(cache))
         .and_then(handlers::search_procs)
 }
 
 /// Route defining the SSE endpoint streaming currently-cached processes and
-/// newly-discovered ones when a request is sent to the refresh endpoint.
+/// newly-discovered ones when a request is sent to the refresh endpoint, or
+/// streaming them in another format negotiated by
+/// [`format::negotiate_stream`].
 ///
 /// See also: [`handlers::stream_procs`].
 pub fn stream_procs(
     cache: ProcCache,
+    config: SharedConfig,
 ) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
-    warp::path("data")
+    warp::path!("data")
         .and(warp::get())
+        .and(auth::require(Scope::Stream, Arc::clone(&config)))
+        .and(format::negotiate_stream())
+        .and(auth::visibility(Arc::clone(&config)))
+        .and(limits::stream(config))
         .and(with_cache(cache))
         .and_then(handlers::stream_procs)
 }
 
+/// Route defining the read-only endpoint listing the snapshots retained in the
+/// cache's history.
+///
+/// See also: [`handlers::list_snapshots`].
+pub fn list_snapshots(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("snapshots")
+        .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(auth::visibility(config))
+        .and(with_cache(cache))
+        .and_then(handlers::list_snapshots)
+}
+
+/// Route defining the read-only endpoint equivalent of [`list_procs`], but for
+/// the retained snapshot identified in the path.
+///
+/// See also: [`handlers::snapshot_procs`].
+pub fn snapshot_procs(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("snapshots" / u64 / "processes")
+        .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(format::negotiate(Format::LISTS))
+        .and(conditional::conditions())
+        .and(auth::visibility(config))
+        .and(with_cache(cache))
+        .and_then(handlers::snapshot_procs)
+}
+
+/// Route defining the read-only endpoint exporting all the retained snapshots,
+/// or only the one identified in the path, in the on-disk format of the
+/// [`crate::store`].
+///
+/// See also: [`handlers::export_snapshots`].
+pub fn export_snapshots(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("snapshots" / "export")
+        .map(|| None)
+        .or(warp::path!("snapshots" / u64 / "export").map(Some))
+        .unify()
+        .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(auth::visibility(config))
+        .and(with_cache(cache))
+        .and_then(handlers::export_snapshots)
+}
+
+/// Route defining the endpoint importing snapshots exported by
+/// [`export_snapshots`], possibly from another host, into the cache, limited
+/// as refreshes.
+///
+/// See also: [`handlers::import_snapshots`].
+pub fn import_snapshots(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("snapshots" / "import")
+        .and(warp::post())
+        .and(auth::require(Scope::Refresh, Arc::clone(&config)))
+        .and(limits::rate(Endpoint::Refresh, Arc::clone(&config)))
+        .and(warp::body::content_length_limit(UPLOAD_LIMIT))
+        .and(warp::body::bytes())
+        .and(auth::visibility(config))
+        .and(with_cache(cache))
+        .and_then(handlers::import_snapshots)
+}
+
+/// Maximum accepted size of the data uploaded to [`diff_uploaded`],
+/// [`import_snapshots`] and [`graphql`].
+const UPLOAD_LIMIT: u64 = 64 * 1024 * 1024;
+
+/// Defines the acceptable parameters for the [`diff_snapshots`] and
+/// [`diff_uploaded`] queries: snapshot IDs, the current one being used when
+/// `to` is absent.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DiffQuery {
+    /// The ID of the snapshot to compare from.
+    pub from: Option<u64>,
+    /// The ID of the snapshot to compare to, the current one by default.
+    pub to: Option<u64>,
+}
+
+/// Route defining the read-only endpoint comparing two retained snapshots and
+/// returning their differences as a JSON object.
+///
+/// See also: [`handlers::diff_snapshots`].
+pub fn diff_snapshots(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("diff")
+        .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(warp::query::<DiffQuery>())
+        .and(auth::visibility(config))
+        .and(with_cache(cache))
+        .and_then(handlers::diff_snapshots)
+}
+
+/// Route defining the endpoint equivalent of [`diff_snapshots`], but comparing
+/// process data uploaded as a JSON array in the request's body, used as the
+/// base, to a retained snapshot.
+///
+/// See also: [`handlers::diff_uploaded`].
+pub fn diff_uploaded(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("diff")
+        .and(warp::post())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(warp::query::<DiffQuery>())
+        .and(warp::body::content_length_limit(UPLOAD_LIMIT))
+        .and(warp::body::json::<CacheData>())
+        .and(auth::visibility(config))
+        .and(with_cache(cache))
+        .and_then(handlers::diff_uploaded)
+}
+
+/// Route defining the read-only endpoint exposing the metrics of the server and
+/// of the cached processes in the Prometheus text format.
+///
+/// See also: [`handlers::metrics`].
+pub fn metrics(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("metrics")
+        .and(warp::get())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(auth::visibility(Arc::clone(&config)))
+        .and(with_cache(cache))
+        .and(with_config(config))
+        .and_then(handlers::metrics)
+}
+
+/// Route defining the read-only endpoint executing the GraphQL queries given
+/// as JSON in the request's body over the current snapshot, limited as
+/// searches.
+///
+/// See also: [`handlers::graphql`].
+pub fn graphql(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let schema = graphql::schema(Arc::clone(&cache));
+
+    warp::path!("graphql")
+        .and(warp::post())
+        .and(auth::require(Scope::Read, Arc::clone(&config)))
+        .and(limits::rate(Endpoint::Search, Arc::clone(&config)))
+        .and(warp::body::content_length_limit(UPLOAD_LIMIT))
+        .and(warp::body::json::<async_graphql::Request>())
+        .and(auth::visibility(config))
+        .and(warp::any().map(move || schema.clone()))
+        .and(with_cache(cache))
+        .and_then(handlers::graphql)
+}
+
+/// Route defining the WebSocket endpoint of the GraphQL subscriptions, counted
+/// as streams in the limits of the client.
+///
+/// See also: [`handlers::graphql_subscriptions`].
+pub fn graphql_subscriptions(
+    cache: ProcCache,
+    config: SharedConfig,
+) -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    let schema = graphql::schema(cache);
+
+    warp::path!("graphql")
+        .and(warp::ws())
+        .and(auth::require(Scope::Stream, Arc::clone(&config)))
+        .and(async_graphql_warp::graphql_protocol())
+        .and(auth::visibility(Arc::clone(&config)))
+        .and(limits::stream(config))
+        .and(warp::any().map(move || schema.clone()))
+        .and_then(handlers::graphql_subscriptions)
+}
+
+/// Route defining the read-only endpoint describing the API as an OpenAPI
+/// document, available without authentication.
+///
+/// See also: [`handlers::openapi`].
+pub fn openapi() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path!("openapi.json")
+        .and(warp::get())
+        .and_then(handlers::openapi)
+}
+
+/// Route defining the web dashboard served at the root, browsing the
+/// processes through the other routes, and thus available without
+/// authentication.
+///
+/// See also: [`handlers::dashboard`].
+pub fn dashboard() -> impl Filter<Extract = (impl warp::Reply,), Error = warp::Rejection> + Clone {
+    warp::path::end()
+        .and(warp::get())
+        .and_then(handlers::dashboard)
+}
+
+/// Convenience shortcut to add the configuration as an argument of the handlers
+/// needing it.
+fn with_config(
+    config: SharedConfig,
+) -> impl Filter<Extract = (SharedConfig,), Error = Infallible> + Clone {
+    warp::any().map(move || Arc::clone(&config))
+}
+
 /// Convenience shortcut to add the current cache as an argument of each handler.
 fn with_cache(cache: ProcCache) -> impl Filter<Extract = (ProcCache,), Error = Infallible> + Clone {
     warp::any().map(move || Arc::clone(&cache))
@@ -0,0 +1,430 @@
+//! This module defines how the API is served: the listeners accepting
+//! connections, over TCP, optionally with TLS, or over a Unix socket, and what
+//! is known about the peer of each connection, made available to the
+//! [`crate::handlers`] through [`peer`].
+
+use std::convert::Infallible;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::num::ParseIntError;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use anyhow::{anyhow, bail, Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::unix::UCred;
+use tokio::net::{TcpListener, UnixListener};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::time::{self, Duration};
+use tokio_rustls::rustls::server::{AllowAnyAuthenticatedClient, ServerConnection};
+use tokio_rustls::rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+use warp::http::header::USER_AGENT;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{Body, Request};
+use warp::reply::Response;
+use warp::{Filter, Reply};
+
+/// Time to wait before accepting connections again after failing to, as such
+/// failures are usually caused by a lack of resources.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Maximum time for clients to complete the TLS handshake, after which their
+/// connection is closed instead of being held open.
+const TLS_HANDSHAKE_TOUT: Duration = Duration::from_secs(10);
+
+/// What is known about the peer of a connection, inserted into the extensions
+/// of each of its requests.
+#[derive(Debug, Clone, Default)]
+pub struct Peer {
+    /// The remote address of the peer, when it connected over TCP.
+    pub addr: Option<SocketAddr>,
+    /// The credentials of the peer process, when it connected over a Unix
+    /// socket.
+    pub cred: Option<UCred>,
+    /// The subject of the verified certificate of the peer, when it connected
+    /// using mutual TLS.
+    pub subject: Option<String>,
+}
+
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let origin = match (self.addr, self.cred) {
+            (Some(addr), _) => addr.to_string(),
+            (None, Some(cred)) => format!("uid {}", cred.uid()),
+            (None, None) => "unknown peer".to_owned(),
+        };
+
+        match &self.subject {
+            Some(subject) => write!(f, "{subject} ({origin})"),
+            None => f.write_str(&origin),
+        }
+    }
+}
+
+/// Filter extracting the [`Peer`] of the request's connection.
+pub fn peer() -> impl Filter<Extract = (Peer,), Error = Infallible> + Clone {
+    warp::ext::optional::<Peer>().map(Option::unwrap_or_default)
+}
+
+/// The TLS configuration of a listener, loaded from files and reloadable at
+/// runtime without interrupting the established connections.
+#[derive(Debug)]
+pub struct Tls {
+    cert: PathBuf,
+    key: PathBuf,
+    client_ca: Option<PathBuf>,
+    config: RwLock<Arc<ServerConfig>>,
+}
+
+impl Tls {
+    /// Loads the certificate chain and private key at the given paths, and
+    /// requires clients to present a certificate issued by one of the
+    /// authorities at the given path if any.
+    pub fn load(
+        cert: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+        client_ca: Option<PathBuf>,
+    ) -> Result<Arc<Self>> {
+        let (cert, key) = (cert.into(), key.into());
+        let config = Self::build(&cert, &key, client_ca.as_deref())?;
+        info!("TLS enabled with certificate {cert:?}.");
+
+        Ok(Arc::new(Self {
+            cert,
+            key,
+            client_ca,
+            config: RwLock::new(Arc::new(config)),
+        }))
+    }
+
+    /// Loads the files again, the new configuration being used by the
+    /// connections accepted from then on. On failure, the previous one is
+    /// kept.
+    pub fn reload(&self) -> Result<()> {
+        let config = Self::build(&self.cert, &self.key, self.client_ca.as_deref())?;
+        *self.config.write().unwrap() = Arc::new(config);
+        info!("TLS certificate {:?} reloaded.", self.cert);
+        Ok(())
+    }
+
+    /// Reloads the files each time the process receives `SIGHUP`.
+    pub fn reload_on_hangup(self: &Arc<Self>) -> Result<()> {
+        let mut hangups = signal(SignalKind::hangup())?;
+        let tls = Arc::clone(self);
+
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                if let Err(err) = tls.reload() {
+                    error!("Unable to reload TLS configuration: {err:#}");
+                }
+            }
+        });
+        Ok(())
+    }
+
+    /// Returns an acceptor using the current configuration.
+    fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(Arc::clone(&self.config.read().unwrap()))
+    }
+
+    /// Builds the configuration out of the files at the given paths.
+    fn build(cert: &Path, key: &Path, client_ca: Option<&Path>) -> Result<ServerConfig> {
+        let certs = read_certs(cert)?;
+        let key = read_key(key)?;
+        let builder = ServerConfig::builder().with_safe_defaults();
+
+        let builder = match client_ca {
+            None => builder.with_no_client_auth(),
+            Some(client_ca) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(client_ca)? {
+                    roots.add(&cert).with_context(|| {
+                        format!("Invalid client certificate authority in {client_ca:?}.")
+                    })?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+        };
+
+        let mut config = builder
+            .with_single_cert(certs, key)
+            .context("Invalid TLS certificate or key.")?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        Ok(config)
+    }
+}
+
+/// Reads all the PEM certificates of the file at the given path.
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).with_context(|| format!("Unable to open {path:?}."))?;
+    let certs = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Unable to read certificates from {path:?}."))?;
+
+    if certs.is_empty() {
+        bail!("No certificate found in {path:?}.");
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Reads the first PEM private key of the file at the given path.
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let file = File::open(path).with_context(|| format!("Unable to open {path:?}."))?;
+    let items = rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Unable to read private key from {path:?}."))?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .with_context(|| format!("No private key found in {path:?}."))
+}
+
+/// Returns the subject of the verified certificate of the client, if any.
+fn client_subject(conn: &ServerConnection) -> Option<String> {
+    let cert = conn.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    Some(cert.subject().to_string())
+}
+
+/// Parses an octal permission mode, such as `660`.
+pub fn parse_mode(mode: &str) -> Result<u32, ParseIntError> {
+    u32::from_str_radix(mode, 8)
+}
+
+/// The owner to give to a Unix socket, as `UID[:GID]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOwner {
+    pub uid: u32,
+    pub gid: Option<u32>,
+}
+
+impl FromStr for SocketOwner {
+    type Err = ParseIntError;
+
+    fn from_str(owner: &str) -> Result<Self, Self::Err> {
+        Ok(match owner.split_once(':') {
+            Some((uid, gid)) => Self {
+                uid: uid.parse()?,
+                gid: Some(gid.parse()?),
+            },
+            None => Self {
+                uid: owner.parse()?,
+                gid: None,
+            },
+        })
+    }
+}
+
+/// Binds a Unix socket at the given path, with the given permission mode and
+/// owner if any.
+///
+/// The socket is bound in a private directory next to the path, then moved to
+/// it once its mode and owner are set, so that it never accepts connections
+/// with the looser permissions given by the umask.
+///
+/// A socket left at the path by a previous server that did not stop
+/// gracefully is removed first, but one still accepting connections is
+/// reported as an error, as is any other kind of file.
+pub fn bind_unix(
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<SocketOwner>,
+) -> Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+        Ok(meta) if !meta.file_type().is_socket() => {
+            bail!("{path:?} already exists and is not a socket.")
+        }
+        Ok(_) => match std::os::unix::net::UnixStream::connect(path) {
+            Ok(_) => bail!("{path:?} is already in use."),
+            Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => {
+                info!("Removing stale socket {path:?}.");
+                fs::remove_file(path)?;
+            }
+            Err(err) => return Err(err.into()),
+        },
+    }
+
+    let Some(name) = path.file_name() else {
+        bail!("{path:?} is not a file path.");
+    };
+    let mut hidden = OsString::from(".");
+    hidden.push(name);
+    hidden.push(format!(".{}", std::process::id()));
+    let private = path.with_file_name(hidden);
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .with_context(|| format!("Unable to create directory {private:?}."))?;
+
+    let res = bind_private(&private.join(name), path, mode, owner);
+    fs::remove_dir_all(&private)?;
+    let listener = res?;
+    info!("Listening on unix:{}.", path.display());
+    Ok(listener)
+}
+
+/// Binds a Unix socket at the given private path, sets its permission mode and
+/// owner, then moves it to the given public path.
+fn bind_private(
+    private: &Path,
+    path: &Path,
+    mode: Option<u32>,
+    owner: Option<SocketOwner>,
+) -> Result<UnixListener> {
+    let listener =
+        UnixListener::bind(private).with_context(|| format!("Unable to bind socket {path:?}."))?;
+    if let Some(mode) = mode {
+        fs::set_permissions(private, fs::Permissions::from_mode(mode))?;
+    }
+    if let Some(owner) = owner {
+        std::os::unix::fs::chown(private, Some(owner.uid), owner.gid)
+            .with_context(|| format!("Unable to change the owner of socket {path:?}."))?;
+    }
+    fs::rename(private, path).with_context(|| format!("Unable to move socket to {path:?}."))?;
+    Ok(listener)
+}
+
+/// Serves the given filter on the connections accepted by the Unix socket
+/// listener, forever.
+pub async fn serve_unix<F>(listener: UnixListener, filter: F) -> Result<()>
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let service = warp::service(filter);
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(err) => {
+                warn!("Unable to accept connection: {err}");
+                time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let peer = Peer {
+            cred: stream.peer_cred().ok(),
+            ..Default::default()
+        };
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            if let Err(err) = serve_connection(stream, peer.clone(), service).await {
+                debug!("Connection from {peer} failed: {err:#}");
+            }
+        });
+    }
+}
+
+/// Serves the given filter on the connections accepted by the TCP listener,
+/// over TLS if configured, forever.
+pub async fn serve_tcp<F>(listener: TcpListener, tls: Option<Arc<Tls>>, filter: F) -> Result<()>
+where
+    F: Filter<Error = warp::Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    info!(
+        "Listening on {}://{}.",
+        if tls.is_some() { "https" } else { "http" },
+        listener.local_addr()?
+    );
+    let service = warp::service(filter);
+
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Unable to accept connection: {err}");
+                time::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let acceptor = tls.as_ref().map(|tls| tls.acceptor());
+        let service = service.clone();
+
+        tokio::spawn(async move {
+            let mut peer = Peer {
+                addr: Some(addr),
+                ..Default::default()
+            };
+            let res = match acceptor {
+                None => serve_connection(stream, peer, service).await,
+                Some(acceptor) => {
+                    match time::timeout(TLS_HANDSHAKE_TOUT, acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            peer.subject = client_subject(stream.get_ref().1);
+                            serve_connection(stream, peer, service).await
+                        }
+                        Ok(Err(err)) => Err(err.into()),
+                        Err(_) => Err(anyhow!(
+                            "No TLS handshake within {} seconds.",
+                            TLS_HANDSHAKE_TOUT.as_secs()
+                        )),
+                    }
+                }
+            };
+
+            if let Err(err) = res {
+                debug!("Connection from {addr} failed: {err:#}");
+            }
+        });
+    }
+}
+
+/// Serves the given service on a single connection, inserting its peer into
+/// the extensions of each request and logging each request once answered, as
+/// [`warp::log`] would.
+async fn serve_connection<I, S>(io: I, peer: Peer, service: S) -> Result<()>
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Service<Request<Body>, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    let service = service_fn(move |mut req: Request<Body>| {
+        let start = Instant::now();
+        let line = format!(
+            "{} \"{} {} {:?}\"",
+            peer,
+            req.method(),
+            req.uri().path(),
+            req.version()
+        );
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|agent| agent.to_str().ok())
+            .unwrap_or("-")
+            .to_owned();
+        req.extensions_mut().insert(peer.clone());
+        let res = service.clone().call(req);
+
+        async move {
+            let res = res.await?;
+            info!(
+                "{line} {} \"{user_agent}\" {:?}",
+                res.status().as_u16(),
+                start.elapsed()
+            );
+            Ok::<_, Infallible>(res)
+        }
+    });
+
+    Http::new()
+        .serve_connection(io, service)
+        .with_upgrades()
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,265 @@
+//! This module defines where the processes stored in the cache come from: the
+//! [`ProcSource`] trait and its implementations.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Context, Result};
+use sysinfo::{Pid, PidExt, ProcessExt, ProcessRefreshKind, System, SystemExt};
+
+use crate::model::Signal;
+use crate::proc::{CacheData, ProcInfo, ProcResources, Snapshot};
+use crate::store;
+
+/// A collector of the processes to be stored in the cache at each refresh.
+pub trait ProcSource: fmt::Debug + Send + Sync {
+    /// Collects all the processes visible from the source, blocking by nature.
+    fn collect(&mut self) -> Result<CacheData>;
+
+    /// Collects the current resource usage of the processes visible from the
+    /// source, blocking by nature. Sources that do not support it return
+    /// nothing, which is the default.
+    fn resources(&self) -> Result<Vec<ProcResources>> {
+        Ok(Vec::new())
+    }
+
+    /// Sends the given signal to the given process, blocking by nature.
+    /// Returns whether the process still existed, as it was collected. Sources
+    /// that do not support it fail, which is the default.
+    fn signal(&self, _proc: &ProcInfo, _signal: Signal) -> Result<bool> {
+        Err(anyhow!("Signals are not supported by this source."))
+    }
+}
+
+/// The default source: the processes currently running on the host.
+///
+/// See also: [`ProcInfo::collect_all`].
+#[derive(Debug, Default)]
+pub struct SysinfoSource {
+    /// Kept between collections of resources so that CPU usages are computed
+    /// over the time elapsed since the previous one.
+    sys: Mutex<System>,
+}
+
+impl ProcSource for SysinfoSource {
+    fn collect(&mut self) -> Result<CacheData> {
+        ProcInfo::collect_all()
+    }
+
+    fn resources(&self) -> Result<Vec<ProcResources>> {
+        debug!("Collecting resources...");
+        let mut sys = self.sys.lock().unwrap();
+        sys.refresh_processes_specifics(ProcessRefreshKind::new().with_cpu());
+
+        Ok(sys
+            .processes()
+            .iter()
+            .map(|(pid, proc)| ProcResources {
+                pid: pid.as_u32(),
+                memory: proc.memory(),
+                virtual_memory: proc.virtual_memory(),
+                cpu_usage: proc.cpu_usage(),
+            })
+            .collect())
+    }
+
+    fn signal(&self, proc: &ProcInfo, signal: Signal) -> Result<bool> {
+        let pid = Pid::from_u32(proc.pid);
+        let mut sys = self.sys.lock().unwrap();
+
+        if !sys.refresh_process(pid) {
+            return Ok(false);
+        }
+        // PIDs are reused: make sure that the process is still the collected
+        // one, and not one of another user.
+        let Some(live) = sys
+            .process(pid)
+            .filter(|live| live.user_id().map(|uid| **uid) == Some(proc.uid))
+            .filter(|live| live.name() == proc.name)
+        else {
+            return Ok(false);
+        };
+        let sent = live.kill_with(match signal {
+            Signal::Hup => sysinfo::Signal::Hangup,
+            Signal::Int => sysinfo::Signal::Interrupt,
+            Signal::Quit => sysinfo::Signal::Quit,
+            Signal::Kill => sysinfo::Signal::Kill,
+            Signal::Usr1 => sysinfo::Signal::User1,
+            Signal::Usr2 => sysinfo::Signal::User2,
+            Signal::Term => sysinfo::Signal::Term,
+            Signal::Cont => sysinfo::Signal::Continue,
+            Signal::Stop => sysinfo::Signal::Stop,
+        });
+
+        match sent {
+            Some(true) => Ok(true),
+            Some(false) => Err(anyhow!("Unable to send {signal} to process {pid}.")),
+            None => Err(anyhow!("{signal} is not supported on this platform.")),
+        }
+    }
+}
+
+/// A source recording everything collected by another one to a file, in the
+/// format of the [`crate::store`], so that it can be replayed later on using
+/// a [`ReplaySource`].
+#[derive(Debug)]
+pub struct RecordingSource<S> {
+    inner: S,
+    writer: BufWriter<File>,
+    count: u64,
+}
+
+impl<S: ProcSource> RecordingSource<S> {
+    /// Wraps the given source, recording to a file created at the given path,
+    /// or truncated if it already exists.
+    pub fn new(inner: S, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let mut writer = BufWriter::new(
+            File::create(path).with_context(|| format!("Unable to create record {path:?}."))?,
+        );
+        store::write_header(&mut writer)?;
+        writer.flush()?;
+        info!("Recording refreshes to {path:?}.");
+
+        Ok(Self {
+            inner,
+            writer,
+            count: 0,
+        })
+    }
+}
+
+impl<S: ProcSource> ProcSource for RecordingSource<S> {
+    fn collect(&mut self) -> Result<CacheData> {
+        let procs = self.inner.collect()?;
+        self.count += 1;
+        debug!("Recording refresh {}...", self.count);
+
+        let snap = Snapshot::new(self.count, procs);
+        store::write_record(&mut self.writer, &snap)?;
+        // Flush each time so that the record is usable even if the server is
+        // not stopped gracefully.
+        self.writer.flush()?;
+        Ok(snap.procs)
+    }
+
+    fn resources(&self) -> Result<Vec<ProcResources>> {
+        self.inner.resources()
+    }
+
+    fn signal(&self, proc: &ProcInfo, signal: Signal) -> Result<bool> {
+        self.inner.signal(proc, signal)
+    }
+}
+
+/// A source replaying a record made by a [`RecordingSource`]: each collection
+/// returns the next recorded one, until the end of the record is reached.
+#[derive(Debug)]
+pub struct ReplaySource {
+    snaps: VecDeque<Snapshot>,
+}
+
+impl ReplaySource {
+    /// Loads the record at the given path.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("Unable to open record {path:?}."))?;
+        let snaps = store::decode(BufReader::new(file))
+            .with_context(|| format!("Unable to load record {path:?}."))?;
+        info!("Replaying {} refreshes from {path:?}.", snaps.len());
+
+        Ok(Self {
+            snaps: snaps.into(),
+        })
+    }
+}
+
+impl ProcSource for ReplaySource {
+    fn collect(&mut self) -> Result<CacheData> {
+        let snap = self
+            .snaps
+            .pop_front()
+            .ok_or_else(|| anyhow!("The end of the replay has been reached."))?;
+        debug!("Replaying refresh {}.", snap.id);
+        Ok(snap.procs)
+    }
+}
+
+/// An in-memory source whose processes are scripted by hand, mainly in order
+/// to drive exact spawn and exit sequences during testing.
+///
+/// Clones share the same state, so one can be given to the cache while
+/// another one is kept in order to script it.
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedSource {
+    state: Arc<Mutex<ScriptedState>>,
+}
+
+/// The shared state of a [`ScriptedSource`].
+#[derive(Debug, Default)]
+struct ScriptedState {
+    procs: CacheData,
+    failure: Option<String>,
+    signals: Vec<(u32, Signal)>,
+}
+
+impl ScriptedSource {
+    /// Adds the given process, replacing any previous one of the same PID.
+    pub fn spawn(&self, proc: ProcInfo) -> &Self {
+        let mut state = self.state.lock().unwrap();
+        state.procs.retain(|other| other.pid != proc.pid);
+        state.procs.insert(proc);
+        self
+    }
+
+    /// Removes the process of the given PID, if any.
+    pub fn exit(&self, pid: u32) -> &Self {
+        self.state
+            .lock()
+            .unwrap()
+            .procs
+            .retain(|proc| proc.pid != pid);
+        self
+    }
+
+    /// Makes the next collection fail with the given message, the following
+    /// ones being unaffected.
+    pub fn fail_next(&self, msg: impl Into<String>) -> &Self {
+        self.state.lock().unwrap().failure = Some(msg.into());
+        self
+    }
+
+    /// Returns the signals sent to processes so far, by PID, in order.
+    pub fn signals(&self) -> Vec<(u32, Signal)> {
+        self.state.lock().unwrap().signals.clone()
+    }
+}
+
+impl ProcSource for ScriptedSource {
+    fn collect(&mut self) -> Result<CacheData> {
+        let mut state = self.state.lock().unwrap();
+
+        match state.failure.take() {
+            Some(msg) => Err(anyhow!(msg)),
+            None => Ok(state.procs.clone()),
+        }
+    }
+
+    /// Records the signal, the process exiting if it is `KILL` or `TERM`.
+    fn signal(&self, proc: &ProcInfo, signal: Signal) -> Result<bool> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.procs.contains(proc) {
+            return Ok(false);
+        }
+        state.signals.push((proc.pid, signal));
+        if matches!(signal, Signal::Kill | Signal::Term) {
+            state.procs.remove(proc);
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,220 @@
+//! This module defines the on-disk representation of [`Snapshot`]s, used both
+//! to persist the cache's history across restarts and to move snapshots
+//! between hosts.
+//!
+//! The format is a header made of [`MAGIC`] and [`VERSION`], followed by any
+//! number of records, each being a little-endian `u32` length and then the
+//! snapshot itself encoded with `bincode`. Records can thus be appended to an
+//! existing file without having to rewrite it.
+//!
+//! Files of the previous version, written before processes had a parent, are
+//! still read, their processes having none.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+use crate::proc::{ProcInfo, Snapshot};
+
+/// Identifies the format at the start of the header.
+const MAGIC: &[u8; 7] = b"PROCAPI";
+/// Current version of the format, bumped at each incompatible change.
+const VERSION: u8 = 2;
+/// Version of the format before processes had a parent.
+const VERSION_NO_PPID: u8 = 1;
+/// Maximum accepted size of a single record, as a sanity check.
+const RECORD_LIMIT: u32 = 1 << 30;
+
+/// Writes the format's header, to be done once before any record.
+pub fn write_header(mut writer: impl Write) -> Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+    Ok(())
+}
+
+/// Writes a single snapshot record.
+pub fn write_record(mut writer: impl Write, snap: &Snapshot) -> Result<()> {
+    let bytes = bincode::serialize(snap)?;
+    writer.write_all(&u32::try_from(bytes.len())?.to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads and checks the format's header, returning the version of the format.
+pub fn read_header(mut reader: impl Read) -> Result<u8> {
+    let mut magic = [0; MAGIC.len() + 1];
+    reader
+        .read_exact(&mut magic)
+        .context("Missing snapshot header.")?;
+
+    if magic[..MAGIC.len()] != MAGIC[..] {
+        bail!("Not a snapshot file.");
+    }
+    match magic[MAGIC.len()] {
+        version @ (VERSION | VERSION_NO_PPID) => Ok(version),
+        version => bail!("Unsupported snapshot format version {version}."),
+    }
+}
+
+/// A snapshot as encoded by [`VERSION_NO_PPID`].
+#[derive(Deserialize)]
+struct SnapshotNoPpid {
+    id: u64,
+    time: SystemTime,
+    procs: Vec<ProcInfoNoPpid>,
+}
+
+/// A process as encoded by [`VERSION_NO_PPID`].
+#[derive(Deserialize)]
+struct ProcInfoNoPpid {
+    pid: u32,
+    uid: u32,
+    name: String,
+    username: String,
+}
+
+impl From<SnapshotNoPpid> for Snapshot {
+    fn from(old: SnapshotNoPpid) -> Self {
+        let procs = old.procs.into_iter().map(|proc| ProcInfo {
+            pid: proc.pid,
+            ppid: None,
+            uid: proc.uid,
+            name: proc.name,
+            username: proc.username,
+        });
+        let mut snap = Snapshot::new(old.id, procs.collect());
+        snap.time = old.time;
+        snap
+    }
+}
+
+/// Reads the next snapshot record of the given version of the format, if any
+/// before the end of the input.
+pub fn read_record(mut reader: impl Read, version: u8) -> Result<Option<Snapshot>> {
+    let mut len = [0; 4];
+
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+
+    let len = u32::from_le_bytes(len);
+    if len > RECORD_LIMIT {
+        bail!("Snapshot record too large: {len} bytes.");
+    }
+
+    // Only grow the buffer with the bytes actually read, so that a length
+    // claimed by a short input does not allocate it in full.
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len.into()).read_to_end(&mut bytes)?;
+    if bytes.len() < len as usize {
+        bail!("Truncated snapshot record.");
+    }
+    Ok(Some(if version == VERSION_NO_PPID {
+        bincode::deserialize::<SnapshotNoPpid>(&bytes)?.into()
+    } else {
+        bincode::deserialize(&bytes)?
+    }))
+}
+
+/// Encodes the given snapshots into a complete in-memory file.
+pub fn encode<'a>(snaps: impl IntoIterator<Item = &'a Snapshot>) -> Result<Vec<u8>> {
+    let mut res = Vec::new();
+    write_header(&mut res)?;
+
+    for snap in snaps {
+        write_record(&mut res, snap)?;
+    }
+
+    Ok(res)
+}
+
+/// Decodes all the snapshots of a complete file.
+pub fn decode(mut reader: impl Read) -> Result<Vec<Snapshot>> {
+    let mut res = Vec::new();
+    let version = read_header(&mut reader)?;
+
+    while let Some(snap) = read_record(&mut reader, version)? {
+        res.push(snap);
+    }
+
+    Ok(res)
+}
+
+/// A directory where the snapshots of the cache's history are persisted, one
+/// file per snapshot.
+#[derive(Debug)]
+pub struct StateDir {
+    path: PathBuf,
+}
+
+impl StateDir {
+    /// Extension of the snapshot files in the directory.
+    const EXT: &'static str = "snap";
+
+    /// Opens the directory at the given path, creating it if necessary.
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)
+            .with_context(|| format!("Unable to create state directory {path:?}."))?;
+        debug!("State directory opened at {path:?}.");
+        Ok(Self { path })
+    }
+
+    /// Loads all the snapshots persisted in the directory, sorted by ID.
+    pub fn load(&self) -> Result<Vec<Snapshot>> {
+        debug!("Loading snapshots from {:?}...", self.path);
+        let mut res = Vec::new();
+
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+
+            if path.extension().is_some_and(|ext| ext == Self::EXT) {
+                let file = File::open(&path)?;
+                res.extend(
+                    decode(BufReader::new(file))
+                        .with_context(|| format!("Unable to load snapshot file {path:?}."))?,
+                );
+            }
+        }
+
+        res.sort_unstable_by_key(|snap| snap.id);
+        debug!("Loaded {} snapshots.", res.len());
+        Ok(res)
+    }
+
+    /// Persists the given snapshot, atomically replacing any previous one of
+    /// the same ID.
+    pub fn save(&self, snap: &Snapshot) -> Result<()> {
+        debug!("Saving snapshot {}...", snap.id);
+        let path = self.snapshot_path(snap.id);
+        let tmp = path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+
+        write_header(&mut writer)?;
+        write_record(&mut writer, snap)?;
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+
+    /// Removes the snapshot of the given ID, if it is persisted.
+    pub fn remove(&self, id: u64) -> Result<()> {
+        debug!("Removing snapshot {id}...");
+
+        match fs::remove_file(self.snapshot_path(id)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the path of the file persisting the snapshot of the given ID.
+    fn snapshot_path(&self, id: u64) -> PathBuf {
+        self.path.join(format!("{id:020}.{}", Self::EXT))
+    }
+}
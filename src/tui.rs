@@ -0,0 +1,474 @@
+//! This module defines the `tui` command: a `top`-like view of the processes
+//! of a remote server, updated by its stream and resynchronized with its list
+//! at each (re)connection, see [`App`] for its state and keys.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use anyhow::Result;
+use crossterm::event::{
+    Event as TermEvent, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers,
+};
+use futures_util::StreamExt;
+use proc_api::client::{Client, ClientError, Event};
+use proc_api::model::{CacheData, ProcInfo, Signal};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Clear, List, ListState, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use reqwest::StatusCode;
+use tokio::sync::mpsc;
+
+use crate::commands::RemoteArgs;
+
+/// The keys of the normal mode, shown in the footer.
+const HELP: &str = "q quit  ↑↓ select  s sort  r reverse  u user  / name  c clear  k signal";
+
+/// The arguments of the `tui` command.
+#[derive(clap::Args, Debug, PartialEq, Eq)]
+pub struct TuiArgs {
+    #[command(flatten)]
+    pub remote: RemoteArgs,
+    /// Ask the server to refresh its processes at this interval, e.g. `2s`,
+    /// or never if `0s`. Stops asking if the token lacks the `refresh` scope.
+    #[arg(long, value_name = "DURATION", default_value = "2s", value_parser = humantime::parse_duration)]
+    pub interval: Duration,
+}
+
+/// A column of the table, by which it can be sorted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Column {
+    Pid,
+    Ppid,
+    Uid,
+    User,
+    Name,
+}
+
+impl Column {
+    /// All the columns, in display order.
+    pub const ALL: [Self; 5] = [Self::Pid, Self::Ppid, Self::Uid, Self::User, Self::Name];
+
+    /// Returns the header of the column.
+    pub fn title(self) -> &'static str {
+        match self {
+            Self::Pid => "PID",
+            Self::Ppid => "PPID",
+            Self::Uid => "UID",
+            Self::User => "USER",
+            Self::Name => "NAME",
+        }
+    }
+
+    /// Returns the column after this one, wrapping around.
+    fn next(self) -> Self {
+        let i = Self::ALL.iter().position(|&column| column == self).unwrap();
+        Self::ALL[(i + 1) % Self::ALL.len()]
+    }
+}
+
+/// A filter being edited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field {
+    User,
+    Name,
+}
+
+/// What keys currently do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    /// Moving, sorting and opening the other modes.
+    Normal,
+    /// Typing the given filter.
+    Filter(Field),
+    /// Choosing the signal to send to the selected process, the given index of
+    /// [`Signal::ALL`] being selected.
+    Signal(usize),
+}
+
+/// What the loop of [`tui`] must do after a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Quit,
+    /// Send the given signal to the process of the given PID.
+    Signal(u32, Signal),
+}
+
+/// The state of the terminal UI, independent of the terminal and the server.
+#[derive(Debug)]
+pub struct App {
+    host: String,
+    procs: BTreeMap<u32, ProcInfo>,
+    sort: Column,
+    reverse: bool,
+    user: String,
+    name: String,
+    mode: Mode,
+    /// The PID of the selected process, kept across updates and sorts.
+    selected: Option<u32>,
+    table: TableState,
+    status: String,
+}
+
+impl App {
+    /// Builds the state of the view of the given server, without processes.
+    pub fn new(host: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            procs: BTreeMap::new(),
+            sort: Column::Pid,
+            reverse: false,
+            user: String::new(),
+            name: String::new(),
+            mode: Mode::Normal,
+            selected: None,
+            table: TableState::default(),
+            status: "Connecting...".to_owned(),
+        }
+    }
+
+    /// Returns the PID of the selected process, if any is shown.
+    pub fn selected(&self) -> Option<u32> {
+        let rows = self.rows();
+        self.index(&rows).map(|i| rows[i].pid)
+    }
+
+    /// Tells the user what happened.
+    pub fn set_status(&mut self, status: impl Into<String>) {
+        self.status = status.into();
+    }
+
+    /// Replaces the processes by the given ones, e.g. at each connection.
+    pub fn resync(&mut self, procs: CacheData) {
+        self.procs = procs.into_iter().map(|proc| (proc.pid, proc)).collect();
+        self.set_status(format!("Connected to {}.", self.host));
+    }
+
+    /// Applies the given event of the stream.
+    pub fn apply(&mut self, event: Event) {
+        match event {
+            Event::Connected => {}
+            Event::Process(proc) => {
+                self.procs.insert(proc.pid, proc);
+            }
+            Event::Removed(proc) => {
+                // The PID may already be reused by a newer process.
+                if self.procs.get(&proc.pid) == Some(&proc) {
+                    self.procs.remove(&proc.pid);
+                }
+            }
+        }
+    }
+
+    /// Returns the processes matching the filters, sorted.
+    pub fn rows(&self) -> Vec<&ProcInfo> {
+        let user = self.user.to_lowercase();
+        let name = self.name.to_lowercase();
+        let mut rows = self
+            .procs
+            .values()
+            .filter(|proc| proc.username.to_lowercase().contains(&user))
+            .filter(|proc| proc.name.to_lowercase().contains(&name))
+            .collect::<Vec<_>>();
+
+        // The processes are sorted by PID already: stable sorts keep them so
+        // among equal keys.
+        match self.sort {
+            Column::Pid => {}
+            Column::Ppid => rows.sort_by_key(|proc| proc.ppid),
+            Column::Uid => rows.sort_by_key(|proc| proc.uid),
+            Column::User => rows.sort_by(|a, b| a.username.cmp(&b.username)),
+            Column::Name => rows.sort_by(|a, b| a.name.cmp(&b.name)),
+        }
+        if self.reverse {
+            rows.reverse();
+        }
+        rows
+    }
+
+    /// Handles the given key, returning what the loop must do, if anything.
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<Action> {
+        if key.kind == KeyEventKind::Release {
+            return None;
+        }
+        if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('c') {
+            return Some(Action::Quit);
+        }
+
+        match self.mode {
+            Mode::Normal => return self.handle_normal(key.code),
+            Mode::Filter(field) => {
+                let filter = match field {
+                    Field::User => &mut self.user,
+                    Field::Name => &mut self.name,
+                };
+                match key.code {
+                    KeyCode::Enter | KeyCode::Esc => self.mode = Mode::Normal,
+                    KeyCode::Backspace => {
+                        filter.pop();
+                    }
+                    KeyCode::Char(c) => filter.push(c),
+                    _ => {}
+                }
+            }
+            Mode::Signal(i) => match key.code {
+                KeyCode::Up | KeyCode::Char('k') => self.mode = Mode::Signal(i.saturating_sub(1)),
+                KeyCode::Down | KeyCode::Char('j') => {
+                    self.mode = Mode::Signal((i + 1).min(Signal::ALL.len() - 1));
+                }
+                KeyCode::Enter => {
+                    self.mode = Mode::Normal;
+                    return self
+                        .selected()
+                        .map(|pid| Action::Signal(pid, Signal::ALL[i]));
+                }
+                KeyCode::Esc | KeyCode::Char('q') => self.mode = Mode::Normal,
+                _ => {}
+            },
+        }
+        None
+    }
+
+    /// Handles the given key of the normal mode.
+    fn handle_normal(&mut self, code: KeyCode) -> Option<Action> {
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc => return Some(Action::Quit),
+            KeyCode::Up => self.move_by(-1),
+            KeyCode::Down => self.move_by(1),
+            KeyCode::PageUp => self.move_by(-20),
+            KeyCode::PageDown => self.move_by(20),
+            KeyCode::Home => self.move_by(isize::MIN),
+            KeyCode::End => self.move_by(isize::MAX),
+            KeyCode::Char('s') => self.sort = self.sort.next(),
+            KeyCode::Char('r') => self.reverse = !self.reverse,
+            KeyCode::Char('u') => self.mode = Mode::Filter(Field::User),
+            KeyCode::Char('/') => self.mode = Mode::Filter(Field::Name),
+            KeyCode::Char('c') => {
+                self.user.clear();
+                self.name.clear();
+            }
+            KeyCode::Char('k') if self.selected().is_some() => {
+                // TERM by default, as by `kill`.
+                let term = Signal::ALL.iter().position(|&s| s == Signal::Term);
+                self.mode = Mode::Signal(term.unwrap_or_default());
+            }
+            _ => {}
+        }
+        None
+    }
+
+    /// Returns the index of the selected process among the given rows: the
+    /// first one if it is not shown anymore.
+    fn index(&self, rows: &[&ProcInfo]) -> Option<usize> {
+        if rows.is_empty() {
+            return None;
+        }
+        let pid = self.selected?;
+        Some(rows.iter().position(|proc| proc.pid == pid).unwrap_or(0))
+    }
+
+    /// Moves the selection by the given number of rows, within bounds.
+    fn move_by(&mut self, delta: isize) {
+        let rows = self.rows();
+        let Some(last) = rows.len().checked_sub(1) else {
+            return;
+        };
+        let i = match self.index(&rows) {
+            Some(i) => i.saturating_add_signed(delta).min(last),
+            None => 0,
+        };
+        self.selected = Some(rows[i].pid);
+    }
+
+    /// Draws the view on the given frame.
+    pub fn draw(&mut self, frame: &mut Frame) {
+        let [title, body, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(0),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        let rows = self.rows();
+        let index = self.index(&rows);
+        let pid = index.map(|i| rows[i].pid).unwrap_or_default();
+        let arrow = if self.reverse { "▼" } else { "▲" };
+        let header = Row::new(Column::ALL.map(|column| {
+            if column == self.sort {
+                format!("{}{arrow}", column.title())
+            } else {
+                column.title().to_owned()
+            }
+        }))
+        .bold();
+        let cells = rows
+            .iter()
+            .map(|proc| {
+                Row::new([
+                    proc.pid.to_string(),
+                    proc.ppid
+                        .map_or_else(|| "-".to_owned(), |ppid| ppid.to_string()),
+                    proc.uid.to_string(),
+                    proc.username.clone(),
+                    proc.name.clone(),
+                ])
+            })
+            .collect::<Vec<_>>();
+        let summary = format!(
+            "{} — {} processes, {} shown",
+            self.host,
+            self.procs.len(),
+            rows.len(),
+        );
+        let table = Table::new(
+            cells,
+            [
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(16),
+                Constraint::Min(0),
+            ],
+        )
+        .header(header)
+        .row_highlight_style(Style::new().reversed());
+
+        frame.render_widget(Line::from(summary).bold(), title);
+        self.table.select(index);
+        frame.render_stateful_widget(table, body, &mut self.table);
+        frame.render_widget(Line::from(self.footer()), footer);
+
+        if let Mode::Signal(i) = self.mode {
+            let items = Signal::ALL.map(|signal| signal.name());
+            let area = centered(frame.area(), 24, items.len() as u16 + 2);
+            let list = List::new(items)
+                .block(Block::bordered().title(format!(" Signal {pid} ")))
+                .highlight_style(Style::new().reversed());
+
+            frame.render_widget(Clear, area);
+            frame.render_stateful_widget(
+                list,
+                area,
+                &mut ListState::default().with_selected(Some(i)),
+            );
+        }
+    }
+
+    /// Returns the footer: the filter being edited, or the active filters,
+    /// status and keys.
+    fn footer(&self) -> String {
+        match self.mode {
+            Mode::Filter(Field::User) => format!("User: {}▏ (Enter to apply)", self.user),
+            Mode::Filter(Field::Name) => format!("Name: {}▏ (Enter to apply)", self.name),
+            Mode::Signal(_) => "↑↓ select  Enter send  Esc cancel".to_owned(),
+            Mode::Normal => {
+                let mut footer = String::new();
+                if !self.user.is_empty() {
+                    footer += &format!("user~{}  ", self.user);
+                }
+                if !self.name.is_empty() {
+                    footer += &format!("name~{}  ", self.name);
+                }
+                format!("{footer}{}  │  {HELP}", self.status)
+            }
+        }
+    }
+}
+
+/// Returns the area of the given size centered in the given one.
+fn centered(area: Rect, width: u16, height: u16) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(
+        area.x + (area.width - width) / 2,
+        area.y + (area.height - height) / 2,
+        width,
+        height,
+    )
+}
+
+/// Runs the terminal UI until the user quits.
+pub async fn tui(args: TuiArgs) -> Result<()> {
+    let client = args.remote.client()?;
+    let mut terminal = ratatui::try_init()?;
+    let res = run(
+        &mut terminal,
+        App::new(&args.remote.host),
+        client,
+        args.interval,
+    )
+    .await;
+
+    ratatui::restore();
+    res
+}
+
+/// Draws the given app and updates it with the keys, the stream of the server
+/// and the failures of the refreshes, until the user quits.
+async fn run(
+    terminal: &mut DefaultTerminal,
+    mut app: App,
+    client: Client,
+    interval: Duration,
+) -> Result<()> {
+    let mut keys = EventStream::new();
+    let mut events = Box::pin(client.subscribe());
+    let (errors, mut refresh_errors) = mpsc::unbounded_channel();
+
+    if !interval.is_zero() {
+        tokio::spawn(refresh(client.clone(), interval, errors));
+    }
+
+    loop {
+        terminal.draw(|frame| app.draw(frame))?;
+
+        tokio::select! {
+            Some(key) = keys.next() => {
+                let TermEvent::Key(key) = key? else {
+                    continue;
+                };
+                match app.handle_key(key) {
+                    Some(Action::Quit) => return Ok(()),
+                    Some(Action::Signal(pid, signal)) => match client.signal(pid, signal).await {
+                        Ok(()) => app.set_status(format!("Sent {signal} to {pid}.")),
+                        Err(err) => app.set_status(err.to_string()),
+                    },
+                    None => {}
+                }
+            }
+            Some(event) = events.next() => match event {
+                // The stream sends all the processes again at each connection,
+                // but not the ones that exited in the meantime.
+                Ok(Event::Connected) => match client.processes().await {
+                    Ok(procs) => app.resync(procs),
+                    Err(err) => app.set_status(err.to_string()),
+                },
+                Ok(event) => app.apply(event),
+                Err(err) => app.set_status(err.to_string()),
+            },
+            Some(err) = refresh_errors.recv() => app.set_status(err.to_string()),
+        }
+    }
+}
+
+/// Asks the server to refresh its processes at the given interval, sending
+/// the failures to the UI and stopping if the token is not allowed to.
+async fn refresh(client: Client, interval: Duration, errors: mpsc::UnboundedSender<ClientError>) {
+    let mut ticks = tokio::time::interval(interval);
+
+    loop {
+        ticks.tick().await;
+        let Err(err) = client.refresh().await else {
+            continue;
+        };
+        let denied = matches!(
+            &err,
+            ClientError::Api { status, .. }
+                if *status == StatusCode::UNAUTHORIZED || *status == StatusCode::FORBIDDEN
+        );
+        if errors.send(err).is_err() || denied {
+            break;
+        }
+    }
+}